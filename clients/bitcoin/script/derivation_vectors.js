@@ -0,0 +1,157 @@
+#!/usr/bin/env node
+// Prints the deposit key derivation vectors of `addr.rs` (`DERIVATION_VECTORS`).
+//
+// A port of `derivate()` in contract/bridge/test/KeyDerivation.test.js, which the contract's
+// `BitcoinKeyDerivation.derivate` is tested against, with fixed instead of random inputs.
+// It only needs node: the curve arithmetic and keccak256 are implemented below, and the
+// keys are checked against the secp256k1 implementation of openssl.
+//
+// Usage: node script/derivation_vectors.js
+
+const crypto = require('crypto');
+
+// vault secret key and issue id of each vector
+const INPUTS = [
+    ['0101010101010101010101010101010101010101010101010101010101010101',
+     '0000000000000000000000000000000000000000000000000000000000000001'],
+    ['c9a9b1a2e2cca6a8cc8b2a8a7f6a5a4d3c2b1a0918273645546372819a0b0c0d',
+     '6f2a4c1b8e9d0f7a3b5c6d8e9fa1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3'],
+];
+
+const P = 0xFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC2Fn;
+const N = 0xFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141n;
+const G = [
+    0x79BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798n,
+    0x483ADA7726A3C4655DA4FBFC0E1108A8FD17B448A68554199C47D08FFB10D4B8n,
+];
+
+const mod = (a, m) => ((a % m) + m) % m;
+const bn = b => BigInt(`0x${b.toString('hex')}`);
+const toBuffer32 = n => Buffer.from(n.toString(16).padStart(64, '0'), 'hex');
+
+function inverse(a) {
+    // fermat, P is prime
+    let result = 1n;
+    let base = mod(a, P);
+    for (let e = P - 2n; e > 0n; e >>= 1n) {
+        if (e & 1n) result = result * base % P;
+        base = base * base % P;
+    }
+    return result;
+}
+
+function pointAdd(a, b) {
+    if (a === null) return b;
+    if (b === null) return a;
+    if (a[0] === b[0] && mod(a[1] + b[1], P) === 0n) return null;
+    const slope = a[0] === b[0]
+        ? 3n * a[0] * a[0] * inverse(2n * a[1])
+        : (b[1] - a[1]) * inverse(b[0] - a[0]);
+    const x = mod(slope * slope - a[0] - b[0], P);
+    return [x, mod(slope * (a[0] - x) - a[1], P)];
+}
+
+function pointMultiply(point, scalar) {
+    let result = null;
+    for (let k = scalar; k > 0n; k >>= 1n) {
+        if (k & 1n) result = pointAdd(result, point);
+        point = pointAdd(point, point);
+    }
+    return result;
+}
+
+const uncompressed = point => Buffer.concat([Buffer.from([4]), toBuffer32(point[0]), toBuffer32(point[1])]);
+const compressed = point => Buffer.concat([Buffer.from([point[1] & 1n ? 3 : 2]), toBuffer32(point[0])]);
+
+// keccak256 as used by the EVM, which differs from sha3-256 in the padding
+const ROUND_CONSTANTS = [
+    0x0000000000000001n, 0x0000000000008082n, 0x800000000000808An, 0x8000000080008000n,
+    0x000000000000808Bn, 0x0000000080000001n, 0x8000000080008081n, 0x8000000000008009n,
+    0x000000000000008An, 0x0000000000000088n, 0x0000000080008009n, 0x000000008000000An,
+    0x000000008000808Bn, 0x800000000000008Bn, 0x8000000000008089n, 0x8000000000008003n,
+    0x8000000000008002n, 0x8000000000000080n, 0x000000000000800An, 0x800000008000000An,
+    0x8000000080008081n, 0x8000000000008080n, 0x0000000080000001n, 0x8000000080008008n,
+];
+const ROTATIONS = [
+    0, 1, 62, 28, 27, 36, 44, 6, 55, 20, 3, 10, 43, 25, 39, 41, 45, 15, 21, 8, 18, 2, 61, 56, 14,
+];
+const MASK = (1n << 64n) - 1n;
+const rotl = (x, n) => (n === 0 ? x : ((x << BigInt(n)) | (x >> BigInt(64 - n))) & MASK);
+
+function keccakF(state) {
+    for (const roundConstant of ROUND_CONSTANTS) {
+        const c = [0, 1, 2, 3, 4].map(x => state[x] ^ state[x + 5] ^ state[x + 10] ^ state[x + 15] ^ state[x + 20]);
+        for (let x = 0; x < 5; x++) {
+            const d = c[(x + 4) % 5] ^ rotl(c[(x + 1) % 5], 1);
+            for (let y = 0; y < 25; y += 5) state[x + y] ^= d;
+        }
+        const b = new Array(25);
+        for (let x = 0; x < 5; x++) {
+            for (let y = 0; y < 5; y++) {
+                b[y + 5 * ((2 * x + 3 * y) % 5)] = rotl(state[x + 5 * y], ROTATIONS[x + 5 * y]);
+            }
+        }
+        for (let i = 0; i < 25; i++) {
+            const x = i % 5;
+            const row = i - x;
+            state[i] = b[i] ^ (~b[row + (x + 1) % 5] & MASK & b[row + (x + 2) % 5]);
+        }
+        state[0] ^= roundConstant;
+    }
+}
+
+function keccak256(data) {
+    const rate = 136;
+    const padded = Buffer.alloc((Math.floor(data.length / rate) + 1) * rate);
+    data.copy(padded);
+    padded[data.length] ^= 0x01;
+    padded[padded.length - 1] ^= 0x80;
+    const state = new Array(25).fill(0n);
+    for (let offset = 0; offset < padded.length; offset += rate) {
+        for (let i = 0; i < rate / 8; i++) {
+            state[i] ^= padded.readBigUInt64LE(offset + 8 * i);
+        }
+        keccakF(state);
+    }
+    const out = Buffer.alloc(32);
+    for (let i = 0; i < 4; i++) out.writeBigUInt64LE(state[i], 8 * i);
+    return out;
+}
+
+if (keccak256(Buffer.alloc(0)).toString('hex') !== 'c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470'
+    || keccak256(Buffer.from('abc')).toString('hex') !== '4e03657aea45a94fc7d47ba826c8d667c0d1e6e33a64a036ec44f58fa12d6c45') {
+    throw new Error('keccak256 test vector mismatch');
+}
+
+function opensslPublicKey(secretKey) {
+    const ecdh = crypto.createECDH('secp256k1');
+    ecdh.setPrivateKey(secretKey);
+    return ecdh.getPublicKey(null, 'compressed');
+}
+
+const hash160 = b => crypto.createHash('ripemd160').update(crypto.createHash('sha256').update(b).digest()).digest();
+
+// same as in KeyDerivation.test.js
+function derivate(priD, id) {
+    const vaultPoint = pointMultiply(G, bn(priD));
+    if (!opensslPublicKey(priD).equals(compressed(vaultPoint))) throw new Error('curve arithmetic mismatch');
+    const publicKey = uncompressed(vaultPoint);
+    const scale = keccak256(Buffer.concat([publicKey.slice(1), id]));
+    if (bn(scale) % N === 0n) throw new Error('invalid scale');
+    const derivatedPub = compressed(pointMultiply(vaultPoint, bn(scale) % N));
+    const derivatedPriD = toBuffer32(bn(priD) * bn(scale) % N);
+    if (!opensslPublicKey(derivatedPriD).equals(derivatedPub)) throw new Error('impossible error!');
+    return { vaultPoint, derivatedPub, derivatedPriD };
+}
+
+for (const [secretKey, issueId] of INPUTS) {
+    const { vaultPoint, derivatedPub, derivatedPriD } = derivate(Buffer.from(secretKey, 'hex'), Buffer.from(issueId, 'hex'));
+    console.log(JSON.stringify({
+        vault_public_key: compressed(vaultPoint).toString('hex'),
+        vault_secret_key: secretKey,
+        issue_id: issueId,
+        deposit_public_key: derivatedPub.toString('hex'),
+        deposit_secret_key: derivatedPriD.toString('hex'),
+        key_hash: hash160(derivatedPub).toString('hex'),
+    }, null, 4));
+}
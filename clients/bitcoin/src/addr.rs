@@ -1,6 +1,11 @@
-use crate::Error;
-use bitcoincore_rpc::bitcoin::secp256k1::SecretKey;
+use crate::{ConversionError, Error};
+use bitcoincore_rpc::bitcoin::{
+    hashes::{hash160, Hash},
+    secp256k1::{constants::CURVE_ORDER, PublicKey, Secp256k1, SecretKey},
+    Address, Network, PublicKey as BitcoinPublicKey,
+};
 use fixed_hash::construct_fixed_hash;
+use tiny_keccak::{Hasher, Keccak};
 
 construct_fixed_hash! {
     pub struct H256(32);
@@ -18,11 +23,82 @@ pub fn calculate_deposit_secret_key(
     Ok(deposit_key)
 }
 
+/// Compute the scalar `c = keccak256(pubX, pubY, issueId)` that `BitcoinKeyDerivation.derivate`
+/// multiplies the vault public key with. The issue id is the big-endian encoding of the
+/// contract's `uint256`. The result can be passed as secret to `add_new_deposit_key`.
+pub fn calculate_deposit_scalar(
+    vault_public_key: &PublicKey,
+    issue_id: H256,
+) -> Result<SecretKey, Error> {
+    let mut scalar = [0u8; 32];
+    let mut hasher = Keccak::v256();
+    // skip the 0x04 prefix: abi.encodePacked(pubX, pubY) is the raw 64 byte point
+    hasher.update(&vault_public_key.serialize_uncompressed()[1..]);
+    hasher.update(issue_id.as_bytes());
+    hasher.finalize(&mut scalar);
+
+    // ecMul implicitly works modulo the curve order, the hash is less than twice the order
+    if scalar >= CURVE_ORDER {
+        let mut borrow = 0u16;
+        for (byte, order) in scalar.iter_mut().zip(CURVE_ORDER.iter()).rev() {
+            let diff = *byte as u16 + 0x100 - *order as u16 - borrow;
+            *byte = diff as u8;
+            borrow = if diff < 0x100 { 1 } else { 0 };
+        }
+    }
+    // zero is rejected here, which the contract would map to the point at infinity
+    Ok(SecretKey::from_slice(&scalar)?)
+}
+
+/// Derive the deposit public key for `issue_id`, matching `BitcoinKeyDerivation.derivate`.
+pub fn derive_deposit_public_key(
+    vault_public_key: &PublicKey,
+    issue_id: H256,
+) -> Result<PublicKey, Error> {
+    let scalar = calculate_deposit_scalar(vault_public_key, issue_id)?;
+    let mut deposit_key = *vault_public_key;
+    deposit_key.mul_assign(&Secp256k1::verification_only(), &scalar[..])?;
+    Ok(deposit_key)
+}
+
+/// Derive the secret key of the deposit public key returned by [`derive_deposit_public_key`].
+pub fn derive_deposit_secret_key(vault_key: SecretKey, issue_id: H256) -> Result<SecretKey, Error> {
+    let vault_public_key = PublicKey::from_secret_key(&Secp256k1::signing_only(), &vault_key);
+    calculate_deposit_secret_key(
+        vault_key,
+        calculate_deposit_scalar(&vault_public_key, issue_id)?,
+    )
+}
+
+/// The 20 byte hash returned by `BitcoinKeyDerivation.derivate`, i.e. the HASH160 of the
+/// compressed deposit public key, which is also the P2WPKH witness program.
+pub fn derive_deposit_key_hash(
+    vault_public_key: &PublicKey,
+    issue_id: H256,
+) -> Result<H160, Error> {
+    let deposit_key = derive_deposit_public_key(vault_public_key, issue_id)?;
+    Ok(H160::from_slice(
+        &hash160::Hash::hash(&deposit_key.serialize())[..],
+    ))
+}
+
+/// The P2WPKH address the user is asked to pay to for `issue_id`.
+pub fn derive_deposit_address(
+    vault_public_key: &PublicKey,
+    issue_id: H256,
+    network: Network,
+) -> Result<Address, Error> {
+    let deposit_key = BitcoinPublicKey {
+        compressed: true,
+        key: derive_deposit_public_key(vault_public_key, issue_id)?,
+    };
+    Ok(Address::p2wpkh(&deposit_key, network).map_err(ConversionError::from)?)
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use bitcoincore_rpc::bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
     use secp256k1::rand::rngs::OsRng;
 
     #[test]
@@ -36,7 +112,7 @@ mod tests {
         let vault_secret_key = SecretKey::new(&mut rng);
         let vault_public_key = PublicKey::from_secret_key(&secp, &vault_secret_key);
 
-        let mut deposit_public_key = vault_public_key.clone();
+        let mut deposit_public_key = vault_public_key;
         deposit_public_key
             .mul_assign(&secp, &secret_key[..])
             .unwrap();
@@ -49,4 +125,90 @@ mod tests {
             PublicKey::from_secret_key(&secp, &deposit_secret_key)
         );
     }
+
+    struct DerivationVector {
+        vault_public_key: &'static str,
+        vault_secret_key: &'static str,
+        issue_id: &'static str,
+        deposit_public_key: &'static str,
+        deposit_secret_key: &'static str,
+        key_hash: &'static str,
+    }
+
+    // printed by script/derivation_vectors.js, a port of `derivate()` in
+    // contract/bridge/test/KeyDerivation.test.js, for the vault secret keys and issue ids below
+    const DERIVATION_VECTORS: [DerivationVector; 2] = [
+        DerivationVector {
+            vault_public_key: "031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f",
+            vault_secret_key: "0101010101010101010101010101010101010101010101010101010101010101",
+            issue_id: "0000000000000000000000000000000000000000000000000000000000000001",
+            deposit_public_key:
+                "02cf20b669d84dea83ac12fc75c255e7d15759893f757dceeb1f49a75e9c0d209c",
+            deposit_secret_key: "a2852f9ad76779523e96529baec7c0380827036233b3fab9b118694be4fda426",
+            key_hash: "ed1f85ebb34eabced69172506252b1b8645bb8ef",
+        },
+        DerivationVector {
+            vault_public_key: "031c8ea914287db75d058230f910e22b30d521a48eb9a2bef6186e5669551670d7",
+            vault_secret_key: "c9a9b1a2e2cca6a8cc8b2a8a7f6a5a4d3c2b1a0918273645546372819a0b0c0d",
+            issue_id: "6f2a4c1b8e9d0f7a3b5c6d8e9fa1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3",
+            deposit_public_key:
+                "02172d11114bf9aca66c8765af60a71c35ec3105f133d83a11c7a1da6d8e4d10d4",
+            deposit_secret_key: "aebf816a587c10cd82b0c4faa1ea61a4231d5e0261dee45374d7c015014c1210",
+            key_hash: "2e8ddd9585b14dedd1859cb4dcef652bc54a54a0",
+        },
+    ];
+
+    #[test]
+    fn test_derive_deposit_keys_matches_contract() {
+        for vector in DERIVATION_VECTORS.iter() {
+            let vault_public_key =
+                PublicKey::from_slice(&hex::decode(vector.vault_public_key).unwrap()).unwrap();
+            let vault_secret_key =
+                SecretKey::from_slice(&hex::decode(vector.vault_secret_key).unwrap()).unwrap();
+            let issue_id = H256::from_slice(&hex::decode(vector.issue_id).unwrap());
+
+            assert_eq!(
+                derive_deposit_public_key(&vault_public_key, issue_id)
+                    .unwrap()
+                    .serialize()
+                    .to_vec(),
+                hex::decode(vector.deposit_public_key).unwrap()
+            );
+            assert_eq!(
+                derive_deposit_secret_key(vault_secret_key, issue_id)
+                    .unwrap()
+                    .to_string(),
+                vector.deposit_secret_key
+            );
+            assert_eq!(
+                derive_deposit_key_hash(&vault_public_key, issue_id).unwrap(),
+                H160::from_slice(&hex::decode(vector.key_hash).unwrap())
+            );
+
+            let address =
+                derive_deposit_address(&vault_public_key, issue_id, Network::Regtest).unwrap();
+            assert_eq!(
+                address.script_pubkey().as_bytes()[2..],
+                hex::decode(vector.key_hash).unwrap()[..]
+            );
+        }
+    }
+
+    #[test]
+    fn test_derive_deposit_secret_key_matches_public_key() {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new().unwrap();
+
+        let vault_secret_key = SecretKey::new(&mut rng);
+        let vault_public_key = PublicKey::from_secret_key(&secp, &vault_secret_key);
+        let issue_id = H256::random();
+
+        assert_eq!(
+            derive_deposit_public_key(&vault_public_key, issue_id).unwrap(),
+            PublicKey::from_secret_key(
+                &secp,
+                &derive_deposit_secret_key(vault_secret_key, issue_id).unwrap()
+            )
+        );
+    }
 }
@@ -0,0 +1,85 @@
+//! Paying several recipients with a single transaction, which saves fees and takes the
+//! `transaction_creation_lock` only once.
+//!
+//! Standard transactions carry at most one OP_RETURN output, and the contract compares the
+//! whole payload of the first OP_RETURN to the id of a single request (see
+//! [`validate_transaction`](crate::validate::validate_transaction)). A batch can therefore
+//! carry at most one request id: redeem payments still need a transaction each, while
+//! payments that are not matched against an id (e.g. replace or withdraw transfers) can be
+//! batched freely. The OP_RETURN follows the first payout, the same layout as a transaction
+//! with a single payment. Batching redeems would require the contract to accept a
+//! commitment to several ids instead, e.g. the merkle root of the ids in the OP_RETURN, with
+//! the vault submitting the merkle path of the request id alongside the transaction proof.
+
+use crate::{Address, Error};
+use std::collections::HashSet;
+
+/// A payment of `sat` satoshis to `address`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payout {
+    pub address: Address,
+    pub sat: u64,
+}
+
+impl Payout {
+    pub fn new(address: Address, sat: u64) -> Self {
+        Self { address, sat }
+    }
+}
+
+/// Ensure the batch is not empty and pays every address at most once, since
+/// `createrawtransaction` does not accept duplicate outputs.
+pub(crate) fn check_payouts(payouts: &[Payout]) -> Result<(), Error> {
+    if payouts.is_empty() {
+        return Err(Error::EmptyBatch);
+    }
+    let mut addresses = HashSet::with_capacity(payouts.len());
+    for payout in payouts {
+        if !addresses.insert(&payout.address) {
+            return Err(Error::DuplicateRecipient(payout.address.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// The recipients of the batch, as stored in the [`LockedTransaction`](crate::LockedTransaction).
+pub(crate) fn recipients(payouts: &[Payout]) -> String {
+    payouts
+        .iter()
+        .map(|payout| payout.address.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Network, PublicKey};
+    use bitcoincore_rpc::bitcoin::secp256k1::{rand::rngs::OsRng, Secp256k1};
+
+    fn new_address() -> Address {
+        let secp = Secp256k1::new();
+        let (_, public_key) = secp.generate_keypair(&mut OsRng::new().unwrap());
+        let public_key = PublicKey {
+            compressed: true,
+            key: public_key,
+        };
+        Address::p2wpkh(&public_key, Network::Regtest).unwrap()
+    }
+
+    #[test]
+    fn test_check_payouts() {
+        let (first, second) = (new_address(), new_address());
+        assert!(check_payouts(&[
+            Payout::new(first.clone(), 1_000),
+            Payout::new(second, 2_000)
+        ])
+        .is_ok());
+
+        assert!(matches!(check_payouts(&[]), Err(Error::EmptyBatch)));
+        assert!(matches!(
+            check_payouts(&[Payout::new(first.clone(), 1_000), Payout::new(first.clone(), 2_000)]),
+            Err(Error::DuplicateRecipient(address)) if address == first.to_string()
+        ));
+    }
+}
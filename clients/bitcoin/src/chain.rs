@@ -0,0 +1,235 @@
+//! Tracking of the recent main chain, to notice when blocks that were relied upon (e.g. the
+//! `block_hash` of a [`TransactionMetadata`](crate::TransactionMetadata)) are orphaned.
+
+use crate::{BitcoinCoreApi, BlockHash, Error, RETRY_DURATION};
+use futures::stream::{self, BoxStream, StreamExt};
+use log::warn;
+use std::collections::VecDeque;
+use tokio::time::sleep;
+
+/// Number of blocks tracked unless configured otherwise.
+pub const DEFAULT_WINDOW_SIZE: usize = 100;
+
+/// The main chain switched to a different branch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reorg {
+    /// Height and hash of the last block shared by the old and the new main chain.
+    pub fork_point: (u32, BlockHash),
+    /// Blocks that are no longer in the main chain, in ascending height.
+    pub removed: Vec<(u32, BlockHash)>,
+    /// Blocks of the new main chain after the fork point, in ascending height.
+    pub added: Vec<(u32, BlockHash)>,
+}
+
+impl Reorg {
+    /// True if the block was part of the main chain before, but not anymore.
+    pub fn is_orphaned(&self, block_hash: &BlockHash) -> bool {
+        self.removed.iter().any(|(_, hash)| hash == block_hash)
+    }
+}
+
+/// Keeps a window of the most recent `(height, BlockHash)` pairs of the main chain.
+pub struct ChainTracker<B> {
+    rpc: B,
+    window_size: usize,
+    /// Consecutive main chain blocks in ascending height, the last one is the tip.
+    window: VecDeque<(u32, BlockHash)>,
+}
+
+impl<B: BitcoinCoreApi + Send + Sync + 'static> ChainTracker<B> {
+    /// Start tracking from the current tip, remembering up to `window_size` blocks.
+    pub async fn new(rpc: B, window_size: usize) -> Result<Self, Error> {
+        let window_size = window_size.max(1);
+        let tip_height = rpc.get_block_count().await? as u32;
+        let heights: Vec<u32> =
+            (tip_height.saturating_sub(window_size as u32 - 1)..=tip_height).collect();
+        let hashes = rpc.get_block_hashes(&heights).await?;
+        Ok(Self {
+            rpc,
+            window_size,
+            window: heights.into_iter().zip(hashes).collect(),
+        })
+    }
+
+    /// Height and hash of the best block seen by the last update.
+    pub fn tip(&self) -> (u32, BlockHash) {
+        *self.window.back().expect("window is never empty")
+    }
+
+    /// Height of the block if it is among the tracked main chain blocks.
+    pub fn height_of(&self, block_hash: &BlockHash) -> Option<u32> {
+        self.window
+            .iter()
+            .find(|(_, hash)| hash == block_hash)
+            .map(|(height, _)| *height)
+    }
+
+    /// Catch up with the best block of the node, returning the reorg if the new best
+    /// block does not extend the previous one.
+    pub async fn update(&mut self) -> Result<Option<Reorg>, Error> {
+        let best_block_hash = self.rpc.get_best_block_hash().await?;
+        let mut height = self.rpc.get_block_info(&best_block_hash).await?.height as u32;
+
+        // walk back from the new tip until reaching a tracked block
+        let mut added = Vec::new();
+        let mut block_hash = best_block_hash;
+        let fork_height = loop {
+            if let Some(height) = self.height_of(&block_hash) {
+                break height;
+            }
+            if height <= self.window[0].0 {
+                // the fork point is below the oldest tracked block
+                return Err(Error::ReorgTooDeep(self.window[0].0));
+            }
+            let header = self.rpc.get_block_header(&block_hash).await?;
+            added.push(block_hash);
+            block_hash = header.prev_blockhash;
+            height -= 1;
+        };
+
+        let fork_index = self
+            .window
+            .iter()
+            .position(|(height, _)| *height == fork_height)
+            .expect("fork point is tracked");
+        let removed: Vec<_> = self.window.drain(fork_index + 1..).collect();
+        let added: Vec<_> = added
+            .into_iter()
+            .rev()
+            .enumerate()
+            .map(|(index, hash)| (fork_height + 1 + index as u32, hash))
+            .collect();
+
+        self.window.extend(added.iter().copied());
+        if self.window.len() > self.window_size {
+            self.window.drain(..self.window.len() - self.window_size);
+        }
+
+        if removed.is_empty() {
+            return Ok(None);
+        }
+        warn!(
+            "Reorg of {} block(s) after height {}",
+            removed.len(),
+            fork_height
+        );
+        Ok(Some(Reorg {
+            fork_point: (fork_height, block_hash),
+            removed,
+            added,
+        }))
+    }
+
+    /// Stream of reorgs, checking for a new best block every second.
+    pub fn into_stream(self) -> BoxStream<'static, Result<Reorg, Error>> {
+        stream::unfold(self, |mut tracker| async move {
+            loop {
+                match tracker.update().await {
+                    Ok(Some(reorg)) => return Some((Ok(reorg), tracker)),
+                    Ok(None) => sleep(RETRY_DURATION).await,
+                    Err(err) => return Some((Err(err), tracker)),
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock::MockBitcoinCore, Network};
+
+    #[tokio::test]
+    async fn test_update_without_reorg() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let mined = btc_rpc.mine_blocks(2);
+        let mut tracker = ChainTracker::new(btc_rpc.clone(), 3).await.unwrap();
+        assert_eq!(tracker.tip(), (2, mined[1]));
+        assert_eq!(tracker.height_of(&mined[0]), Some(1));
+        assert_eq!(tracker.update().await.unwrap(), None);
+
+        let mined = btc_rpc.mine_blocks(3);
+        assert_eq!(tracker.update().await.unwrap(), None);
+        assert_eq!(tracker.tip(), (5, mined[2]));
+        assert_eq!(tracker.height_of(&mined[0]), Some(3));
+        // only the most recent blocks are remembered
+        assert_eq!(tracker.window.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_reorg() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let mined = btc_rpc.mine_blocks(4);
+        let mut tracker = ChainTracker::new(btc_rpc.clone(), 3).await.unwrap();
+        let next = btc_rpc.mine_block();
+        tracker.update().await.unwrap();
+
+        btc_rpc.invalidate_block(&mined[3]);
+        let fork = btc_rpc.mine_blocks(3);
+
+        let reorg = tracker.update().await.unwrap().unwrap();
+        assert_eq!(reorg.fork_point, (3, mined[2]));
+        assert_eq!(reorg.removed, vec![(4, mined[3]), (5, next)]);
+        assert!(reorg.is_orphaned(&mined[3]));
+        assert!(!reorg.is_orphaned(&mined[2]));
+        assert_eq!(reorg.added, vec![(4, fork[0]), (5, fork[1]), (6, fork[2])]);
+        assert_eq!(tracker.tip(), (6, fork[2]));
+    }
+
+    #[tokio::test]
+    async fn test_reorg_deeper_than_window() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let mined = btc_rpc.mine_blocks(2);
+        let mut tracker = ChainTracker::new(btc_rpc.clone(), 1).await.unwrap();
+
+        btc_rpc.invalidate_block(&mined[1]);
+        assert!(matches!(
+            tracker.update().await,
+            Err(Error::ReorgTooDeep(2))
+        ));
+
+        // the walk back stops at the window instead of at genesis
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let mined = btc_rpc.mine_blocks(20);
+        let mut tracker = ChainTracker::new(btc_rpc.clone(), 3).await.unwrap();
+        btc_rpc.invalidate_block(&mined[9]);
+        btc_rpc.mine_blocks(15);
+        assert!(matches!(
+            tracker.update().await,
+            Err(Error::ReorgTooDeep(18))
+        ));
+        assert_eq!(tracker.tip(), (20, mined[19]));
+    }
+
+    #[tokio::test]
+    async fn test_reorg_to_shorter_chain() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let mined = btc_rpc.mine_blocks(1);
+        let mut tracker = ChainTracker::new(btc_rpc.clone(), 2).await.unwrap();
+        btc_rpc.mine_block();
+        tracker.update().await.unwrap();
+        btc_rpc.invalidate_block(&btc_rpc.get_best_block_hash().await.unwrap());
+
+        let reorg = tracker.update().await.unwrap().unwrap();
+        assert_eq!(reorg.fork_point, (1, mined[0]));
+        assert!(reorg.added.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stream_yields_reorgs() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let mined = btc_rpc.mine_blocks(2);
+        let mut reorgs = ChainTracker::new(btc_rpc.clone(), DEFAULT_WINDOW_SIZE)
+            .await
+            .unwrap()
+            .into_stream();
+
+        btc_rpc.invalidate_block(&mined[1]);
+        let fork = btc_rpc.mine_blocks(2);
+
+        let reorg = reorgs.next().await.unwrap().unwrap();
+        assert_eq!(reorg.removed, vec![(2, mined[1])]);
+        assert_eq!(reorg.added, vec![(2, fork[0]), (3, fork[1])]);
+    }
+}
@@ -1,5 +1,10 @@
-use crate::{BitcoinCore, Error};
-use bitcoincore_rpc::{bitcoin::Network, Auth};
+#[cfg(unix)]
+use crate::lock::FileLock;
+use crate::{BitcoinCore, BroadcastPolicy, Error, FileJournal, VaultMasterKey, ZmqConfig};
+use bitcoincore_rpc::{
+    bitcoin::{util::bip32::ExtendedPrivKey, Network},
+    Auth,
+};
 use std::{str::FromStr, time::Duration};
 use clap::Clap;
 
@@ -33,6 +38,45 @@ pub struct BitcoinOpts {
     #[clap(long, default_value = "60000")]
     pub bitcoin_connection_timeout_ms: u64,
 
+    /// Timeout in milliseconds for a single request to bitcoin-core.
+    #[clap(long, default_value = "60000")]
+    pub bitcoin_request_timeout_ms: u64,
+
+    /// ZMQ endpoint of bitcoin-core publishing raw blocks (`-zmqpubrawblock`),
+    /// polls for new blocks if not set.
+    #[clap(long, env = "BITCOIN_ZMQ_RAW_BLOCK")]
+    pub bitcoin_zmq_raw_block: Option<String>,
+
+    /// ZMQ endpoint of bitcoin-core publishing transaction hashes (`-zmqpubhashtx`),
+    /// polls the mempool if not set.
+    #[clap(long, env = "BITCOIN_ZMQ_HASH_TX")]
+    pub bitcoin_zmq_hash_tx: Option<String>,
+
+    /// File locked while creating a transaction, to prevent double spends by other
+    /// processes using the same wallet and file. Only guards this process if not set.
+    #[cfg(unix)]
+    #[clap(long, env = "BITCOIN_TRANSACTION_LOCK_FILE")]
+    pub bitcoin_transaction_lock_file: Option<std::path::PathBuf>,
+
+    /// File recording the transactions sent by this client, so that they can be rebroadcast
+    /// after a crash. Transactions are not journaled if not set.
+    #[clap(long, env = "BITCOIN_TRANSACTION_JOURNAL")]
+    pub bitcoin_transaction_journal: Option<std::path::PathBuf>,
+
+    /// BIP32 master key (xprv/tprv) to derive the vault key and deposit keys from, instead
+    /// of using keys generated by the wallet.
+    #[clap(long, env = "BITCOIN_VAULT_MASTER_KEY")]
+    pub bitcoin_vault_master_key: Option<ExtendedPrivKey>,
+
+    /// Highest fee in satoshis of a transaction to broadcast. Transactions are checked with
+    /// `testmempoolaccept` before broadcast if this or `bitcoin-max-fee-percent` is set.
+    #[clap(long, env = "BITCOIN_MAX_FEE")]
+    pub bitcoin_max_fee: Option<u64>,
+
+    /// Highest fee of a transaction to broadcast, as a percentage of the amount it pays.
+    #[clap(long, env = "BITCOIN_MAX_FEE_PERCENT")]
+    pub bitcoin_max_fee_percent: Option<f64>,
+
     /// Bitcoin network type for address encoding.
     #[clap(long, default_value = "regtest")]
     pub network: BitcoinNetwork,
@@ -44,12 +88,39 @@ impl BitcoinOpts {
     }
 
     pub fn new_client(&self, wallet_name: Option<String>) -> Result<BitcoinCore, Error> {
-        BitcoinCore::new(
+        let client = BitcoinCore::new(
             self.bitcoin_rpc_url.clone(),
             self.new_auth(),
             wallet_name,
             self.network.0,
             Duration::from_millis(self.bitcoin_connection_timeout_ms),
-        )
+        )?
+        .with_request_timeout(Duration::from_millis(self.bitcoin_request_timeout_ms))
+        .with_zmq(ZmqConfig {
+            raw_block: self.bitcoin_zmq_raw_block.clone(),
+            hash_tx: self.bitcoin_zmq_hash_tx.clone(),
+        });
+        #[cfg(unix)]
+        let client = match self.bitcoin_transaction_lock_file {
+            Some(ref path) => client.with_transaction_lock(FileLock::new(path)),
+            None => client,
+        };
+        let client = match self.bitcoin_transaction_journal {
+            Some(ref path) => client.with_journal(FileJournal::new(path)),
+            None => client,
+        };
+        let client = match self.bitcoin_vault_master_key {
+            Some(master_key) => client.with_vault_key(VaultMasterKey::new(master_key)?),
+            None => client,
+        };
+        let client = if self.bitcoin_max_fee.is_some() || self.bitcoin_max_fee_percent.is_some() {
+            client.with_broadcast_policy(BroadcastPolicy::new(
+                self.bitcoin_max_fee,
+                self.bitcoin_max_fee_percent,
+            ))
+        } else {
+            client
+        };
+        Ok(client)
     }
 }
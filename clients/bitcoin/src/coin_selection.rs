@@ -0,0 +1,266 @@
+//! Explicit choice of the wallet outputs that fund a transaction, instead of leaving it to
+//! `fundrawtransaction`. Selected outputs are locked with `lockunspent`, so that other
+//! processes using the same wallet do not spend them concurrently.
+
+use crate::{Error, OutPoint, Script};
+use std::cmp::Reverse;
+
+/// Label of the deposit keys imported by `add_new_deposit_key`.
+pub const DEPOSIT_LABEL: &str = "deposit";
+
+/// Virtual size of an input spending a p2wpkh output.
+pub const P2WPKH_INPUT_VSIZE: u64 = 68;
+
+/// Virtual size of a p2wpkh output.
+pub const P2WPKH_OUTPUT_VSIZE: u64 = 31;
+
+/// Number of branches explored by branch-and-bound before giving up, as in bitcoind.
+const BNB_TOTAL_TRIES: usize = 100_000;
+
+/// An unspent output of the wallet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub amount: u64,
+    pub script_pubkey: Script,
+    pub confirmations: u32,
+    /// Paid to a deposit address, i.e. a key imported with [`DEPOSIT_LABEL`].
+    pub is_deposit: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectionStrategy {
+    /// Spend the largest outputs first, keeping the number of inputs low.
+    LargestFirst,
+    /// Search for a set of outputs that pays the target without a change output, falling
+    /// back to [`SelectionStrategy::LargestFirst`] if there is none.
+    BranchAndBound,
+}
+
+/// Which wallet outputs may fund a transaction, and how to choose among them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoinSelection {
+    pub strategy: SelectionStrategy,
+    /// Only spend outputs with at least this many confirmations.
+    pub min_confirmations: u32,
+    /// Do not spend outputs paid to deposit addresses, e.g. to keep issued funds separate.
+    pub avoid_deposits: bool,
+}
+
+impl Default for CoinSelection {
+    fn default() -> Self {
+        Self {
+            strategy: SelectionStrategy::BranchAndBound,
+            min_confirmations: 0,
+            avoid_deposits: true,
+        }
+    }
+}
+
+impl CoinSelection {
+    pub fn with_strategy(strategy: SelectionStrategy) -> Self {
+        Self {
+            strategy,
+            ..Default::default()
+        }
+    }
+
+    /// Choose outputs worth at least `target` satoshis, plus the fee of spending them at
+    /// `fee_rate` sat/vB. The `target` must include the fee of the transaction without
+    /// inputs and change, see [`base_vsize`].
+    pub fn select(&self, utxos: Vec<Utxo>, target: u64, fee_rate: u64) -> Result<Vec<Utxo>, Error> {
+        let input_fee = fee_rate * P2WPKH_INPUT_VSIZE;
+        let mut candidates: Vec<_> = utxos
+            .into_iter()
+            .filter(|utxo| {
+                utxo.confirmations >= self.min_confirmations
+                    && !(self.avoid_deposits && utxo.is_deposit)
+                    // skip outputs that cost more to spend than they are worth
+                    && utxo.amount > input_fee
+            })
+            .collect();
+        candidates.sort_by_key(|utxo| Reverse(utxo.amount));
+        let effective_values: Vec<_> = candidates
+            .iter()
+            .map(|utxo| utxo.amount - input_fee)
+            .collect();
+
+        let change_fee = fee_rate * P2WPKH_OUTPUT_VSIZE;
+        let selected = match self.strategy {
+            SelectionStrategy::LargestFirst => largest_first(&effective_values, target, change_fee),
+            SelectionStrategy::BranchAndBound => {
+                // creating the change output now and spending it later
+                let cost_of_change = change_fee + input_fee;
+                branch_and_bound(&effective_values, target, cost_of_change)
+                    .or_else(|| largest_first(&effective_values, target, change_fee))
+            }
+        }
+        .ok_or(Error::InsufficientFunds)?;
+
+        Ok(selected
+            .into_iter()
+            .map(|index| candidates[index].clone())
+            .collect())
+    }
+}
+
+/// Virtual size of a transaction with `outputs` and without any inputs.
+pub fn base_vsize(outputs: &[Script]) -> u64 {
+    // version, locktime, counts and the segwit marker
+    11 + outputs
+        .iter()
+        // value and script length
+        .map(|script_pubkey| 9 + script_pubkey.len() as u64)
+        .sum::<u64>()
+}
+
+/// Indices of the largest values until they cover `target` and the change output.
+fn largest_first(values: &[u64], target: u64, change_fee: u64) -> Option<Vec<usize>> {
+    let mut selected = Vec::new();
+    let mut total = 0;
+    for (index, value) in values.iter().enumerate() {
+        if total >= target + change_fee {
+            break;
+        }
+        total += value;
+        selected.push(index);
+    }
+    // without change, the excess goes to the fee
+    (total >= target).then_some(selected)
+}
+
+/// Indices of the values whose sum is closest to `target` while not exceeding it by more
+/// than `cost_of_change`. The values must be sorted in descending order.
+fn branch_and_bound(values: &[u64], target: u64, cost_of_change: u64) -> Option<Vec<usize>> {
+    let mut search = BranchAndBound {
+        values,
+        target,
+        upper_bound: target + cost_of_change,
+        tries: 0,
+        selected: Vec::new(),
+        best: None,
+    };
+    search.explore(0, 0, values.iter().sum());
+    search.best.map(|(_, selected)| selected)
+}
+
+struct BranchAndBound<'a> {
+    values: &'a [u64],
+    target: u64,
+    upper_bound: u64,
+    tries: usize,
+    selected: Vec<usize>,
+    /// The excess and indices of the best selection found so far.
+    best: Option<(u64, Vec<usize>)>,
+}
+
+impl BranchAndBound<'_> {
+    /// Explore including and excluding `values[index]`, with `total` selected so far and
+    /// `remaining` available from the values not decided yet.
+    fn explore(&mut self, index: usize, total: u64, remaining: u64) {
+        self.tries += 1;
+        if self.tries > BNB_TOTAL_TRIES
+            || total > self.upper_bound
+            || total + remaining < self.target
+        {
+            return;
+        }
+        if total >= self.target {
+            let excess = total - self.target;
+            if self.best.as_ref().is_none_or(|(best, _)| excess < *best) {
+                self.best = Some((excess, self.selected.clone()));
+            }
+            return;
+        }
+        if let Some(&value) = self.values.get(index) {
+            self.selected.push(index);
+            self.explore(index + 1, total + value, remaining - value);
+            self.selected.pop();
+            self.explore(index + 1, total, remaining - value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Txid;
+
+    fn utxos(amounts: &[u64]) -> Vec<Utxo> {
+        amounts
+            .iter()
+            .enumerate()
+            .map(|(vout, amount)| Utxo {
+                outpoint: OutPoint::new(Txid::default(), vout as u32),
+                amount: *amount,
+                script_pubkey: Script::new(),
+                confirmations: 1,
+                is_deposit: false,
+            })
+            .collect()
+    }
+
+    fn amounts(selected: Vec<Utxo>) -> Vec<u64> {
+        selected.into_iter().map(|utxo| utxo.amount).collect()
+    }
+
+    #[test]
+    fn test_largest_first() {
+        let selection = CoinSelection::with_strategy(SelectionStrategy::LargestFirst);
+        let selected = selection
+            .select(utxos(&[1_000, 50_000, 20_000, 30_000]), 60_000, 1)
+            .unwrap();
+        assert_eq!(amounts(selected), vec![50_000, 30_000]);
+    }
+
+    #[test]
+    fn test_branch_and_bound_avoids_change() {
+        let selection = CoinSelection::with_strategy(SelectionStrategy::BranchAndBound);
+        // 20_000 + 30_000 pays the target and the fee of two inputs exactly
+        let selected = selection
+            .select(utxos(&[1_000, 50_000, 20_000, 30_000]), 50_000 - 136, 1)
+            .unwrap();
+        assert_eq!(amounts(selected), vec![30_000, 20_000]);
+
+        // no exact match, fall back to largest first
+        let selected = selection
+            .select(utxos(&[50_000, 30_000]), 60_000, 1)
+            .unwrap();
+        assert_eq!(amounts(selected), vec![50_000, 30_000]);
+    }
+
+    #[test]
+    fn test_filters_utxos() {
+        let mut available = utxos(&[50_000, 20_000, 30_000]);
+        available[0].is_deposit = true;
+        available[2].confirmations = 0;
+
+        let selection = CoinSelection {
+            min_confirmations: 1,
+            ..Default::default()
+        };
+        let selected = selection.select(available.clone(), 10_000, 1).unwrap();
+        assert_eq!(amounts(selected), vec![20_000]);
+        assert!(matches!(
+            selection.select(available.clone(), 30_000, 1),
+            Err(Error::InsufficientFunds)
+        ));
+
+        let selection = CoinSelection {
+            avoid_deposits: false,
+            ..selection
+        };
+        let selected = selection.select(available, 30_000, 1).unwrap();
+        assert_eq!(amounts(selected), vec![50_000]);
+    }
+
+    #[test]
+    fn test_base_vsize() {
+        let p2wpkh = Script::new_v0_wpkh(&Default::default());
+        let op_return = Script::new_op_return(&[0; 32]);
+        let mut outputs = vec![p2wpkh];
+        assert_eq!(base_vsize(&outputs), 11 + P2WPKH_OUTPUT_VSIZE);
+        outputs.push(op_return);
+        assert_eq!(base_vsize(&outputs), 11 + P2WPKH_OUTPUT_VSIZE + 43);
+    }
+}
@@ -0,0 +1,116 @@
+//! Support for descriptor wallets, the default since bitcoind 23. They do not support the
+//! legacy `dumpprivkey`, `importprivkey` and `importpubkey`: keys are imported as output
+//! descriptors with `importdescriptors`, and the private key of a wallet address has to be
+//! derived from the extended key of the descriptor that generated it.
+
+use crate::{Network, PrivateKey, PublicKey};
+use bitcoincore_rpc::bitcoin::{
+    secp256k1::Secp256k1,
+    util::bip32::{DerivationPath, ExtendedPrivKey, Fingerprint},
+};
+use std::str::FromStr;
+
+/// The kind of wallet managed by bitcoind, which determines how keys are imported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WalletType {
+    /// Keys are imported and exported individually, deprecated in newer versions.
+    Legacy,
+    /// Keys are imported as output descriptors.
+    Descriptor,
+}
+
+/// Where a wallet key was derived from, as reported by `getaddressinfo`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyOrigin {
+    pub fingerprint: Fingerprint,
+    pub path: DerivationPath,
+}
+
+/// Descriptor of the p2wpkh output of `key`, which is either a public key or a private key
+/// in WIF.
+pub fn wpkh<K: ToString>(key: K) -> String {
+    format!("wpkh({})", key.to_string())
+}
+
+/// The extended private keys in a descriptor, e.g. the master key in
+/// `wpkh(tprv.../84h/1h/0h/0/*)#checksum`.
+fn extended_private_keys(descriptor: &str) -> impl Iterator<Item = ExtendedPrivKey> + '_ {
+    descriptor
+        .split(['(', ')', '[', ']', '/', ',', '#'])
+        .filter_map(|token| ExtendedPrivKey::from_str(token).ok())
+}
+
+/// Derive the private key at `origin` from the master key of one of the private
+/// `descriptors`, as listed by `listdescriptors true`.
+pub fn derive_private_key(
+    descriptors: &[String],
+    origin: &KeyOrigin,
+    network: Network,
+) -> Option<PrivateKey> {
+    let secp = Secp256k1::new();
+    let master = descriptors
+        .iter()
+        .flat_map(|descriptor| extended_private_keys(descriptor))
+        .find(|key| key.depth == 0 && key.fingerprint(&secp) == origin.fingerprint)?;
+    let key = master.derive_priv(&secp, &origin.path).ok()?.private_key;
+    Some(PrivateKey { network, ..key })
+}
+
+/// Like [`derive_private_key`], but only returns the key if it belongs to `public_key`.
+pub fn find_private_key(
+    descriptors: &[String],
+    origin: &KeyOrigin,
+    public_key: &PublicKey,
+    network: Network,
+) -> Option<PrivateKey> {
+    derive_private_key(descriptors, origin, network)
+        .filter(|key| &PublicKey::from_private_key(&Secp256k1::new(), key) == public_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_private_key() {
+        let secp = Secp256k1::new();
+        let master = ExtendedPrivKey::new_master(Network::Regtest, &[0x42; 32]).unwrap();
+        let other = ExtendedPrivKey::new_master(Network::Regtest, &[0x13; 32]).unwrap();
+        let descriptors = vec![
+            format!("pkh({}/44h/1h/0h/0/*)#00000000", other),
+            format!("wpkh({}/84h/1h/0h/0/*)#00000000", master),
+        ];
+        let path = DerivationPath::from_str("m/84'/1'/0'/0/5").unwrap();
+        let expected = master.derive_priv(&secp, &path).unwrap().private_key;
+        let public_key = PublicKey::from_private_key(&secp, &expected);
+
+        let origin = KeyOrigin {
+            fingerprint: master.fingerprint(&secp),
+            path,
+        };
+        assert_eq!(
+            find_private_key(&descriptors, &origin, &public_key, Network::Regtest),
+            Some(expected)
+        );
+
+        // derived from a key the wallet does not hold
+        let origin = KeyOrigin {
+            fingerprint: Fingerprint::default(),
+            ..origin
+        };
+        assert_eq!(
+            find_private_key(&descriptors, &origin, &public_key, Network::Regtest),
+            None
+        );
+    }
+
+    #[test]
+    fn test_wpkh() {
+        let key =
+            PrivateKey::from_wif("cVt4o7BGAig1UXywgGSmARhxMdzP5qvQsxKkSsc1XEkw3tDTQFpy").unwrap();
+        assert_eq!(
+            wpkh(key),
+            "wpkh(cVt4o7BGAig1UXywgGSmARhxMdzP5qvQsxKkSsc1XEkw3tDTQFpy)"
+        );
+    }
+}
@@ -1,19 +1,25 @@
+use crate::{policy::PolicyError, validate::ValidationError};
+use base64::DecodeError as Base64Error;
 use bitcoincore_rpc::{
     bitcoin::{
         consensus::encode::Error as BitcoinEncodeError,
         hashes::Error as HashesError,
         secp256k1::Error as Secp256k1Error,
-        util::{address::Error as AddressError, key::Error as KeyError},
+        util::{
+            address::Error as AddressError, bip32::Error as Bip32Error, key::Error as KeyError,
+            psbt::Error as PsbtError,
+        },
     },
     jsonrpc::{error::RpcError, Error as JsonRpcError},
     Error as BitcoinError,
 };
 use hex::FromHexError;
-use hyper::Error as HyperError;
+use reqwest::Error as HttpError;
 use serde_json::Error as SerdeJsonError;
-use std::io::ErrorKind as IoErrorKind;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 use thiserror::Error;
 use tokio::time::error::Elapsed;
+use zeromq::ZmqError;
 
 #[derive(Error, Debug)]
 pub enum ConversionError {
@@ -23,6 +29,8 @@ pub enum ConversionError {
     AddressError(#[from] AddressError),
     #[error("HashesError: {0}")]
     HashesError(#[from] HashesError),
+    #[error("Base64Error: {0}")]
+    Base64Error(#[from] Base64Error),
     #[error("Invalid format")]
     InvalidFormat,
     #[error("Invalid payload")]
@@ -47,8 +55,18 @@ pub enum Error {
     Secp256k1Error(#[from] Secp256k1Error),
     #[error("KeyError: {0}")]
     KeyError(#[from] KeyError),
+    #[error("Bip32Error: {0}")]
+    Bip32Error(#[from] Bip32Error),
     #[error("Timeout: {0}")]
     TimeElapsed(#[from] Elapsed),
+    #[error("HttpError: {0}")]
+    HttpError(#[from] HttpError),
+    #[error("ZmqError: {0}")]
+    ZmqError(#[from] ZmqError),
+    #[error("ValidationError: {0}")]
+    ValidationError(#[from] ValidationError),
+    #[error("PolicyError: {0}")]
+    PolicyError(#[from] PolicyError),
 
     #[error("Could not confirm transaction")]
     ConfirmationError,
@@ -66,20 +84,68 @@ pub enum Error {
     WalletNotFound,
     #[error("Invalid Bitcoin network")]
     InvalidBitcoinNetwork,
+    #[error("Invalid zmq notification")]
+    InvalidZmqNotification,
+    #[error("Reorg deeper than the tracked blocks starting at height {0}")]
+    ReorgTooDeep(u32),
+    #[error("Invalid merkle proof")]
+    InvalidMerkleProof,
+    #[error("Merkle proof does not match the merkle root")]
+    MerkleRootMismatch,
+    #[error("Invalid block header size")]
+    InvalidBlockHeader,
+    #[error("Transaction not included in merkle proof")]
+    TransactionNotInProof,
+    #[error("Transaction has no output to the recipient")]
+    RecipientOutputNotFound,
+    #[error("Transaction has no spendable wallet output")]
+    NoSpendableOutput,
+    #[error("Wallet output is too small to pay for the package")]
+    CpfpOutputTooSmall,
+    #[error("Batch contains no payouts")]
+    EmptyBatch,
+    #[error("Batch pays {0} more than once")]
+    DuplicateRecipient(String),
+    #[error("Insufficient funds in the selectable outputs")]
+    InsufficientFunds,
+    #[error("PsbtError: {0}")]
+    PsbtError(#[from] PsbtError),
+    #[error("PSBT is missing signatures")]
+    IncompletePsbt,
+    #[error("Signer does not hold the key")]
+    UnknownSigningKey,
+    #[error("Failed to derive the private key from the wallet descriptors")]
+    PrivateKeyNotFound,
+    #[error("No vault master key configured")]
+    MissingVaultKey,
+    #[error("Failed to access the transaction journal: {0}")]
+    JournalError(IoError),
+    #[error("Failed to acquire the transaction lock: {0}")]
+    TransactionLockError(IoError),
+}
+
+/// Find the io error that caused a failed http request, if any
+fn io_error_kind(err: &HttpError) -> Option<IoErrorKind> {
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<IoError>() {
+            return Some(err.kind());
+        }
+        source = err.source();
+    }
+    None
 }
 
 impl Error {
     pub fn is_connection_refused(&self) -> bool {
         matches!(self,
-            Self::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Hyper(HyperError::Io(err))))
-                if err.kind() == IoErrorKind::ConnectionRefused
+            Self::HttpError(err) if io_error_kind(err) == Some(IoErrorKind::ConnectionRefused)
         )
     }
 
     pub fn is_connection_aborted(&self) -> bool {
         matches!(self,
-            Self::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Hyper(HyperError::Io(err))))
-                if err.kind() == IoErrorKind::ConnectionAborted
+            Self::HttpError(err) if io_error_kind(err) == Some(IoErrorKind::ConnectionAborted)
         )
     }
 
@@ -110,6 +176,18 @@ impl Error {
                 if BitcoinRpcError::from(err.clone()) == BitcoinRpcError::RpcInvalidParameter
         )
     }
+
+    /// Whether bitcoind refused a transaction, e.g. because an input is spent already or the
+    /// fee is too low, as opposed to failing to process the request.
+    pub fn is_transaction_rejected(&self) -> bool {
+        matches!(self,
+            Self::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Rpc(err)))
+                if matches!(
+                    BitcoinRpcError::from(err.clone()),
+                    BitcoinRpcError::RpcVerifyError | BitcoinRpcError::RpcVerifyRejected
+                )
+        )
+    }
 }
 
 #[derive(Debug, FromPrimitive, PartialEq, Eq)]
@@ -0,0 +1,313 @@
+//! Control over the fee rate, replaceability and change output of wallet transactions.
+
+use crate::{BitcoinCoreApi, CoinSelection, Error, TransactionMetadata, Txid};
+use futures::future::select_ok;
+use log::{info, warn};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+/// Confirmation target used to estimate the fee rate if only caps are configured.
+pub const DEFAULT_CONF_TARGET: u16 = 6;
+
+/// nSequence of inputs signalling opt-in replace-by-fee (BIP125).
+pub const RBF_SEQUENCE: u32 = 0xfffffffd;
+
+/// Outputs below this value (in satoshis) are not relayed.
+pub const DUST_LIMIT: u64 = 546;
+
+/// Lowest fee rate in sat/vB that is relayed by default.
+pub const MIN_RELAY_FEE_RATE: u64 = 1;
+
+/// Expected time between two blocks, used to turn a deadline into a confirmation target.
+const BLOCK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Largest confirmation target supported by `estimatesmartfee`.
+const MAX_CONF_TARGET: u16 = 1008;
+
+/// How to fund a transaction. The default leaves all choices to bitcoind.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeePolicy {
+    /// Explicit fee rate in sat/vB, takes precedence over `conf_target`.
+    pub fee_rate: Option<u64>,
+    /// Confirmation target in blocks for `estimatesmartfee`.
+    pub conf_target: Option<u16>,
+    /// Lower bound for the explicit or estimated fee rate in sat/vB.
+    pub min_fee_rate: Option<u64>,
+    /// Upper bound for the explicit or estimated fee rate in sat/vB, applied after
+    /// `min_fee_rate`.
+    pub max_fee_rate: Option<u64>,
+    /// Signal opt-in replace-by-fee, bitcoind's `-walletrbf` setting applies if not set.
+    pub replaceable: Option<bool>,
+    /// Position of the change output, random if not set.
+    pub change_position: Option<u32>,
+    /// Fund the transaction from explicitly selected outputs, instead of the ones chosen
+    /// by bitcoind.
+    pub coin_selection: Option<CoinSelection>,
+}
+
+impl FeePolicy {
+    /// Pay exactly `fee_rate` sat/vB.
+    pub fn with_fee_rate(fee_rate: u64) -> Self {
+        Self {
+            fee_rate: Some(fee_rate),
+            ..Default::default()
+        }
+    }
+
+    /// Pay the fee rate estimated for confirmation within `conf_target` blocks.
+    pub fn with_conf_target(conf_target: u16) -> Self {
+        Self {
+            conf_target: Some(conf_target),
+            ..Default::default()
+        }
+    }
+
+    fn clamp(&self, fee_rate: u64) -> u64 {
+        let fee_rate = self.min_fee_rate.map_or(fee_rate, |min| fee_rate.max(min));
+        self.max_fee_rate.map_or(fee_rate, |max| fee_rate.min(max))
+    }
+
+    /// The fee rate in sat/vB to fund the transaction with, or `None` to let bitcoind decide.
+    /// If no estimate is available, the minimum fee rate is used (if set).
+    pub async fn resolve_fee_rate<B: BitcoinCoreApi + Sync>(
+        &self,
+        rpc: &B,
+    ) -> Result<Option<u64>, Error> {
+        if let Some(fee_rate) = self.fee_rate {
+            return Ok(Some(self.clamp(fee_rate)));
+        }
+        let conf_target = match (self.conf_target, self.min_fee_rate, self.max_fee_rate) {
+            (Some(conf_target), _, _) => conf_target,
+            (None, None, None) => return Ok(None),
+            (None, _, _) => DEFAULT_CONF_TARGET,
+        };
+        Ok(rpc
+            .estimate_fee_rate(conf_target)
+            .await?
+            .map(|fee_rate| self.clamp(fee_rate))
+            .or(self.min_fee_rate))
+    }
+}
+
+/// A stuck transaction and the child that spends its wallet output, paying enough fee to
+/// bring the package up to the requested fee rate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChildPaysForParent {
+    pub parent_txid: Txid,
+    pub child_txid: Txid,
+}
+
+/// Fee the child of a package must pay so that the package, i.e. the unconfirmed
+/// ancestors (`package_fee` and `package_vsize`) plus the child, reaches `fee_rate`. The
+/// child pays at least `fee_rate` for itself.
+pub fn child_fee(fee_rate: u64, package_fee: u64, package_vsize: u64, child_vsize: u64) -> u64 {
+    (fee_rate * (package_vsize + child_vsize))
+        .saturating_sub(package_fee)
+        .max(fee_rate * child_vsize)
+}
+
+/// Keeps bumping the fee of an unconfirmed transaction (which must signal opt-in RBF) while
+/// the fee rate estimated to confirm it before the deadline increases.
+#[derive(Debug, Clone)]
+pub struct FeeEscalation {
+    deadline: Instant,
+    check_interval: Duration,
+    max_fee_rate: Option<u64>,
+}
+
+impl FeeEscalation {
+    /// Aim for confirmation before `deadline`, re-estimating the fee rate every block interval.
+    pub fn new(deadline: Instant) -> Self {
+        Self {
+            deadline,
+            check_interval: BLOCK_INTERVAL,
+            max_fee_rate: None,
+        }
+    }
+
+    /// Re-estimate the fee rate every `check_interval` instead.
+    pub fn with_check_interval(mut self, check_interval: Duration) -> Self {
+        self.check_interval = check_interval;
+        self
+    }
+
+    /// Never bump the fee rate above `max_fee_rate` sat/vB.
+    pub fn with_max_fee_rate(mut self, max_fee_rate: u64) -> Self {
+        self.max_fee_rate = Some(max_fee_rate);
+        self
+    }
+
+    /// The number of blocks expected until the deadline, at least one.
+    fn conf_target(&self, now: Instant) -> u16 {
+        let blocks =
+            self.deadline.saturating_duration_since(now).as_secs() / BLOCK_INTERVAL.as_secs();
+        blocks.clamp(1, MAX_CONF_TARGET as u64) as u16
+    }
+
+    /// Wait until the transaction or one of its replacements has `num_confirmations`,
+    /// bumping the fee whenever the estimate for the remaining time exceeds the fee rate
+    /// paid so far. Returns the metadata of the version that confirmed.
+    pub async fn wait_for_confirmation<B: BitcoinCoreApi + Sync>(
+        &self,
+        rpc: &B,
+        txid: Txid,
+        num_confirmations: u32,
+    ) -> Result<TransactionMetadata, Error> {
+        // a replaced version may still be mined, so all of them are watched
+        let mut txids = vec![txid];
+        let mut fee_rate = None;
+        loop {
+            let confirmed = select_ok(
+                txids
+                    .iter()
+                    .map(|txid| rpc.wait_for_transaction_metadata(*txid, num_confirmations)),
+            );
+            if let Ok(result) = timeout(self.check_interval, confirmed).await {
+                return result.map(|(metadata, _)| metadata);
+            }
+
+            let conf_target = self.conf_target(Instant::now());
+            let estimate = match rpc.estimate_fee_rate(conf_target).await? {
+                Some(estimate) => self.max_fee_rate.map_or(estimate, |max| estimate.min(max)),
+                None => continue,
+            };
+            if fee_rate.is_some_and(|fee_rate| estimate <= fee_rate) {
+                continue;
+            }
+
+            let current = *txids.last().expect("txids is never empty");
+            match rpc.bump_fee(&current, Some(estimate)).await {
+                Ok(replacement) => {
+                    info!(
+                        "Replaced {} by {} paying {} sat/vB (target {} blocks)",
+                        current, replacement, estimate, conf_target
+                    );
+                    txids.push(replacement);
+                    fee_rate = Some(estimate);
+                }
+                // the transaction already pays at least the estimated fee rate
+                Err(err) if err.is_invalid_parameter() => fee_rate = Some(estimate),
+                Err(err) => warn!("Failed to bump the fee of {}: {}", current, err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deserialize, mock::MockBitcoinCore, Network, Transaction, TransactionExt, H256};
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn test_resolve_fee_rate() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        btc_rpc.set_fee_estimate(Some(20));
+
+        let resolve = |policy: FeePolicy| {
+            let btc_rpc = btc_rpc.clone();
+            async move { policy.resolve_fee_rate(&btc_rpc).await.unwrap() }
+        };
+
+        assert_eq!(resolve(FeePolicy::default()).await, None);
+        assert_eq!(resolve(FeePolicy::with_fee_rate(5)).await, Some(5));
+        assert_eq!(resolve(FeePolicy::with_conf_target(2)).await, Some(20));
+        assert_eq!(
+            resolve(FeePolicy {
+                max_fee_rate: Some(15),
+                ..Default::default()
+            })
+            .await,
+            Some(15)
+        );
+        assert_eq!(
+            resolve(FeePolicy {
+                fee_rate: Some(5),
+                min_fee_rate: Some(8),
+                ..Default::default()
+            })
+            .await,
+            Some(8)
+        );
+
+        btc_rpc.set_fee_estimate(None);
+        assert_eq!(resolve(FeePolicy::with_conf_target(2)).await, None);
+        assert_eq!(
+            resolve(FeePolicy {
+                conf_target: Some(2),
+                min_fee_rate: Some(3),
+                ..Default::default()
+            })
+            .await,
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn test_child_fee() {
+        // the parent pays 2 sat/vB, the child makes up for the difference
+        assert_eq!(child_fee(10, 400, 200, 110), 10 * 310 - 400);
+        // the parent already pays enough
+        assert_eq!(child_fee(10, 5_000, 200, 110), 10 * 110);
+    }
+
+    #[test]
+    fn test_conf_target() {
+        let now = Instant::now();
+        let conf_target = |remaining| FeeEscalation::new(now + remaining).conf_target(now);
+        assert_eq!(conf_target(Duration::from_secs(0)), 1);
+        assert_eq!(conf_target(Duration::from_secs(5 * 60)), 1);
+        assert_eq!(conf_target(Duration::from_secs(60 * 60)), 6);
+        assert_eq!(
+            conf_target(Duration::from_secs(30 * 24 * 60 * 60)),
+            MAX_CONF_TARGET
+        );
+        // the deadline has passed
+        assert_eq!(FeeEscalation::new(now).conf_target(now + BLOCK_INTERVAL), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fee_escalation() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        btc_rpc.fund_wallet(100_000);
+        let address = btc_rpc.get_new_address().await.unwrap();
+        let request_id = H256::random();
+        let fee_policy = FeePolicy {
+            fee_rate: Some(2),
+            replaceable: Some(true),
+            ..Default::default()
+        };
+        let txid = btc_rpc
+            .create_and_send_transaction(address.clone(), 10_000, Some(request_id), fee_policy)
+            .await
+            .unwrap();
+        btc_rpc.set_fee_estimate(Some(20));
+
+        let escalation = FeeEscalation::new(Instant::now() + Duration::from_secs(60 * 60))
+            .with_check_interval(Duration::from_millis(20))
+            .with_max_fee_rate(15);
+        let mine_replacement = async {
+            while btc_rpc.get_transactions(&[txid]).await.unwrap()[0].is_some() {
+                sleep(Duration::from_millis(10)).await;
+            }
+            btc_rpc.mine_block();
+        };
+        let (metadata, _) = timeout(Duration::from_secs(5), async {
+            tokio::join!(
+                escalation.wait_for_confirmation(&btc_rpc, txid, 1),
+                mine_replacement
+            )
+        })
+        .await
+        .unwrap();
+        let metadata = metadata.unwrap();
+
+        assert_ne!(metadata.txid, txid);
+        let replacement: Transaction = deserialize(&metadata.raw_tx).unwrap();
+        assert_eq!(replacement.get_op_return(), Some(request_id));
+        assert_eq!(
+            replacement.get_payment_amount_to(address.payload),
+            Some(10_000)
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Vault keys managed as a BIP32 extended private key rather than by the bitcoind wallet. The
+//! vault key registered with `VaultRegistry.registerVault` is a hardened child of the master
+//! key, and the deposit key of an issue request is derived from the vault key and the issue
+//! id as `BitcoinKeyDerivation.derivate` does on-chain. Every key can thus be re-derived from
+//! the master key alone, e.g. to restore a lost wallet with
+//! [`recover_deposit_keys`](crate::BitcoinCore::recover_deposit_keys).
+
+use crate::{addr, Error, Network, PrivateKey, PublicKey, H256};
+use bitcoincore_rpc::bitcoin::{
+    secp256k1::{All, Secp256k1},
+    util::bip32::{ChildNumber, ExtendedPrivKey, ExtendedPubKey},
+};
+
+/// Hardened index of the vault key below the master key, i.e. the vault key is at `m/0'`.
+pub const VAULT_KEY_INDEX: u32 = 0;
+
+pub struct VaultMasterKey {
+    secp: Secp256k1<All>,
+    master_key: ExtendedPrivKey,
+    vault_key: ExtendedPrivKey,
+}
+
+impl VaultMasterKey {
+    pub fn new(master_key: ExtendedPrivKey) -> Result<Self, Error> {
+        let secp = Secp256k1::new();
+        let vault_key =
+            master_key.ckd_priv(&secp, ChildNumber::from_hardened_idx(VAULT_KEY_INDEX)?)?;
+        Ok(Self {
+            secp,
+            master_key,
+            vault_key,
+        })
+    }
+
+    /// Master key generated from `seed`, which should be 16 to 64 random bytes.
+    pub fn from_seed(network: Network, seed: &[u8]) -> Result<Self, Error> {
+        Self::new(ExtendedPrivKey::new_master(network, seed)?)
+    }
+
+    /// The master key to back up, from which all other keys are derived.
+    pub fn master_key(&self) -> &ExtendedPrivKey {
+        &self.master_key
+    }
+
+    /// Extended public key of the vault key. Its `public_key` is the key to register with
+    /// `VaultRegistry.registerVault`.
+    pub fn vault_xpub(&self) -> ExtendedPubKey {
+        ExtendedPubKey::from_private(&self.secp, &self.vault_key)
+    }
+
+    pub fn vault_public_key(&self) -> PublicKey {
+        self.vault_xpub().public_key
+    }
+
+    pub fn vault_private_key(&self) -> PrivateKey {
+        self.vault_key.private_key
+    }
+
+    /// Private key of the deposit address of `issue_id`.
+    pub fn deposit_private_key(&self, issue_id: H256) -> Result<PrivateKey, Error> {
+        Ok(PrivateKey {
+            key: addr::derive_deposit_secret_key(self.vault_key.private_key.key, issue_id)?,
+            ..self.vault_key.private_key
+        })
+    }
+
+    /// Public key of the deposit address of `issue_id`, as derived by the contract.
+    pub fn deposit_public_key(&self, issue_id: H256) -> Result<PublicKey, Error> {
+        Ok(PublicKey {
+            compressed: true,
+            key: addr::derive_deposit_public_key(&self.vault_public_key().key, issue_id)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::util::bip32::DerivationPath;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_deposit_keys_are_deterministic() {
+        let master_key = VaultMasterKey::from_seed(Network::Regtest, &[0x42; 32]).unwrap();
+        let vault_key = master_key
+            .master_key()
+            .derive_priv(
+                &Secp256k1::new(),
+                &DerivationPath::from_str("m/0'").unwrap(),
+            )
+            .unwrap();
+        assert_eq!(master_key.vault_private_key(), vault_key.private_key);
+
+        // restored from the backup of the master key
+        let restored = VaultMasterKey::new(*master_key.master_key()).unwrap();
+        assert_eq!(restored.vault_xpub(), master_key.vault_xpub());
+
+        let issue_id = H256::from_low_u64_be(7);
+        let private_key = restored.deposit_private_key(issue_id).unwrap();
+        assert_eq!(
+            PublicKey::from_private_key(&Secp256k1::new(), &private_key),
+            master_key.deposit_public_key(issue_id).unwrap()
+        );
+        assert_eq!(
+            master_key.deposit_public_key(issue_id).unwrap().key,
+            addr::derive_deposit_public_key(&master_key.vault_public_key().key, issue_id).unwrap()
+        );
+        assert_ne!(
+            master_key
+                .deposit_public_key(H256::from_low_u64_be(8))
+                .unwrap(),
+            master_key.deposit_public_key(issue_id).unwrap()
+        );
+    }
+}
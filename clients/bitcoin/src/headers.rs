@@ -0,0 +1,247 @@
+//! Ordered feed of main chain block headers, as submitted to the relay contract via
+//! `submitBlockHeader` and `submitBlockHeaderBatch`.
+
+use crate::{serialize, BitcoinCoreApi, BlockHash, BlockHeader, Error, RETRY_DURATION};
+use futures::stream::{self, BoxStream, StreamExt};
+use log::{info, warn};
+use std::collections::VecDeque;
+use tokio::time::sleep;
+
+/// Maximum number of headers per batch unless configured otherwise.
+pub const DEFAULT_BATCH_SIZE: usize = 16;
+
+/// Number of emitted block hashes that are remembered to find the fork point after a reorg.
+pub const MAX_REORG_DEPTH: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderBatch {
+    /// Height of the first header in the batch.
+    pub start_height: u32,
+    /// Consecutive main chain headers, each extending the previous one.
+    pub headers: Vec<BlockHeader>,
+    /// Number of previously emitted headers that are no longer in the main chain. If
+    /// non-zero, the batch extends the fork point instead of the previous batch.
+    pub reorg_depth: u32,
+}
+
+impl HeaderBatch {
+    /// The concatenated 80-byte headers, as expected by `submitBlockHeaderBatch`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.headers.iter().flat_map(serialize).collect()
+    }
+}
+
+/// Streams the headers of the main chain in order, starting at a given height.
+pub struct BlockHeaderStream<B> {
+    rpc: B,
+    next_height: u32,
+    batch_size: usize,
+    /// Hashes of the most recently emitted headers, the last one is at `next_height - 1`.
+    emitted: VecDeque<BlockHash>,
+}
+
+impl<B: BitcoinCoreApi + Send + Sync + 'static> BlockHeaderStream<B> {
+    pub fn new(rpc: B, start_height: u32) -> Self {
+        Self {
+            rpc,
+            next_height: start_height,
+            batch_size: DEFAULT_BATCH_SIZE,
+            emitted: VecDeque::new(),
+        }
+    }
+
+    /// Emit at most `batch_size` headers at once.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Height of the next header that will be emitted.
+    pub fn next_height(&self) -> u32 {
+        self.next_height
+    }
+
+    /// Wait until at least one new header is available and return all available headers,
+    /// up to the batch size. After a reorg the batch starts just after the fork point.
+    pub async fn next_batch(&mut self) -> Result<HeaderBatch, Error> {
+        let mut reorg_depth = 0;
+        loop {
+            reorg_depth += self.rewind_to_main_chain().await?;
+
+            let tip = self.rpc.get_block_count().await? as u32;
+            if tip < self.next_height {
+                sleep(RETRY_DURATION).await;
+                continue;
+            }
+
+            let last_height = tip.min(self.next_height + self.batch_size as u32 - 1);
+            let heights: Vec<u32> = (self.next_height..=last_height).collect();
+            let mut headers = Vec::with_capacity(heights.len());
+            for hash in self.rpc.get_block_hashes(&heights).await? {
+                headers.push(self.rpc.get_block_header(&hash).await?);
+            }
+
+            // the main chain may have changed while fetching
+            if !self.extends_emitted(&headers) {
+                continue;
+            }
+
+            let batch = HeaderBatch {
+                start_height: self.next_height,
+                headers,
+                reorg_depth,
+            };
+            self.emitted
+                .extend(batch.headers.iter().map(BlockHeader::block_hash));
+            if self.emitted.len() > MAX_REORG_DEPTH {
+                self.emitted.drain(..self.emitted.len() - MAX_REORG_DEPTH);
+            }
+            self.next_height += batch.headers.len() as u32;
+            return Ok(batch);
+        }
+    }
+
+    /// Stream of all header batches, see [`BlockHeaderStream::next_batch`].
+    pub fn into_stream(self) -> BoxStream<'static, Result<HeaderBatch, Error>> {
+        stream::unfold(self, |mut headers| async move {
+            let batch = headers.next_batch().await;
+            Some((batch, headers))
+        })
+        .boxed()
+    }
+
+    fn extends_emitted(&self, headers: &[BlockHeader]) -> bool {
+        let mut prev_blockhash = self.emitted.back().copied();
+        headers.iter().all(|header| {
+            let extends = prev_blockhash.is_none_or(|hash| header.prev_blockhash == hash);
+            prev_blockhash = Some(header.block_hash());
+            extends
+        })
+    }
+
+    /// Forget the emitted headers that are no longer in the main chain, returning how many.
+    async fn rewind_to_main_chain(&mut self) -> Result<u32, Error> {
+        let first_height = self.next_height - self.emitted.len() as u32;
+        for (index, emitted) in self.emitted.iter().enumerate().rev() {
+            let height = first_height + index as u32;
+            match self.rpc.get_block_hash(height).await {
+                Ok(hash) if &hash == emitted => {
+                    let reorg_depth = self.next_height - height - 1;
+                    if reorg_depth > 0 {
+                        warn!("Reorg of {} block(s) after height {}", reorg_depth, height);
+                        self.emitted.truncate(index + 1);
+                        self.next_height = height + 1;
+                    }
+                    return Ok(reorg_depth);
+                }
+                // the main chain may also have become shorter
+                Ok(_) | Err(Error::InvalidBitcoinHeight) => {
+                    info!(
+                        "Block {} at height {} is no longer in the main chain",
+                        emitted, height
+                    )
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        if self.emitted.is_empty() {
+            Ok(0)
+        } else {
+            Err(Error::ReorgTooDeep(first_height))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock::MockBitcoinCore, Network};
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn hashes(batch: &HeaderBatch) -> Vec<BlockHash> {
+        batch.headers.iter().map(BlockHeader::block_hash).collect()
+    }
+
+    #[tokio::test]
+    async fn test_batches_in_order() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let mined = btc_rpc.mine_blocks(5);
+
+        let batches: Vec<_> = BlockHeaderStream::new(btc_rpc.clone(), 1)
+            .with_batch_size(2)
+            .into_stream()
+            .take(3)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        assert_eq!(
+            batches
+                .iter()
+                .map(|batch| batch.start_height)
+                .collect::<Vec<_>>(),
+            vec![1, 3, 5]
+        );
+        assert_eq!(batches.iter().flat_map(hashes).collect::<Vec<_>>(), mined);
+        assert!(batches.iter().all(|batch| batch.reorg_depth == 0));
+
+        let bytes = batches[0].to_bytes();
+        assert_eq!(bytes.len(), 160);
+        assert_eq!(&bytes[80..], &serialize(&batches[0].headers[1])[..]);
+    }
+
+    #[tokio::test]
+    async fn test_waits_for_next_block() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        btc_rpc.mine_blocks(1);
+        let mut headers = BlockHeaderStream::new(btc_rpc.clone(), 1);
+        headers.next_batch().await.unwrap();
+
+        let mine = async {
+            sleep(Duration::from_millis(100)).await;
+            btc_rpc.mine_block()
+        };
+        let (batch, mined) =
+            tokio::join!(timeout(Duration::from_secs(5), headers.next_batch()), mine);
+        let batch = batch.unwrap().unwrap();
+        assert_eq!(batch.start_height, 2);
+        assert_eq!(hashes(&batch), vec![mined]);
+    }
+
+    #[tokio::test]
+    async fn test_reorg() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let mined = btc_rpc.mine_blocks(4);
+        let mut headers = BlockHeaderStream::new(btc_rpc.clone(), 1);
+        headers.next_batch().await.unwrap();
+
+        // replace the blocks at height 3 and 4 by a longer fork
+        btc_rpc.invalidate_block(&mined[2]);
+        let fork = btc_rpc.mine_blocks(3);
+
+        let batch = headers.next_batch().await.unwrap();
+        assert_eq!(batch.start_height, 3);
+        assert_eq!(batch.reorg_depth, 2);
+        assert_eq!(batch.headers[0].prev_blockhash, mined[1]);
+        assert_eq!(hashes(&batch), fork);
+        assert_eq!(headers.next_height(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_reorg_deeper_than_emitted() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let mined = btc_rpc.mine_blocks(4);
+        let mut headers = BlockHeaderStream::new(btc_rpc.clone(), 3);
+        headers.next_batch().await.unwrap();
+
+        btc_rpc.invalidate_block(&mined[0]);
+        btc_rpc.mine_blocks(5);
+
+        assert!(matches!(
+            headers.next_batch().await,
+            Err(Error::ReorgTooDeep(3))
+        ));
+    }
+}
@@ -0,0 +1,393 @@
+//! Durable record of the transactions sent by this client. A [`LockedTransaction`] only lives
+//! in memory, so a crash between creating a transaction and its confirmation would lose
+//! track of it. With a journal, every signed transaction is recorded before it is broadcast,
+//! and the pending ones are broadcast again on startup by
+//! [`rebroadcast_pending`](crate::BitcoinCoreApi::rebroadcast_pending).
+
+use crate::{
+    deserialize, serialize, ConversionError, Error, LockedTransaction, Transaction, TransactionExt,
+    Txid, H256,
+};
+use async_trait::async_trait;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionStatus {
+    /// Recorded before broadcast, not yet confirmed.
+    Pending,
+    /// Mined with the number of confirmations that was waited for.
+    Confirmed,
+    /// An input was spent by another transaction, e.g. a fee bump.
+    Conflicted,
+    /// Rejected by bitcoind or no longer known to it.
+    Dropped,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    pub transaction: Transaction,
+    pub recipient: String,
+    pub request_id: Option<H256>,
+    pub status: TransactionStatus,
+}
+
+impl JournalEntry {
+    /// Entry of a transaction that is about to be broadcast.
+    pub fn pending(transaction: &LockedTransaction) -> Self {
+        Self {
+            transaction: transaction.transaction.clone(),
+            recipient: transaction.recipient.clone(),
+            request_id: transaction.request_id,
+            status: TransactionStatus::Pending,
+        }
+    }
+
+    pub fn txid(&self) -> Txid {
+        self.transaction.txid()
+    }
+}
+
+#[async_trait]
+pub trait TransactionJournal: Send + Sync {
+    /// Add `entry`, replacing an earlier entry of the same transaction. Must be durable
+    /// when it returns.
+    async fn record(&self, entry: JournalEntry) -> Result<(), Error>;
+
+    /// Update the status of a recorded transaction, unknown transactions are ignored.
+    async fn set_status(&self, txid: &Txid, status: TransactionStatus) -> Result<(), Error>;
+
+    async fn entries(&self) -> Result<Vec<JournalEntry>, Error>;
+
+    /// Entries that have not been confirmed, conflicted or dropped yet.
+    async fn pending(&self) -> Result<Vec<JournalEntry>, Error> {
+        let mut entries = self.entries().await?;
+        entries.retain(|entry| entry.status == TransactionStatus::Pending);
+        Ok(entries)
+    }
+}
+
+/// Update the status in `journal`, if any. A failed update is only logged: the transaction
+/// remains pending, which at worst causes a redundant rebroadcast.
+pub(crate) async fn update_status(
+    journal: &Option<Arc<dyn TransactionJournal>>,
+    txid: &Txid,
+    status: TransactionStatus,
+) {
+    if let Some(journal) = journal {
+        if let Err(err) = journal.set_status(txid, status).await {
+            warn!("Failed to mark {} as {:?}: {}", txid, status, err);
+        }
+    }
+}
+
+/// Request id that `transaction` was recorded with in `journal`, or the payload of its
+/// OP_RETURN if it was not recorded, e.g. because it was sent without a journal.
+pub(crate) async fn request_id(
+    journal: &Option<Arc<dyn TransactionJournal>>,
+    transaction: &Transaction,
+) -> Result<Option<H256>, Error> {
+    if let Some(journal) = journal {
+        let txid = transaction.txid();
+        if let Some(entry) = journal
+            .entries()
+            .await?
+            .into_iter()
+            .find(|entry| entry.txid() == txid)
+        {
+            return Ok(entry.request_id);
+        }
+    }
+    Ok(transaction.get_op_return())
+}
+
+/// Serialized form of a [`JournalEntry`].
+#[derive(Serialize, Deserialize)]
+struct Record {
+    txid: Txid,
+    raw_tx: String,
+    recipient: String,
+    request_id: Option<String>,
+    status: TransactionStatus,
+}
+
+impl From<&JournalEntry> for Record {
+    fn from(entry: &JournalEntry) -> Self {
+        Self {
+            txid: entry.txid(),
+            raw_tx: hex::encode(serialize(&entry.transaction)),
+            recipient: entry.recipient.clone(),
+            request_id: entry
+                .request_id
+                .map(|request_id| hex::encode(request_id.as_bytes())),
+            status: entry.status,
+        }
+    }
+}
+
+impl Record {
+    fn into_entry(self) -> Result<JournalEntry, Error> {
+        let raw_tx = hex::decode(&self.raw_tx).map_err(ConversionError::from)?;
+        let request_id = match self.request_id {
+            Some(request_id) => {
+                let bytes = hex::decode(request_id).map_err(ConversionError::from)?;
+                if bytes.len() != 32 {
+                    return Err(ConversionError::InvalidFormat.into());
+                }
+                Some(H256::from_slice(&bytes))
+            }
+            None => None,
+        };
+        Ok(JournalEntry {
+            transaction: deserialize(&raw_tx)?,
+            recipient: self.recipient,
+            request_id,
+            status: self.status,
+        })
+    }
+}
+
+/// How many confirmed, conflicted or dropped entries a [`FileJournal`] keeps by default.
+const DEFAULT_RETENTION: usize = 1_000;
+
+/// Keeps the journal in a JSON file, which is rewritten atomically on every change. Only
+/// one process may use the file at a time.
+pub struct FileJournal {
+    path: PathBuf,
+    /// Serializes the read-modify-write cycles of this process.
+    lock: Arc<Mutex<()>>,
+    retention: usize,
+}
+
+impl FileJournal {
+    /// Use the journal at `path`, which is created on the first write.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            lock: Arc::new(Mutex::new(())),
+            retention: DEFAULT_RETENTION,
+        }
+    }
+
+    /// Keep only the `retention` most recently recorded entries that are no longer pending,
+    /// so that the file does not grow without bounds. Pending entries are always kept.
+    pub fn with_retention(mut self, retention: usize) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    fn read(path: &Path) -> Result<Vec<Record>, Error> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(Error::JournalError(err)),
+        }
+    }
+
+    fn write(path: &Path, records: &[Record]) -> Result<(), Error> {
+        // the rename replaces the journal atomically, so a crash leaves either version
+        let tmp_path = path.with_extension("tmp");
+        let write = || {
+            let file = fs::File::create(&tmp_path)?;
+            serde_json::to_writer_pretty(&file, records)?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, path)?;
+            // the rename itself is only durable once the directory is synced
+            #[cfg(unix)]
+            {
+                let dir = match path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                };
+                fs::File::open(dir)?.sync_all()?;
+            }
+            Ok(())
+        };
+        write().map_err(Error::JournalError)
+    }
+
+    /// Remove the oldest finished records beyond the `retention` most recent ones.
+    fn prune(records: &mut Vec<Record>, retention: usize) {
+        let finished = records
+            .iter()
+            .filter(|record| record.status != TransactionStatus::Pending)
+            .count();
+        let mut excess = finished.saturating_sub(retention);
+        records.retain(|record| {
+            if excess > 0 && record.status != TransactionStatus::Pending {
+                excess -= 1;
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Run `f` on the file while holding the lock, on a thread where blocking is allowed.
+    async fn with_file<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Path) -> Result<T, Error> + Send + 'static,
+    {
+        let (path, lock) = (self.path.clone(), self.lock.clone());
+        tokio::task::spawn_blocking(move || {
+            let _guard = lock.lock().expect("journal lock poisoned");
+            f(&path)
+        })
+        .await
+        .map_err(|err| Error::JournalError(io::Error::other(err)))?
+    }
+
+    async fn update<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Vec<Record>) + Send + 'static,
+    {
+        let retention = self.retention;
+        self.with_file(move |path| {
+            let mut records = Self::read(path)?;
+            f(&mut records);
+            Self::prune(&mut records, retention);
+            Self::write(path, &records)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl TransactionJournal for FileJournal {
+    async fn record(&self, entry: JournalEntry) -> Result<(), Error> {
+        let record = Record::from(&entry);
+        self.update(move |records| {
+            records.retain(|existing| existing.txid != record.txid);
+            records.push(record);
+        })
+        .await
+    }
+
+    async fn set_status(&self, txid: &Txid, status: TransactionStatus) -> Result<(), Error> {
+        let txid = *txid;
+        self.update(move |records| {
+            for record in records.iter_mut().filter(|record| record.txid == txid) {
+                record.status = status;
+            }
+        })
+        .await
+    }
+
+    async fn entries(&self) -> Result<Vec<JournalEntry>, Error> {
+        self.with_file(|path| {
+            Self::read(path)?
+                .into_iter()
+                .map(Record::into_entry)
+                .collect()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OutPoint, Script, TxIn, TxOut};
+
+    fn entry(value: u64, request_id: Option<H256>) -> JournalEntry {
+        JournalEntry {
+            transaction: Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![TxIn {
+                    previous_output: OutPoint::new(Default::default(), 0),
+                    script_sig: Script::new(),
+                    sequence: u32::MAX,
+                    witness: vec![vec![0x01; 72], vec![0x02; 33]],
+                }],
+                output: vec![TxOut {
+                    value,
+                    script_pubkey: Script::new(),
+                }],
+            },
+            recipient: "bcrt1qrecipient".to_string(),
+            request_id,
+            status: TransactionStatus::Pending,
+        }
+    }
+
+    #[test]
+    fn test_pending_entry_request_id() {
+        // the request id the transaction was created for, not read back from its outputs
+        let request_id = Some(H256::from_low_u64_be(1));
+        let transaction = entry(1_000, None).transaction;
+        let locked = LockedTransaction::new(transaction, vec![], request_id, None);
+        assert_eq!(JournalEntry::pending(&locked).request_id, request_id);
+    }
+
+    #[tokio::test]
+    async fn test_file_journal_persists_entries() {
+        let dir = std::env::temp_dir().join(format!("journal-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("journal.json");
+        let _ = fs::remove_file(&path);
+
+        let journal = FileJournal::new(&path);
+        assert!(journal.entries().await.unwrap().is_empty());
+        let paid = entry(1_000, Some(H256::from_low_u64_be(1)));
+        let change = entry(2_000, None);
+        journal.record(paid.clone()).await.unwrap();
+        journal.record(change.clone()).await.unwrap();
+        journal
+            .set_status(&paid.txid(), TransactionStatus::Confirmed)
+            .await
+            .unwrap();
+
+        // e.g. after a restart
+        let journal = FileJournal::new(&path);
+        let entries = journal.entries().await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].status, TransactionStatus::Confirmed);
+        assert_eq!(entries[0].request_id, paid.request_id);
+        assert_eq!(entries[0].transaction, paid.transaction);
+        assert_eq!(journal.pending().await.unwrap(), vec![change]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_journal_prunes_finished_entries() {
+        let dir = std::env::temp_dir().join(format!("journal-prune-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("journal.json");
+        let _ = fs::remove_file(&path);
+
+        let journal = FileJournal::new(&path).with_retention(2);
+        let pending = entry(1_000, None);
+        journal.record(pending.clone()).await.unwrap();
+        let finished: Vec<_> = (2..6).map(|value| entry(value * 1_000, None)).collect();
+        for entry in finished.iter() {
+            journal.record(entry.clone()).await.unwrap();
+            journal
+                .set_status(&entry.txid(), TransactionStatus::Confirmed)
+                .await
+                .unwrap();
+        }
+
+        // the oldest finished entries are removed, pending ones stay regardless of age
+        let txids: Vec<_> = journal
+            .entries()
+            .await
+            .unwrap()
+            .iter()
+            .map(JournalEntry::txid)
+            .collect();
+        assert_eq!(
+            txids,
+            vec![pending.txid(), finished[2].txid(), finished[3].txid()]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,11 +2,35 @@
 pub mod cli;
 
 mod addr;
+pub mod batch;
+pub mod chain;
+pub mod coin_selection;
+pub mod descriptor;
 mod error;
-
-use addr::H256;
+pub mod fee;
+pub mod hd;
+pub mod headers;
+pub mod journal;
+pub mod lock;
+pub mod policy;
+pub mod psbt;
+pub mod relay;
+mod rpc;
+pub mod signer;
+pub mod tracker;
+pub mod validate;
+pub mod zmq;
+
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+
+pub use addr::{
+    calculate_deposit_scalar, derive_deposit_address, derive_deposit_key_hash,
+    derive_deposit_public_key, derive_deposit_secret_key, H160, H256,
+};
 use async_trait::async_trait;
 use backoff::{backoff::Backoff, future::retry, ExponentialBackoff};
+pub use batch::Payout;
 pub use bitcoincore_rpc::{
     bitcoin::{
         blockdata,
@@ -15,22 +39,38 @@ pub use bitcoincore_rpc::{
         hashes::{hex::ToHex, Hash},
         secp256k1::{constants::PUBLIC_KEY_SIZE, SecretKey},
         util::address::Payload,
-        Address, Amount, Block, BlockHeader, Network, PrivateKey, PubkeyHash, PublicKey, Script,
-        ScriptHash, Transaction, TxIn, Txid, WPubkeyHash, WScriptHash,
+        Address, Amount, Block, BlockHeader, Network, OutPoint, PrivateKey, PubkeyHash, PublicKey,
+        Script, ScriptHash, Transaction, TxIn, TxOut, Txid, WPubkeyHash, WScriptHash,
     },
     bitcoincore_rpc_json::{GetTransactionResult, WalletTxInfo},
     json::{self, AddressType, GetBlockResult},
     jsonrpc::{error::RpcError, Error as JsonRpcError},
     Auth, Client, Error as BitcoinError, RpcApi,
 };
+use coin_selection::DEPOSIT_LABEL;
+pub use coin_selection::{CoinSelection, SelectionStrategy, Utxo};
+pub use descriptor::WalletType;
 pub use error::{BitcoinRpcError, ConversionError, Error};
-use hyper::Error as HyperError;
+pub use fee::{ChildPaysForParent, FeePolicy};
+use fee::{DEFAULT_CONF_TARGET, DUST_LIMIT, MIN_RELAY_FEE_RATE};
+use futures::{stream::BoxStream, StreamExt};
+pub use hd::VaultMasterKey;
+pub use journal::{FileJournal, JournalEntry, TransactionJournal, TransactionStatus};
+pub use lock::{
+    InputReservation, ProcessLock, TransactionLock, TransactionLockGuard, UtxoReservation,
+};
 use log::{info, trace};
+pub use policy::{BroadcastPolicy, PolicyError};
+pub use psbt::{LockedPsbt, PartiallySignedTransaction};
+pub use rpc::{RpcClient, DEFAULT_REQUEST_TIMEOUT};
 use serde_json::error::Category as SerdeJsonCategory;
-use std::io::ErrorKind as IoErrorKind;
+pub use signer::{Signer, SoftwareSigner};
 use std::{future::Future, sync::Arc, time::Duration};
-use tokio::sync::{Mutex, OwnedMutexGuard};
-use tokio::time::{sleep, timeout};
+use tokio::{
+    sync::OnceCell,
+    time::{sleep, timeout},
+};
+pub use zmq::ZmqConfig;
 
 #[macro_use]
 extern crate num_derive;
@@ -39,11 +79,17 @@ const NOT_IN_MEMPOOL_ERROR_CODE: i32 = BitcoinRpcError::RpcInvalidAddressOrKey a
 
 const RETRY_DURATION: Duration = Duration::from_millis(1000);
 
+/// Number of most recent wallet transactions searched for an earlier payment of a request.
+const REQUEST_SEARCH_DEPTH: usize = 1000;
+
 #[derive(Debug, Clone)]
 pub struct TransactionMetadata {
     pub txid: Txid,
     pub proof: Vec<u8>,
     pub raw_tx: Vec<u8>,
+    /// `raw_tx` without witness data, whose hash is the txid. Submit this to the contracts,
+    /// which identify transactions by the hash of the raw transaction.
+    pub stripped_raw_tx: Vec<u8>,
     pub block_height: u32,
     pub block_hash: BlockHash,
 }
@@ -60,6 +106,8 @@ pub trait BitcoinCoreApi {
 
     async fn get_block_hash(&self, height: u32) -> Result<BlockHash, Error>;
 
+    async fn get_block_hashes(&self, heights: &[u32]) -> Result<Vec<BlockHash>, Error>;
+
     async fn is_block_known(&self, block_hash: BlockHash) -> Result<bool, Error>;
 
     async fn get_new_address(&self) -> Result<Address, Error>;
@@ -86,26 +134,75 @@ pub trait BitcoinCoreApi {
         &'a self,
     ) -> Result<Box<dyn Iterator<Item = Result<Transaction, Error>> + Send + 'a>, Error>;
 
+    async fn get_transactions(&self, txids: &[Txid]) -> Result<Vec<Option<Transaction>>, Error>;
+
+    async fn find_transaction_block(&self, txid: &Txid) -> Result<Option<BlockHash>, Error>;
+
+    async fn find_request_payment(&self, request_id: H256) -> Result<Option<Txid>, Error>;
+
+    async fn wait_for_payment(
+        &self,
+        payload: Payload,
+        min_amount: Option<u64>,
+        since_height: u32,
+        num_confirmations: u32,
+    ) -> Result<Vec<TransactionMetadata>, Error>;
+
     async fn wait_for_transaction_metadata(
         &self,
         txid: Txid,
         num_confirmations: u32,
     ) -> Result<TransactionMetadata, Error>;
 
+    async fn estimate_fee_rate(&self, conf_target: u16) -> Result<Option<u64>, Error>;
+
+    async fn list_utxos(&self, min_confirmations: u32) -> Result<Vec<Utxo>, Error>;
+
+    async fn lock_utxos(&self, outpoints: &[OutPoint]) -> Result<(), Error>;
+
+    async fn unlock_utxos(&self, outpoints: &[OutPoint]) -> Result<(), Error>;
+
     async fn create_transaction(
         &self,
         address: Address,
         sat: u64,
         request_id: Option<H256>,
+        fee_policy: FeePolicy,
     ) -> Result<LockedTransaction, Error>;
 
+    async fn create_batch_transaction(
+        &self,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+    ) -> Result<LockedTransaction, Error>;
+
+    async fn create_psbt(
+        &self,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+    ) -> Result<LockedPsbt, Error>;
+
+    async fn finalize_psbt(&self, psbt: LockedPsbt) -> Result<LockedTransaction, Error>;
+
     async fn send_transaction(&self, transaction: LockedTransaction) -> Result<Txid, Error>;
 
+    async fn rebroadcast_pending(&self) -> Result<Vec<Txid>, Error>;
+
     async fn create_and_send_transaction(
         &self,
         address: Address,
         sat: u64,
         request_id: Option<H256>,
+        fee_policy: FeePolicy,
+    ) -> Result<Txid, Error>;
+
+    async fn create_and_send_batch_transaction(
+        &self,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
     ) -> Result<Txid, Error>;
 
     async fn send_to_address(
@@ -114,8 +211,17 @@ pub trait BitcoinCoreApi {
         sat: u64,
         request_id: Option<H256>,
         num_confirmations: u32,
+        fee_policy: FeePolicy,
     ) -> Result<TransactionMetadata, Error>;
 
+    async fn bump_fee(&self, txid: &Txid, fee_rate: Option<u64>) -> Result<Txid, Error>;
+
+    async fn bump_fee_with_child(
+        &self,
+        txid: &Txid,
+        fee_rate: u64,
+    ) -> Result<ChildPaysForParent, Error>;
+
     async fn create_or_load_wallet(&self) -> Result<(), Error>;
 
     async fn wallet_has_public_key<P>(&self, public_key: P) -> Result<bool, Error>
@@ -136,19 +242,42 @@ pub trait BitcoinCoreApi {
 pub struct LockedTransaction {
     pub transaction: Transaction,
     pub recipient: String,
-    _lock: Option<OwnedMutexGuard<()>>,
+    /// The payments and request id the transaction was created for, which the
+    /// [`BroadcastPolicy`] verifies.
+    pub payouts: Vec<Payout>,
+    pub request_id: Option<H256>,
+    _lock: Option<TransactionLockGuard>,
+    reservation: Option<InputReservation>,
 }
 
 impl LockedTransaction {
     pub fn new(
         transaction: Transaction,
-        recipient: String,
-        lock: Option<OwnedMutexGuard<()>>,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        lock: Option<TransactionLockGuard>,
     ) -> Self {
         LockedTransaction {
             transaction,
-            recipient,
+            recipient: batch::recipients(&payouts),
+            payouts,
+            request_id,
             _lock: lock,
+            reservation: None,
+        }
+    }
+
+    /// Unlock the inputs in `reservation` if the transaction is dropped without being sent.
+    pub fn with_reservation(mut self, reservation: Option<InputReservation>) -> Self {
+        self.reservation = reservation;
+        self
+    }
+
+    /// Keep the inputs locked when dropped, because the transaction was sent or the inputs
+    /// were unlocked explicitly.
+    pub(crate) fn disarm_reservation(&mut self) {
+        if let Some(ref mut reservation) = self.reservation {
+            reservation.disarm();
         }
     }
 }
@@ -165,13 +294,70 @@ fn get_exponential_backoff() -> ExponentialBackoff {
     }
 }
 
+/// The inputs and outputs arguments of `createrawtransaction` and `walletcreatefundedpsbt`.
+fn raw_transaction_args(
+    inputs: &[OutPoint],
+    payouts: &[Payout],
+    request_id: Option<H256>,
+) -> Result<(serde_json::Value, serde_json::Value), Error> {
+    let inputs: Vec<_> = inputs
+        .iter()
+        .map(|outpoint| json::CreateRawTransactionInput {
+            txid: outpoint.txid,
+            vout: outpoint.vout,
+            sequence: None,
+        })
+        .collect();
+
+    // outputs are passed as an array of single-entry objects to keep their order
+    let mut outputs: Vec<_> = payouts
+        .iter()
+        .map(|payout| {
+            serde_json::json!({
+                payout.address.to_string(): Amount::from_sat(payout.sat).as_btc()
+            })
+        })
+        .collect();
+
+    if let Some(request_id) = request_id {
+        // add the op_return data - bitcoind will add op_return and the length automatically.
+        // It follows the first payment, the same layout as a transaction with one payment
+        outputs.insert(1, serde_json::json!({ "data": request_id.to_hex() }));
+    }
+
+    Ok((
+        serde_json::to_value(inputs)?,
+        serde_json::to_value(outputs)?,
+    ))
+}
+
+/// Serialize `transaction` in the legacy format without witness data, which is hashed to
+/// the txid. The default serialization includes the witness of segwit transactions.
+pub fn serialize_without_witness(transaction: &Transaction) -> Vec<u8> {
+    let mut transaction = transaction.clone();
+    for input in transaction.input.iter_mut() {
+        input.witness.clear();
+    }
+    serialize(&transaction)
+}
+
 #[derive(Clone)]
 pub struct BitcoinCore {
-    rpc: Arc<Client>,
+    rpc: RpcClient,
     wallet_name: Option<String>,
     network: Network,
-    transaction_creation_lock: Arc<Mutex<()>>,
+    transaction_creation_lock: Arc<dyn TransactionLock>,
     connection_timeout: Duration,
+    zmq: ZmqConfig,
+    signer: Option<Arc<dyn Signer>>,
+    /// Type of wallet to create if it does not exist yet.
+    new_wallet_type: Option<WalletType>,
+    /// Type of the loaded wallet, detected on first use.
+    wallet_type: Arc<OnceCell<WalletType>>,
+    vault_key: Option<Arc<VaultMasterKey>>,
+    idempotent_payments: bool,
+    journal: Option<Arc<dyn TransactionJournal>>,
+    broadcast_policy: Option<BroadcastPolicy>,
 }
 
 impl BitcoinCore {
@@ -187,28 +373,228 @@ impl BitcoinCore {
             None => url,
         };
         Ok(Self {
-            rpc: Arc::new(Client::new(url, auth)?),
+            rpc: RpcClient::new(url, auth, DEFAULT_REQUEST_TIMEOUT)?,
             wallet_name,
             network,
-            transaction_creation_lock: Arc::new(Mutex::new(())),
+            transaction_creation_lock: Arc::new(ProcessLock::default()),
             connection_timeout,
+            zmq: Default::default(),
+            signer: None,
+            new_wallet_type: None,
+            wallet_type: Default::default(),
+            vault_key: None,
+            idempotent_payments: false,
+            journal: None,
+            broadcast_policy: None,
         })
     }
 
+    /// Set the timeout after which a single rpc request to bitcoin-core fails. The
+    /// connection pool is shared with the previous client.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.rpc = self.rpc.with_request_timeout(request_timeout);
+        self
+    }
+
+    /// Receive notifications from the given zmq endpoints of bitcoin-core instead of polling.
+    pub fn with_zmq(mut self, zmq: ZmqConfig) -> Self {
+        self.zmq = zmq;
+        self
+    }
+
+    /// Guard transaction creation with `lock` instead of a lock private to this client (and
+    /// its clones), e.g. a [`FileLock`](lock::FileLock) shared with other processes using
+    /// the same wallet.
+    pub fn with_transaction_lock<L: TransactionLock + 'static>(mut self, lock: L) -> Self {
+        self.transaction_creation_lock = Arc::new(lock);
+        self
+    }
+
+    /// Operate a watch-only wallet whose keys are held by `signer`: the wallet is created
+    /// without private keys and tracks the addresses of the signer, and wallet transactions
+    /// are funded as PSBTs that the signer signs. Fee bumping and deposit keys still require
+    /// the private keys in the wallet.
+    pub fn with_signer<S: Signer + 'static>(mut self, signer: S) -> Self {
+        self.signer = Some(Arc::new(signer));
+        self
+    }
+
+    /// Create the wallet as `wallet_type` if it does not exist, instead of the default type of
+    /// bitcoin-core. Existing wallets are used as they are.
+    pub fn with_wallet_type(mut self, wallet_type: WalletType) -> Self {
+        self.new_wallet_type = Some(wallet_type);
+        self
+    }
+
+    /// Use the vault key derived from `vault_key` instead of a new wallet key, see
+    /// [`hd`]. The vault key is imported into the wallet when it is loaded, and the private
+    /// keys of its deposit addresses are derived from it rather than exported from the wallet.
+    pub fn with_vault_key(mut self, vault_key: VaultMasterKey) -> Self {
+        self.vault_key = Some(Arc::new(vault_key));
+        self
+    }
+
+    /// Before paying for a request, check whether an earlier payment carrying the same
+    /// request id was already sent (see [`BitcoinCoreApi::find_request_payment`]) and return
+    /// its txid instead of paying twice, e.g. when the txid was lost in a restart. Concurrent
+    /// payments of the same request are not detected.
+    pub fn with_idempotent_payments(mut self, idempotent_payments: bool) -> Self {
+        self.idempotent_payments = idempotent_payments;
+        self
+    }
+
+    /// Record every transaction in `journal` before it is broadcast, so that it can be
+    /// rebroadcast after a crash with [`BitcoinCoreApi::rebroadcast_pending`]. The status is
+    /// updated by `wait_for_transaction_metadata`.
+    pub fn with_journal<J: TransactionJournal + 'static>(mut self, journal: J) -> Self {
+        self.journal = Some(Arc::new(journal));
+        self
+    }
+
+    /// Check every transaction against `policy` before it is broadcast: it must pay the
+    /// payouts and carry the request id it was created for, be accepted by
+    /// `testmempoolaccept` and pay a fee within the limits of the policy.
+    pub fn with_broadcast_policy(mut self, policy: BroadcastPolicy) -> Self {
+        self.broadcast_policy = Some(policy);
+        self
+    }
+
+    /// Re-import the vault key and the deposit keys of `issue_ids`, derived from the vault
+    /// master key, and rescan the chain from `start_height` for their outputs. Restores a
+    /// wallet that was lost or recreated, as long as the issue ids are known.
+    pub async fn recover_deposit_keys(
+        &self,
+        issue_ids: &[H256],
+        start_height: usize,
+    ) -> Result<(), Error> {
+        let vault_key = self.vault_key.as_ref().ok_or(Error::MissingVaultKey)?;
+        self.import_key(&vault_key.vault_private_key(), "", false)
+            .await?;
+        for issue_id in issue_ids {
+            self.import_key(
+                &vault_key.deposit_private_key(*issue_id)?,
+                DEPOSIT_LABEL,
+                false,
+            )
+            .await?;
+        }
+        info!(
+            "Imported {} deposit keys, rescanning from height {}",
+            issue_ids.len(),
+            start_height
+        );
+        self.rpc.rescan_blockchain(start_height).await?;
+        Ok(())
+    }
+
+    /// Type of the loaded wallet, which determines how keys are imported.
+    pub async fn wallet_type(&self) -> Result<WalletType, Error> {
+        self.wallet_type
+            .get_or_try_init(|| self.rpc.get_wallet_type())
+            .await
+            .copied()
+    }
+
+    /// Import `private_key` with the rpc matching the wallet type. Without `rescan`, only
+    /// outputs received from now on are tracked.
+    async fn import_key(
+        &self,
+        private_key: &PrivateKey,
+        label: &str,
+        rescan: bool,
+    ) -> Result<(), Error> {
+        match self.wallet_type().await? {
+            WalletType::Legacy => {
+                self.rpc
+                    .import_private_key(private_key, label, Some(rescan))
+                    .await
+            }
+            WalletType::Descriptor => {
+                let descriptor = self
+                    .rpc
+                    .add_descriptor_checksum(&descriptor::wpkh(private_key))
+                    .await?;
+                self.rpc.import_descriptor(&descriptor, label, rescan).await
+            }
+        }
+    }
+
+    /// Private key of a wallet `address`. Descriptor wallets cannot export single keys, so
+    /// the key is derived from the extended key of the descriptor that generated it.
+    async fn wallet_private_key(
+        &self,
+        address: &Address,
+        public_key: &PublicKey,
+    ) -> Result<PrivateKey, Error> {
+        match self.wallet_type().await? {
+            WalletType::Legacy => self.rpc.dump_private_key(address).await,
+            WalletType::Descriptor => {
+                let origin = self
+                    .rpc
+                    .get_key_origin(address)
+                    .await?
+                    .ok_or(Error::PrivateKeyNotFound)?;
+                let descriptors = self.rpc.list_private_descriptors().await?;
+                descriptor::find_private_key(&descriptors, &origin, public_key, self.network)
+                    .ok_or(Error::PrivateKeyNotFound)
+            }
+        }
+    }
+
+    /// Stream of blocks connected to the main chain from now on. Uses `-zmqpubrawblock`
+    /// if configured, otherwise polls for the next block.
+    pub async fn subscribe_blocks(
+        &self,
+    ) -> Result<BoxStream<'static, Result<Block, Error>>, Error> {
+        match self.zmq.raw_block {
+            Some(ref endpoint) => zmq::raw_blocks(endpoint).await,
+            None => {
+                let height = self.get_block_count().await? as u32 + 1;
+                Ok(zmq::poll_blocks(self.clone(), height))
+            }
+        }
+    }
+
+    /// Stream of transactions entering the mempool. Uses `-zmqpubhashtx` if configured,
+    /// otherwise polls the mempool. Transactions announced over zmq that are no longer
+    /// in the mempool when fetched (e.g. because they were mined) are skipped.
+    pub async fn subscribe_transactions(
+        &self,
+    ) -> Result<BoxStream<'static, Result<Transaction, Error>>, Error> {
+        match self.zmq.hash_tx {
+            Some(ref endpoint) => {
+                let rpc = self.rpc.clone();
+                let transactions = zmq::tx_hashes(endpoint).await?.filter_map(move |txid| {
+                    let rpc = rpc.clone();
+                    async move {
+                        let txid = match txid {
+                            Ok(txid) => txid,
+                            Err(err) => return Some(Err(err)),
+                        };
+                        match rpc.get_raw_transaction(&txid, None).await {
+                            Err(err) if err_not_in_mempool(&err) => None,
+                            result => Some(result),
+                        }
+                    }
+                });
+                Ok(transactions.boxed())
+            }
+            None => Ok(zmq::poll_mempool(self.clone(), RETRY_DURATION)),
+        }
+    }
+
     /// Connect to a bitcoin-core full node or timeout
     pub async fn connect(&self) -> Result<(), Error> {
         info!("Connecting to bitcoin-core...");
         timeout(self.connection_timeout, async move {
             loop {
-                match self.rpc.get_blockchain_info() {
-                    Err(BitcoinError::JsonRpc(JsonRpcError::Hyper(HyperError::Io(err))))
-                        if err.kind() == IoErrorKind::ConnectionRefused =>
-                    {
+                match self.rpc.get_blockchain_info().await {
+                    Err(err) if err.is_connection_refused() => {
                         trace!("could not connect to bitcoin-core");
                         sleep(RETRY_DURATION).await;
                         continue;
                     }
-                    Err(BitcoinError::JsonRpc(JsonRpcError::Rpc(err)))
+                    Err(Error::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Rpc(err))))
                         if BitcoinRpcError::from(err.clone()) == BitcoinRpcError::RpcInWarmup =>
                     {
                         // may be loading block index or verifying wallet
@@ -216,7 +602,7 @@ impl BitcoinCore {
                         sleep(RETRY_DURATION).await;
                         continue;
                     }
-                    Err(BitcoinError::JsonRpc(JsonRpcError::Json(err)))
+                    Err(Error::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Json(err))))
                         if err.classify() == SerdeJsonCategory::Syntax =>
                     {
                         // invalid response, can happen if server is in shutdown
@@ -228,7 +614,7 @@ impl BitcoinCore {
                         info!("Connected!");
                         return Ok(());
                     }
-                    Err(err) => return Err(err.into()),
+                    Err(err) => return Err(err),
                 }
             }
         })
@@ -239,7 +625,7 @@ impl BitcoinCore {
     pub async fn sync(&self) -> Result<(), Error> {
         info!("Waiting for bitcoin-core to sync...");
         loop {
-            let info = self.rpc.get_blockchain_info()?;
+            let info = self.rpc.get_blockchain_info().await?;
             // NOTE: initial_block_download is always true on regtest
             if !info.initial_block_download || info.verification_progress.eq(&1.0) {
                 info!("Synced!");
@@ -251,37 +637,325 @@ impl BitcoinCore {
     }
 
     /// Wrapper of rust_bitcoincore_rpc::create_raw_transaction_hex that accepts an optional op_return
-    fn create_raw_transaction_hex(
+    async fn create_raw_transaction_hex(
         &self,
-        address: String,
-        amount: Amount,
+        inputs: &[OutPoint],
+        payouts: &[Payout],
         request_id: Option<H256>,
     ) -> Result<String, Error> {
-        let mut outputs = serde_json::Map::<String, serde_json::Value>::new();
-        // add the payment output
-        outputs.insert(address, serde_json::Value::from(amount.as_btc()));
-
-        if let Some(request_id) = request_id {
-            // add the op_return data - bitcoind will add op_return and the length automatically
-            outputs.insert(
-                "data".to_string(),
-                serde_json::Value::from(request_id.to_hex()),
+        let (inputs, outputs) = raw_transaction_args(inputs, payouts, request_id)?;
+        self.rpc
+            .call("createrawtransaction", &[inputs, outputs])
+            .await
+    }
+
+    /// Options of `fundrawtransaction` (also accepted by `walletcreatefundedpsbt`) for
+    /// `fee_policy`, along with the fee rate that was resolved for it, if any.
+    async fn funding_options(
+        &self,
+        fee_policy: &FeePolicy,
+    ) -> Result<(Option<u64>, serde_json::Value), Error> {
+        let fee_rate = match (
+            fee_policy.resolve_fee_rate(self).await?,
+            &fee_policy.coin_selection,
+        ) {
+            // the fee of the selected inputs has to be known up front
+            (None, Some(_)) => Some(
+                self.estimate_fee_rate(DEFAULT_CONF_TARGET)
+                    .await?
+                    .unwrap_or(MIN_RELAY_FEE_RATE),
+            ),
+            (fee_rate, _) => fee_rate,
+        };
+        let mut options = serde_json::Map::new();
+        match (fee_rate, fee_policy.conf_target) {
+            (Some(fee_rate), _) => {
+                options.insert("fee_rate".to_string(), fee_rate.into());
+            }
+            // no estimate available, let bitcoind use its fallback fee
+            (None, Some(conf_target)) => {
+                options.insert("conf_target".to_string(), conf_target.into());
+            }
+            (None, None) => {}
+        }
+        if let Some(replaceable) = fee_policy.replaceable {
+            options.insert("replaceable".to_string(), replaceable.into());
+        }
+        if let Some(change_position) = fee_policy.change_position {
+            options.insert("changePosition".to_string(), change_position.into());
+        }
+        if fee_policy.coin_selection.is_some() {
+            options.insert("add_inputs".to_string(), false.into());
+        }
+        if let Some(ref signer) = self.signer {
+            let change_address = self.signer_address(signer.as_ref())?;
+            options.insert(
+                "changeAddress".to_string(),
+                change_address.to_string().into(),
             );
+            options.insert("includeWatching".to_string(), true.into());
         }
+        // lock the inputs until the transaction is broadcast, so that other processes using
+        // the same wallet do not spend them
+        options.insert("lockUnspents".to_string(), true.into());
+        Ok((fee_rate, serde_json::Value::Object(options)))
+    }
+
+    /// Choose and reserve the inputs paying for `payouts` if the policy selects them
+    /// explicitly, otherwise funding picks them. Must be called while holding the transaction
+    /// lock.
+    async fn select_inputs(
+        &self,
+        payouts: &[Payout],
+        request_id: Option<H256>,
+        fee_policy: &FeePolicy,
+        fee_rate: Option<u64>,
+    ) -> Result<Vec<OutPoint>, Error> {
+        let (coin_selection, fee_rate) = match (&fee_policy.coin_selection, fee_rate) {
+            (Some(coin_selection), Some(fee_rate)) => (coin_selection, fee_rate),
+            _ => return Ok(vec![]),
+        };
+        let mut outputs: Vec<_> = payouts
+            .iter()
+            .map(|payout| payout.address.script_pubkey())
+            .collect();
+        outputs.extend(request_id.map(|id| Script::new_op_return(id.as_bytes())));
+        let target = payouts.iter().map(|payout| payout.sat).sum::<u64>()
+            + fee_rate * coin_selection::base_vsize(&outputs);
+        let utxos = self.list_utxos(coin_selection.min_confirmations).await?;
+        let inputs: Vec<_> = coin_selection
+            .select(utxos, target, fee_rate)?
+            .into_iter()
+            .map(|utxo| utxo.outpoint)
+            .collect();
+        // without a lock shared by all processes, another one may have selected the same
+        // outputs since they were listed, in which case bitcoind refuses to lock them again
+        self.lock_utxos(&inputs).await?;
+        Ok(inputs)
+    }
+
+    /// Release outputs locked for a transaction that failed to be funded, signed or
+    /// broadcast. Failures are only logged, the locks do not outlive bitcoind anyway.
+    async fn release_utxos(&self, outpoints: &[OutPoint]) {
+        if outpoints.is_empty() {
+            return;
+        }
+        if let Err(err) = self.unlock_utxos(outpoints).await {
+            log::warn!("Failed to unlock {:?}: {}", outpoints, err);
+        }
+    }
 
-        let args = [
-            serde_json::to_value::<&[json::CreateRawTransactionInput]>(&[])?,
-            serde_json::to_value(outputs)?,
-        ];
-        Ok(self.rpc.call("createrawtransaction", &args)?)
+    /// Address of the first key of `signer`. A watch-only wallet cannot derive new keys, so it
+    /// receives both payments and change.
+    fn signer_address(&self, signer: &dyn Signer) -> Result<Address, Error> {
+        let public_key = signer
+            .public_keys()
+            .into_iter()
+            .next()
+            .ok_or(Error::MissingPublicKey)?;
+        Ok(Address::p2wpkh(&public_key, self.network).map_err(ConversionError::from)?)
+    }
+
+    /// Reservation of the inputs locked while funding `transaction`, which unlocks them in
+    /// the background if the transaction is dropped without being sent.
+    fn reserve_inputs(&self, transaction: &Transaction) -> InputReservation {
+        let outpoints = transaction
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+            .collect();
+        let rpc = self.rpc.clone();
+        InputReservation::new(
+            outpoints,
+            move |outpoints| match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn(async move {
+                        if let Err(err) = rpc.lock_unspent(true, &outpoints).await {
+                            log::warn!("Failed to unlock {:?}: {}", outpoints, err);
+                        }
+                    });
+                }
+                Err(_) => log::warn!("Failed to unlock {:?}: no runtime", outpoints),
+            },
+        )
+    }
+
+    /// `create_batch_transaction`, holding `lock` already.
+    async fn create_batch_transaction_locked(
+        &self,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+        lock: TransactionLockGuard,
+    ) -> Result<LockedTransaction, Error> {
+        if let Some(ref signer) = self.signer {
+            // the wallet cannot sign, let the signer sign the funded transaction instead
+            let mut psbt = self
+                .create_psbt_locked(payouts, request_id, fee_policy, lock)
+                .await?;
+            if let Err(err) = signer.sign_psbt(&mut psbt.psbt).await {
+                psbt.disarm_reservation();
+                self.unlock_inputs(&psbt.psbt.global.unsigned_tx).await;
+                return Err(err);
+            }
+            return self.finalize_psbt(psbt).await;
+        }
+
+        batch::check_payouts(&payouts)?;
+        let (fee_rate, options) = self.funding_options(&fee_policy).await?;
+
+        let (transaction, funded_raw_tx) = self
+            .with_wallet(|| async {
+                let inputs = self
+                    .select_inputs(&payouts, request_id, &fee_policy, fee_rate)
+                    .await?;
+
+                // create raw transaction that includes the op_return (if any). If we were to add the op_return
+                // after funding, the fees might be insufficient. An alternative to our own version of
+                // this function would be to call create_raw_transaction (without the _hex suffix), and
+                // to add the op_return afterwards. However, this function fails if no inputs are
+                // specified, as is the case for us prior to calling fund_raw_transaction.
+                let funded_raw_tx = async {
+                    let raw_tx = self
+                        .create_raw_transaction_hex(&inputs, &payouts, request_id)
+                        .await?;
+                    // fund the transaction: adds required inputs, and possibly a return-to-self output
+                    let funded_raw_tx = self.rpc.fund_raw_transaction(&raw_tx, &options).await?;
+                    Ok(funded_raw_tx.transaction()?)
+                }
+                .await;
+                let funded_raw_tx = match funded_raw_tx {
+                    Ok(funded_raw_tx) => funded_raw_tx,
+                    Err(err) => {
+                        self.release_utxos(&inputs).await;
+                        return Err(err);
+                    }
+                };
+
+                // sign the transaction
+                let transaction = async {
+                    let signed_funded_raw_tx = self
+                        .rpc
+                        .sign_raw_transaction_with_wallet(&funded_raw_tx)
+                        .await?;
+                    // Make sure signing is successful
+                    if signed_funded_raw_tx.errors.is_some() {
+                        return Err(Error::TransactionSigningError);
+                    }
+                    Ok(signed_funded_raw_tx.transaction()?)
+                }
+                .await;
+                let transaction = match transaction {
+                    Ok(transaction) => transaction,
+                    Err(err) => {
+                        self.unlock_inputs(&funded_raw_tx).await;
+                        return Err(err);
+                    }
+                };
+
+                Ok((transaction, funded_raw_tx))
+            })
+            .await?;
+
+        let reservation = self.reserve_inputs(&funded_raw_tx);
+        Ok(
+            LockedTransaction::new(transaction, payouts, request_id, Some(lock))
+                .with_reservation(Some(reservation)),
+        )
+    }
+
+    /// `create_psbt`, holding `lock` already.
+    async fn create_psbt_locked(
+        &self,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+        lock: TransactionLockGuard,
+    ) -> Result<LockedPsbt, Error> {
+        batch::check_payouts(&payouts)?;
+        let (fee_rate, options) = self.funding_options(&fee_policy).await?;
+
+        let funded_psbt = self
+            .with_wallet(|| async {
+                let inputs = self
+                    .select_inputs(&payouts, request_id, &fee_policy, fee_rate)
+                    .await?;
+
+                let funded_psbt = async {
+                    let (raw_inputs, outputs) =
+                        raw_transaction_args(&inputs, &payouts, request_id)?;
+                    let funded_psbt = self
+                        .rpc
+                        .wallet_create_funded_psbt(raw_inputs, outputs, &options)
+                        .await?;
+                    psbt::decode(&funded_psbt.psbt)
+                }
+                .await;
+                if funded_psbt.is_err() {
+                    self.release_utxos(&inputs).await;
+                }
+                funded_psbt
+            })
+            .await?;
+
+        let reservation = self.reserve_inputs(&funded_psbt.global.unsigned_tx);
+        Ok(
+            LockedPsbt::new(funded_psbt, payouts, request_id, Some(lock))
+                .with_reservation(Some(reservation)),
+        )
+    }
+
+    /// Release the inputs locked while funding `transaction`.
+    async fn unlock_inputs(&self, transaction: &Transaction) {
+        let outpoints: Vec<_> = transaction
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+            .collect();
+        self.release_utxos(&outpoints).await;
+    }
+
+    /// Spend `utxo` to `address`, paying `fee` satoshis.
+    async fn sign_sweep(
+        &self,
+        utxo: &json::ListUnspentResultEntry,
+        address: &Address,
+        fee: u64,
+    ) -> Result<Transaction, Error> {
+        self.sign_with_wallet(&Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(utxo.txid, utxo.vout),
+                script_sig: Script::new(),
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: utxo.amount.as_sat().saturating_sub(fee),
+                script_pubkey: address.script_pubkey(),
+            }],
+        })
+        .await
+    }
+
+    /// Sign all inputs of `transaction` with the keys of the wallet.
+    async fn sign_with_wallet(&self, transaction: &Transaction) -> Result<Transaction, Error> {
+        let signed = self
+            .rpc
+            .sign_raw_transaction_with_wallet(transaction)
+            .await?;
+        if signed.errors.is_some() {
+            return Err(Error::TransactionSigningError);
+        }
+        Ok(signed.transaction()?)
     }
 
     #[cfg(feature = "regtest-manual-mining")]
-    pub fn mine_block(&self) -> Result<(), Error> {
-        self.rpc.generate_to_address(
-            1,
-            &self.rpc.get_new_address(None, Some(AddressType::Bech32))?,
-        )?;
+    pub async fn mine_block(&self) -> Result<(), Error> {
+        self.rpc
+            .generate_to_address(1, &self.rpc.get_new_address(AddressType::Bech32).await?)
+            .await?;
         Ok(())
     }
 
@@ -318,13 +992,13 @@ impl BitcoinCore {
 }
 
 /// true if the given indicates that the item was not found in the mempool
-fn err_not_in_mempool(err: &bitcoincore_rpc::Error) -> bool {
+fn err_not_in_mempool(err: &Error) -> bool {
     matches!(
         err,
-        &bitcoincore_rpc::Error::JsonRpc(JsonRpcError::Rpc(RpcError {
+        &Error::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Rpc(RpcError {
             code: NOT_IN_MEMPOOL_ERROR_CODE,
             ..
-        }))
+        })))
     )
 }
 
@@ -338,32 +1012,29 @@ impl BitcoinCoreApi for BitcoinCore {
     /// * `num_confirmations` - minimum for a block to be accepted
     async fn wait_for_block(&self, height: u32, num_confirmations: i32) -> Result<Block, Error> {
         loop {
-            match self.rpc.get_block_hash(height.into()) {
+            match self.rpc.get_block_hash(height.into()).await {
                 Ok(hash) => {
-                    let info = self.rpc.get_block_info(&hash)?;
+                    let info = self.rpc.get_block_info(&hash).await?;
                     if info.confirmations >= num_confirmations {
-                        return Ok(self.rpc.get_block(&hash)?);
+                        return self.rpc.get_block(&hash).await;
                     } else {
                         sleep(RETRY_DURATION).await;
                         continue;
                     }
                 }
-                Err(BitcoinError::JsonRpc(JsonRpcError::Rpc(err)))
-                    if BitcoinRpcError::from(err.clone())
-                        == BitcoinRpcError::RpcInvalidParameter =>
-                {
+                Err(err) if err.is_invalid_parameter() => {
                     // block does not exist yet
                     sleep(RETRY_DURATION).await;
                     continue;
                 }
-                Err(err) => return Err(err.into()),
+                Err(err) => return Err(err),
             }
         }
     }
 
     /// Get the tip of the main chain as reported by Bitcoin core.
     async fn get_block_count(&self) -> Result<u64, Error> {
-        Ok(self.rpc.get_block_count()?)
+        self.rpc.get_block_count().await
     }
 
     /// Get the raw transaction identified by `Txid` and stored
@@ -374,7 +1045,7 @@ impl BitcoinCoreApi for BitcoinCore {
     /// * `block_hash` - hash of the block tx is stored in
     async fn get_raw_tx(&self, txid: &Txid, block_hash: &BlockHash) -> Result<Vec<u8>, Error> {
         Ok(serialize(
-            &self.rpc.get_raw_transaction(txid, Some(block_hash))?,
+            &self.rpc.get_raw_transaction(txid, Some(block_hash)).await?,
         ))
     }
 
@@ -384,7 +1055,7 @@ impl BitcoinCoreApi for BitcoinCore {
     /// * `txid` - transaction ID
     /// * `block_hash` - hash of the block tx is stored in
     async fn get_proof(&self, txid: Txid, block_hash: &BlockHash) -> Result<Vec<u8>, Error> {
-        Ok(self.rpc.get_tx_out_proof(&[txid], Some(block_hash))?)
+        self.rpc.get_tx_out_proof(&[txid], Some(block_hash)).await
     }
 
     /// Get the block hash for a given height.
@@ -392,90 +1063,119 @@ impl BitcoinCoreApi for BitcoinCore {
     /// # Arguments
     /// * `height` - block height
     async fn get_block_hash(&self, height: u32) -> Result<BlockHash, Error> {
-        match self.rpc.get_block_hash(height.into()) {
+        match self.rpc.get_block_hash(height.into()).await {
             Ok(block_hash) => Ok(block_hash),
-            Err(BitcoinError::JsonRpc(JsonRpcError::Rpc(err)))
-                if BitcoinRpcError::from(err.clone()) == BitcoinRpcError::RpcInvalidParameter =>
-            {
+            Err(err) if err.is_invalid_parameter() => {
                 // block does not exist yet
                 Err(Error::InvalidBitcoinHeight)
             }
-            Err(err) => return Err(err.into()),
+            Err(err) => Err(err),
         }
     }
 
+    /// Get the block hashes for the given heights using a single batch request.
+    ///
+    /// # Arguments
+    /// * `heights` - block heights, the hashes are returned in the same order
+    async fn get_block_hashes(&self, heights: &[u32]) -> Result<Vec<BlockHash>, Error> {
+        let heights: Vec<u64> = heights.iter().map(|height| (*height).into()).collect();
+        self.rpc
+            .get_block_hashes(&heights)
+            .await?
+            .into_iter()
+            .map(|result| match result {
+                // block does not exist yet
+                Err(err) if err.is_invalid_parameter() => Err(Error::InvalidBitcoinHeight),
+                result => result,
+            })
+            .collect()
+    }
+
     /// Checks if the local full node has seen the specified block hash.
     ///
     /// # Arguments
     /// * `block_hash` - hash of the block to verify
     async fn is_block_known(&self, block_hash: BlockHash) -> Result<bool, Error> {
-        match self.rpc.get_block(&block_hash) {
+        match self.rpc.get_block(&block_hash).await {
             Ok(_) => Ok(true),
-            Err(BitcoinError::JsonRpc(JsonRpcError::Rpc(err)))
+            Err(Error::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Rpc(err))))
                 if BitcoinRpcError::from(err.clone())
                     == BitcoinRpcError::RpcInvalidAddressOrKey =>
             {
                 Ok(false) // block not found
             }
-            Err(e) => Err(e.into()),
+            Err(e) => Err(e),
         }
     }
 
     /// Gets a new address from the wallet
     async fn get_new_address(&self) -> Result<Address, Error> {
-        Ok(self.rpc.get_new_address(None, Some(AddressType::Bech32))?)
+        match self.signer {
+            Some(ref signer) => self.signer_address(signer.as_ref()),
+            None => self.rpc.get_new_address(AddressType::Bech32).await,
+        }
     }
 
-    /// Gets a new public key for an address in the wallet
+    /// Gets a new public key for an address in the wallet, or the vault key if the vault
+    /// master key is set.
     async fn get_new_public_key<P: From<[u8; PUBLIC_KEY_SIZE]> + 'static>(
         &self,
     ) -> Result<P, Error> {
-        let address = self.rpc.get_new_address(None, Some(AddressType::Bech32))?;
-        let address_info = self.rpc.get_address_info(&address)?;
+        if let Some(ref vault_key) = self.vault_key {
+            return Ok(P::from(vault_key.vault_public_key().key.serialize()));
+        }
+        let address = self.get_new_address().await?;
+        let address_info = self.rpc.get_address_info(&address).await?;
         let public_key = address_info.pubkey.ok_or(Error::MissingPublicKey)?;
         Ok(P::from(public_key.key.serialize()))
     }
 
-    /// Derive and import the private key for the master public key and public secret
+    /// Derive and import the private key for the master public key and public secret. To
+    /// import the key of the address derived on-chain for an issue request, pass the
+    /// scalar returned by [`calculate_deposit_scalar`] as `secret_key`.
     async fn add_new_deposit_key<P: Into<[u8; PUBLIC_KEY_SIZE]> + Send + Sync + 'static>(
         &self,
         public_key: P,
         secret_key: Vec<u8>,
     ) -> Result<(), Error> {
-        let address = Address::p2wpkh(&PublicKey::from_slice(&public_key.into())?, self.network)
-            .map_err(ConversionError::from)?;
-        let private_key = self.rpc.dump_private_key(&address)?;
+        let public_key = PublicKey::from_slice(&public_key.into())?;
+        let address = Address::p2wpkh(&public_key, self.network).map_err(ConversionError::from)?;
+        let private_key = match self.vault_key {
+            Some(ref vault_key) if vault_key.vault_public_key() == public_key => {
+                vault_key.vault_private_key()
+            }
+            _ => self.wallet_private_key(&address, &public_key).await?,
+        };
         let deposit_secret_key = addr::calculate_deposit_secret_key(
             private_key.key,
             SecretKey::from_slice(&secret_key)?,
         )?;
-        self.rpc.import_private_key(
+        self.import_key(
             &PrivateKey {
                 compressed: private_key.compressed,
                 network: self.network,
                 key: deposit_secret_key,
             },
-            None,
-            // rescan true by default
-            Some(false),
-        )?;
-        Ok(())
+            DEPOSIT_LABEL,
+            false,
+        )
+        .await
     }
 
     async fn get_best_block_hash(&self) -> Result<BlockHash, Error> {
-        Ok(self.rpc.get_best_block_hash()?)
+        self.rpc.get_best_block_hash().await
     }
 
     async fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
-        Ok(self.rpc.get_block(hash)?)
+        self.rpc.get_block(hash).await
     }
 
     async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, Error> {
-        Ok(self.rpc.get_block_header(hash)?)
+        self.rpc.get_block_header(hash).await
     }
 
     async fn get_block_info(&self, hash: &BlockHash) -> Result<GetBlockResult, Error> {
-        Ok(self.rpc.get_block_info(hash)?)
+        self.rpc.get_block_info(hash).await
     }
 
     /// Get the transactions that are currently in the mempool. Since `impl trait` is not
@@ -484,20 +1184,95 @@ impl BitcoinCoreApi for BitcoinCore {
         &'a self,
     ) -> Result<Box<dyn Iterator<Item = Result<Transaction, Error>> + Send + 'a>, Error> {
         // get txids from the mempool
-        let txids = self.rpc.get_raw_mempool()?;
-        // map txid to the actual Transaction structs
-        let iterator = txids.into_iter().filter_map(move |txid| {
-            match self.rpc.get_raw_transaction_info(&txid, None) {
-                Ok(x) => Some(x.transaction().map_err(Into::into)),
-                Err(e) if err_not_in_mempool(&e) => None, // not in mempool anymore, so filter out
-                Err(e) => Some(Err(e.into())),            // unknown error, propagate to user
-            }
+        let txids = self.rpc.get_raw_mempool().await?;
+        // map txid to the actual Transaction structs, using batched requests
+        let transactions = self.rpc.get_raw_transaction_infos(&txids).await?;
+        let iterator = transactions.into_iter().filter_map(|result| match result {
+            Ok(x) => Some(x.transaction().map_err(Into::into)),
+            Err(e) if err_not_in_mempool(&e) => None, // not in mempool anymore, so filter out
+            Err(e) => Some(Err(e)),                   // unknown error, propagate to user
         });
         Ok(Box::new(iterator))
     }
 
+    /// Get the transactions with the given ids using batched requests. Only mempool
+    /// transactions are found, unless bitcoind is running with `-txindex`.
+    ///
+    /// # Arguments
+    /// * `txids` - transaction IDs, the transactions are returned in the same order
+    async fn get_transactions(&self, txids: &[Txid]) -> Result<Vec<Option<Transaction>>, Error> {
+        self.rpc
+            .get_raw_transaction_infos(txids)
+            .await?
+            .into_iter()
+            .map(|result| match result {
+                Ok(x) => Ok(Some(x.transaction()?)),
+                Err(e) if err_not_in_mempool(&e) => Ok(None),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    /// Hash of the block containing `txid`, if bitcoind can look it up: mined transactions
+    /// are only indexed with `-txindex`. `None` if the transaction is unconfirmed or unknown,
+    /// see [`TransactionTracker`](tracker::TransactionTracker) to scan blocks instead.
+    async fn find_transaction_block(&self, txid: &Txid) -> Result<Option<BlockHash>, Error> {
+        match self.rpc.get_raw_transaction_info(txid, None).await {
+            Ok(info) => Ok(info.blockhash),
+            Err(e) if err_not_in_mempool(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Find a transaction paying for `request_id`, i.e. carrying it in an OP_RETURN output,
+    /// among the most recent transactions sent by the wallet, confirmed or not. Others are
+    /// not trusted, as anyone can broadcast a transaction carrying the request id. Replaced
+    /// or conflicting wallet transactions are ignored.
+    async fn find_request_payment(&self, request_id: H256) -> Result<Option<Txid>, Error> {
+        let txids = self
+            .with_wallet(|| async { self.rpc.list_sent_transactions(REQUEST_SEARCH_DEPTH).await })
+            .await?;
+        let results = self.rpc.get_wallet_transactions(&txids).await?;
+        for (txid, result) in txids.iter().zip(results) {
+            // e.g. abandoned since it was listed, the others may still pay for the request
+            let transaction = match result.and_then(|result| Ok(result.transaction()?)) {
+                Ok(transaction) => transaction,
+                Err(err) => {
+                    log::warn!("Failed to get wallet transaction {}: {}", txid, err);
+                    continue;
+                }
+            };
+            if transaction.get_op_return() == Some(request_id) {
+                return Ok(Some(transaction.txid()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Wait for payments to `payload` mined from `since_height` on, e.g. to the deposit
+    /// address of an issue request. Every output paying `payload` counts, wherever it is in
+    /// the transaction. Resolves once the payments with `num_confirmations` add up to
+    /// `min_amount` (or any amount if not set), returning all of them.
+    ///
+    /// # Arguments
+    /// * `payload` - the address to watch
+    /// * `min_amount` - total amount in satoshis to wait for, which may be split into
+    ///   multiple payments
+    /// * `since_height` - the first block to scan
+    /// * `num_confirmations` - how many confirmations each payment needs, at least 1
+    async fn wait_for_payment(
+        &self,
+        payload: Payload,
+        min_amount: Option<u64>,
+        since_height: u32,
+        num_confirmations: u32,
+    ) -> Result<Vec<TransactionMetadata>, Error> {
+        tracker::wait_for_payment(self, payload, min_amount, since_height, num_confirmations).await
+    }
+
     /// Waits for the required number of confirmations, and collects data about the
-    /// transaction
+    /// transaction. Only works for wallet transactions, see
+    /// [`TransactionTracker`](tracker::TransactionTracker) for others.
     ///
     /// # Arguments
     /// * `txid` - transaction ID
@@ -507,8 +1282,8 @@ impl BitcoinCoreApi for BitcoinCore {
         txid: Txid,
         num_confirmations: u32,
     ) -> Result<TransactionMetadata, Error> {
-        let (block_height, block_hash) = retry(get_exponential_backoff(), || async {
-            Ok(match self.rpc.get_transaction(&txid, None) {
+        let confirmed = retry(get_exponential_backoff(), || async {
+            Ok(match self.rpc.get_transaction(&txid).await {
                 Ok(GetTransactionResult {
                     info:
                         WalletTxInfo {
@@ -521,100 +1296,260 @@ impl BitcoinCoreApi for BitcoinCore {
                 }) if confirmations >= 0 && confirmations as u32 >= num_confirmations => {
                     Ok((height, hash))
                 }
+                Ok(GetTransactionResult {
+                    info: WalletTxInfo { confirmations, .. },
+                    ..
+                }) if confirmations < 0 => {
+                    // keep waiting, a reorg may still confirm it
+                    journal::update_status(&self.journal, &txid, TransactionStatus::Conflicted)
+                        .await;
+                    Err(Error::ConfirmationError)
+                }
                 Ok(_) => Err(Error::ConfirmationError),
-                Err(e) => Err(e.into()),
+                Err(e) => Err(e),
             }?)
         })
-        .await?;
-
-        let proof = retry(get_exponential_backoff(), || async {
-            Ok(self.get_proof(txid, &block_hash).await?)
-        })
-        .await?;
+        .await;
+        let (block_height, block_hash) = match confirmed {
+            Ok(confirmed) => confirmed,
+            Err(err) => {
+                if err_not_in_mempool(&err) {
+                    // no longer known to the wallet
+                    journal::update_status(&self.journal, &txid, TransactionStatus::Dropped).await;
+                }
+                return Err(err);
+            }
+        };
 
-        let raw_tx = retry(get_exponential_backoff(), || async {
-            Ok(self.get_raw_tx(&txid, &block_hash).await?)
+        // fetch the proof and the raw transaction in a single round trip
+        let (transaction, proof) = retry(get_exponential_backoff(), || async {
+            Ok(self
+                .rpc
+                .get_raw_transaction_and_proof(&txid, &block_hash)
+                .await?)
         })
         .await?;
-
+        journal::update_status(&self.journal, &txid, TransactionStatus::Confirmed).await;
         Ok(TransactionMetadata {
             txid,
             proof,
-            raw_tx,
+            raw_tx: serialize(&transaction),
+            stripped_raw_tx: serialize_without_witness(&transaction),
             block_height,
             block_hash,
         })
     }
 
+    /// Estimate the fee rate in sat/vB for confirmation within `conf_target` blocks,
+    /// `None` if bitcoind does not have enough data.
+    async fn estimate_fee_rate(&self, conf_target: u16) -> Result<Option<u64>, Error> {
+        let estimate = self.rpc.estimate_smart_fee(conf_target).await?;
+        // BTC/kvB to sat/vB, rounded up
+        Ok(estimate
+            .fee_rate
+            .map(|fee_rate| fee_rate.as_sat().div_ceil(1000)))
+    }
+
+    /// Spendable outputs of the wallet that are not locked, see [`BitcoinCore::lock_utxos`].
+    async fn list_utxos(&self, min_confirmations: u32) -> Result<Vec<Utxo>, Error> {
+        let unspent = self
+            .with_wallet(|| async { self.rpc.list_unspent(min_confirmations, 9_999_999).await })
+            .await?;
+        Ok(unspent
+            .into_iter()
+            .filter(|entry| entry.spendable)
+            .map(|entry| Utxo {
+                outpoint: OutPoint::new(entry.txid, entry.vout),
+                amount: entry.amount.as_sat(),
+                is_deposit: entry.label.as_deref() == Some(DEPOSIT_LABEL),
+                script_pubkey: entry.script_pub_key,
+                confirmations: entry.confirmations,
+            })
+            .collect())
+    }
+
+    /// Exclude the outputs from coin selection, by this and any other process using the
+    /// wallet, until they are unlocked or bitcoind restarts.
+    async fn lock_utxos(&self, outpoints: &[OutPoint]) -> Result<(), Error> {
+        self.with_wallet(|| async { self.rpc.lock_unspent(false, outpoints).await })
+            .await?;
+        Ok(())
+    }
+
+    async fn unlock_utxos(&self, outpoints: &[OutPoint]) -> Result<(), Error> {
+        self.with_wallet(|| async { self.rpc.lock_unspent(true, outpoints).await })
+            .await?;
+        Ok(())
+    }
+
     /// Creates and return a transaction; it is not submitted to the mempool. While the returned value
-    /// is alive, no other transactions can be created (this is guarded by a mutex). This prevents
-    /// accidental double spending.
+    /// is alive, no other transactions can be created (this is guarded by the transaction lock,
+    /// see [`BitcoinCore::with_transaction_lock`] for sharing it with other processes). This
+    /// prevents accidental double spending.
     ///
     /// # Arguments
     /// * `address` - Bitcoin address to fund
     /// * `sat` - number of Satoshis to transfer
     /// * `request_id` - the issue/redeem/replace id for which this transfer is being made
+    /// * `fee_policy` - fee rate, replaceability and change position of the transaction
     async fn create_transaction(
         &self,
         address: Address,
         sat: u64,
         request_id: Option<H256>,
+        fee_policy: FeePolicy,
     ) -> Result<LockedTransaction, Error> {
-        self.with_wallet(|| async {
-            let address_string = address.to_string();
-
-            // create raw transaction that includes the op_return (if any). If we were to add the op_return
-            // after funding, the fees might be insufficient. An alternative to our own version of
-            // this function would be to call create_raw_transaction (without the _hex suffix), and
-            // to add the op_return afterwards. However, this function fails if no inputs are
-            // specified, as is the case for us prior to calling fund_raw_transaction.
-            let raw_tx = self.create_raw_transaction_hex(
-                address_string.clone(),
-                Amount::from_sat(sat),
-                request_id,
-            )?;
-
-            // ensure no other fund_raw_transaction calls are made until we submitted the
-            // transaction to the bitcoind. If we don't do this, the same uxto may be used
-            // as input twice (i.e. double spend)
-            let lock = self.transaction_creation_lock.clone().lock_owned().await;
+        self.create_batch_transaction(vec![Payout::new(address, sat)], request_id, fee_policy)
+            .await
+    }
 
-            // fund the transaction: adds required inputs, and possibly a return-to-self output
-            let funded_raw_tx = self.rpc.fund_raw_transaction(raw_tx, None, None)?;
+    /// Like [`create_transaction`](Self::create_transaction), but pays all `payouts` with a
+    /// single transaction. It carries at most one request id, see [`batch`].
+    ///
+    /// # Arguments
+    /// * `payouts` - the recipients and amounts, each address may only be paid once
+    /// * `request_id` - the issue/redeem/replace id for which this transfer is being made
+    /// * `fee_policy` - fee rate, replaceability and change position of the transaction
+    async fn create_batch_transaction(
+        &self,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+    ) -> Result<LockedTransaction, Error> {
+        // ensure no other fund_raw_transaction calls are made until we submitted the
+        // transaction to the bitcoind. If we don't do this, the same uxto may be used
+        // as input twice (i.e. double spend)
+        let lock = self.transaction_creation_lock.acquire().await?;
+        self.create_batch_transaction_locked(payouts, request_id, fee_policy, lock)
+            .await
+    }
 
-            // sign the transaction
-            let signed_funded_raw_tx = self.rpc.sign_raw_transaction_with_wallet(
-                &funded_raw_tx.transaction()?,
-                None,
-                None,
-            )?;
+    /// Like `create_batch_transaction`, but returns the funded transaction unsigned, as a PSBT
+    /// that can be signed outside of bitcoind (see [`psbt`]). The PSBT includes the outputs
+    /// spent by the transaction and the key origins, which signers need.
+    ///
+    /// # Arguments
+    /// * `payouts` - the recipients and amounts
+    /// * `request_id` - the issue/redeem/replace id for which this transfer is being made
+    /// * `fee_policy` - fee rate, replaceability and change position of the transaction
+    async fn create_psbt(
+        &self,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+    ) -> Result<LockedPsbt, Error> {
+        // the inputs are reserved until the signed transaction is sent
+        let lock = self.transaction_creation_lock.acquire().await?;
+        self.create_psbt_locked(payouts, request_id, fee_policy, lock)
+            .await
+    }
 
-            // Make sure signing is successful
-            if signed_funded_raw_tx.errors.is_some() {
-                return Err(Error::TransactionSigningError);
+    /// Finalize a PSBT created by `create_psbt`, once the signatures have been added with
+    /// [`LockedPsbt::combine`]. The transaction can then be sent with `send_transaction`.
+    /// If signatures are missing, the inputs are released and [`Error::IncompletePsbt`] is
+    /// returned.
+    async fn finalize_psbt(&self, mut psbt: LockedPsbt) -> Result<LockedTransaction, Error> {
+        // does not need the wallet, bitcoind only checks the signatures
+        let result = self.rpc.finalize_psbt(&psbt::encode(&psbt.psbt)).await?;
+        match result.transaction() {
+            Some(transaction) if result.complete => Ok(psbt.finalized(transaction?)),
+            _ => {
+                psbt.disarm_reservation();
+                self.unlock_inputs(&psbt.psbt.global.unsigned_tx).await;
+                Err(Error::IncompletePsbt)
             }
-
-            let transaction = signed_funded_raw_tx.transaction()?;
-
-            Ok(LockedTransaction::new(
-                transaction,
-                address_string,
-                Some(lock),
-            ))
-        })
-        .await
+        }
     }
 
     /// Submits a transaction to the mempool
     ///
     /// # Arguments
     /// * `transaction` - The transaction created by create_transaction
-    async fn send_transaction(&self, transaction: LockedTransaction) -> Result<Txid, Error> {
+    async fn send_transaction(&self, mut transaction: LockedTransaction) -> Result<Txid, Error> {
+        if let Some(ref policy) = self.broadcast_policy {
+            let result = match self.rpc.test_mempool_accept(&transaction.transaction).await {
+                Ok(acceptance) => policy.check(&transaction, &acceptance).map_err(Error::from),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                transaction.disarm_reservation();
+                self.unlock_inputs(&transaction.transaction).await;
+                return Err(err);
+            }
+        }
+        if let Some(ref journal) = self.journal {
+            journal.record(JournalEntry::pending(&transaction)).await?;
+        }
         // place the transaction into the mempool, this is fine to retry
-        let txid = self
-            .with_wallet(|| async { Ok(self.rpc.send_raw_transaction(&transaction.transaction)?) })
-            .await?;
-        Ok(txid)
+        let result = self
+            .with_wallet(|| async {
+                self.rpc
+                    .send_raw_transaction(&transaction.transaction)
+                    .await
+            })
+            .await;
+        // the inputs stay locked once spent by the broadcast transaction. On other errors, e.g.
+        // a timeout, it may have been broadcast anyway, so it stays pending for
+        // `rebroadcast_pending`.
+        transaction.disarm_reservation();
+        match result {
+            Err(err) if err.is_transaction_rejected() => {
+                self.unlock_inputs(&transaction.transaction).await;
+                let txid = transaction.transaction.txid();
+                journal::update_status(&self.journal, &txid, TransactionStatus::Dropped).await;
+                Err(err)
+            }
+            result => result,
+        }
+    }
+
+    /// Broadcast the transactions that are still pending in the journal, e.g. on startup
+    /// after a crash. Transactions that have been confirmed or conflict with the chain in the
+    /// meantime are marked as such, and those that bitcoind rejects as dropped. Returns the
+    /// rebroadcast transactions, or the first error that is not a rejection, e.g. if bitcoind
+    /// is unreachable, leaving the remaining transactions pending.
+    async fn rebroadcast_pending(&self) -> Result<Vec<Txid>, Error> {
+        let journal = match self.journal {
+            Some(ref journal) => journal,
+            None => return Ok(Vec::new()),
+        };
+        let mut rebroadcast = Vec::new();
+        for entry in journal.pending().await? {
+            let txid = entry.txid();
+            let status = match self.rpc.get_transaction(&txid).await {
+                Ok(GetTransactionResult {
+                    info: WalletTxInfo { confirmations, .. },
+                    ..
+                }) if confirmations > 0 => Some(TransactionStatus::Confirmed),
+                Ok(GetTransactionResult {
+                    info: WalletTxInfo { confirmations, .. },
+                    ..
+                }) if confirmations < 0 => Some(TransactionStatus::Conflicted),
+                Ok(_) => None,
+                // e.g. the wallet was restored, bitcoind decides whether it is still valid
+                Err(err) if err_not_in_mempool(&err) => None,
+                Err(err) => return Err(err),
+            };
+            if let Some(status) = status {
+                journal.set_status(&txid, status).await?;
+                continue;
+            }
+
+            match self.rpc.send_raw_transaction(&entry.transaction).await {
+                Ok(_) => {
+                    info!("Rebroadcast {} to {}", txid, entry.recipient);
+                    rebroadcast.push(txid);
+                }
+                Err(err) if err.is_transaction_rejected() => {
+                    log::warn!("Dropping {}, rebroadcast failed: {}", txid, err);
+                    journal
+                        .set_status(&txid, TransactionStatus::Dropped)
+                        .await?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(rebroadcast)
     }
 
     /// Send an amount of Bitcoin to an address, but only submit the transaction
@@ -625,15 +1560,49 @@ impl BitcoinCoreApi for BitcoinCore {
     /// * `address` - Bitcoin address to fund
     /// * `sat` - number of Satoshis to transfer
     /// * `request_id` - the issue/redeem/replace id for which this transfer is being made
+    /// * `fee_policy` - fee rate, replaceability and change position of the transaction
     async fn create_and_send_transaction(
         &self,
         address: Address,
         sat: u64,
         request_id: Option<H256>,
+        fee_policy: FeePolicy,
     ) -> Result<Txid, Error> {
-        let tx = self.create_transaction(address, sat, request_id).await?;
-        let txid = self.send_transaction(tx).await?;
-        Ok(txid)
+        self.create_and_send_batch_transaction(
+            vec![Payout::new(address, sat)],
+            request_id,
+            fee_policy,
+        )
+        .await
+    }
+
+    /// Pay all `payouts` with a single transaction, and submit it to the mempool. With
+    /// [`with_idempotent_payments`](BitcoinCore::with_idempotent_payments), returns the
+    /// earlier payment of `request_id` if there is one.
+    ///
+    /// # Arguments
+    /// * `payouts` - the recipients and amounts, each address may only be paid once
+    /// * `request_id` - the issue/redeem/replace id for which this transfer is being made
+    /// * `fee_policy` - fee rate, replaceability and change position of the transaction
+    async fn create_and_send_batch_transaction(
+        &self,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+    ) -> Result<Txid, Error> {
+        // look up while holding the transaction lock, so that a concurrent payment of the
+        // same request has been sent by now
+        let lock = self.transaction_creation_lock.acquire().await?;
+        if let (true, Some(request_id)) = (self.idempotent_payments, request_id) {
+            if let Some(txid) = self.find_request_payment(request_id).await? {
+                info!("Request {:?} was already paid by {}", request_id, txid);
+                return Ok(txid);
+            }
+        }
+        let tx = self
+            .create_batch_transaction_locked(payouts, request_id, fee_policy, lock)
+            .await?;
+        self.send_transaction(tx).await
     }
 
     /// Send an amount of Bitcoin to an address and wait until it is included
@@ -644,28 +1613,146 @@ impl BitcoinCoreApi for BitcoinCore {
     /// * `sat` - number of Satoshis to transfer
     /// * `request_id` - the issue/redeem/replace id for which this transfer is being made
     /// * `num_confirmations` - how many confirmations we need to wait for
+    /// * `fee_policy` - fee rate, replaceability and change position of the transaction
     async fn send_to_address(
         &self,
         address: Address,
         sat: u64,
         request_id: Option<H256>,
         num_confirmations: u32,
+        fee_policy: FeePolicy,
     ) -> Result<TransactionMetadata, Error> {
         let txid = self
-            .create_and_send_transaction(address, sat, request_id)
+            .create_and_send_transaction(address, sat, request_id, fee_policy)
             .await?;
 
         #[cfg(feature = "regtest-mine-on-tx")]
-        self.rpc.generate_to_address(
-            1,
-            &self.rpc.get_new_address(None, Some(AddressType::Bech32))?,
-        )?;
+        self.rpc
+            .generate_to_address(1, &self.rpc.get_new_address(AddressType::Bech32).await?)
+            .await?;
 
         Ok(self
             .wait_for_transaction_metadata(txid, num_confirmations)
             .await?)
     }
 
+    /// Replace an unconfirmed wallet transaction that signals opt-in RBF by one paying a
+    /// higher fee. Only the change output is reduced, so the payment and the OP_RETURN with
+    /// the request id are preserved. Like a new transaction, the replacement is checked
+    /// against the broadcast policy and journaled before it is broadcast, after which the
+    /// original is marked as conflicted. Returns the txid of the replacement.
+    ///
+    /// # Arguments
+    /// * `txid` - the transaction to replace
+    /// * `fee_rate` - new fee rate in sat/vB, bitcoind estimates one if not set
+    async fn bump_fee(&self, txid: &Txid, fee_rate: Option<u64>) -> Result<Txid, Error> {
+        let mut options = serde_json::Map::new();
+        if let Some(fee_rate) = fee_rate {
+            options.insert("fee_rate".to_string(), fee_rate.into());
+        }
+        let options = serde_json::Value::Object(options);
+
+        // the replacement may spend additional wallet outputs, so it must not be funded
+        // concurrently with a new transaction
+        let lock = self.transaction_creation_lock.acquire().await?;
+        // not retried by `with_wallet`: bitcoind reports e.g. a transaction that was mined in
+        // the meantime as a wallet error
+        let original = self.rpc.get_transaction(txid).await?;
+        let psbt = self.rpc.psbt_bump_fee(txid, &options).await?;
+        let replacement = self
+            .sign_with_wallet(&psbt::decode(&psbt)?.global.unsigned_tx)
+            .await?;
+
+        // the replacement must still make every payment of the original, the change is not
+        // listed as sent
+        let payouts = original
+            .details
+            .iter()
+            .filter(|detail| {
+                matches!(
+                    detail.category,
+                    json::GetTransactionResultDetailCategory::Send
+                )
+            })
+            .filter_map(|detail| {
+                let sat = detail.amount.as_sat().unsigned_abs();
+                Some(Payout::new(detail.address.clone()?, sat))
+            })
+            .collect();
+        let request_id = journal::request_id(&self.journal, &original.transaction()?).await?;
+        let replacement_txid = self
+            .send_transaction(LockedTransaction::new(
+                replacement,
+                payouts,
+                request_id,
+                Some(lock),
+            ))
+            .await?;
+        journal::update_status(&self.journal, txid, TransactionStatus::Conflicted).await;
+        Ok(replacement_txid)
+    }
+
+    /// Accelerate an unconfirmed transaction that does not signal RBF (e.g. a deposit) by
+    /// spending its wallet output in a child that pays enough fee for the whole package to
+    /// reach `fee_rate`. The child is checked against the broadcast policy and journaled
+    /// like a new transaction. Returns the txids of the parent and of the child.
+    ///
+    /// # Arguments
+    /// * `txid` - the stuck transaction, it must have an unspent wallet output
+    /// * `fee_rate` - target fee rate of the package in sat/vB
+    async fn bump_fee_with_child(
+        &self,
+        txid: &Txid,
+        fee_rate: u64,
+    ) -> Result<ChildPaysForParent, Error> {
+        // the child must not spend an output that is being used to fund a new transaction
+        let lock = self.transaction_creation_lock.acquire().await?;
+        let (child, payout) = self
+            .with_wallet(|| async {
+                // the ancestor fields include the parent and all of its unconfirmed ancestors
+                let entry = self.rpc.get_mempool_entry(txid).await?;
+                let utxo = self
+                    .rpc
+                    .list_unspent(0, 0)
+                    .await?
+                    .into_iter()
+                    .filter(|utxo| &utxo.txid == txid && utxo.spendable)
+                    .max_by_key(|utxo| utxo.amount)
+                    .ok_or(Error::NoSpendableOutput)?;
+                let address = self.rpc.get_new_address(AddressType::Bech32).await?;
+
+                // sign once without fee to learn the size of the child
+                let unfunded = self.sign_sweep(&utxo, &address, 0).await?;
+                let child_vsize = (unfunded.get_weight() as u64).div_ceil(4);
+                let child_fee = fee::child_fee(
+                    fee_rate,
+                    entry.fees.ancestor.as_sat(),
+                    entry.ancestor_size,
+                    child_vsize,
+                );
+                if utxo.amount.as_sat() < child_fee + DUST_LIMIT {
+                    return Err(Error::CpfpOutputTooSmall);
+                }
+
+                let child = self.sign_sweep(&utxo, &address, child_fee).await?;
+                let payout = Payout::new(address, child.output[0].value);
+                Ok((child, payout))
+            })
+            .await?;
+        let child_txid = self
+            .send_transaction(LockedTransaction::new(
+                child,
+                vec![payout],
+                None,
+                Some(lock),
+            ))
+            .await?;
+        Ok(ChildPaysForParent {
+            parent_txid: *txid,
+            child_txid,
+        })
+    }
+
     /// Create or load a wallet on Bitcoin Core.
     async fn create_or_load_wallet(&self) -> Result<(), Error> {
         let wallet_name = if let Some(ref wallet_name) = self.wallet_name {
@@ -675,16 +1762,38 @@ impl BitcoinCoreApi for BitcoinCore {
         };
 
         // NOTE: bitcoincore-rpc does not expose listwalletdir
-        if self.rpc.list_wallets()?.contains(wallet_name)
-            || self.rpc.load_wallet(wallet_name).is_ok()
+        if !(self.rpc.list_wallets().await?.contains(wallet_name)
+            || self.rpc.load_wallet(wallet_name).await.is_ok())
         {
-            // wallet already loaded
-            return Ok(());
+            // wallet does not exist, create
+            self.rpc
+                .create_wallet(wallet_name, self.signer.is_some(), self.new_wallet_type)
+                .await?;
         }
 
-        // wallet does not exist, create
-        self.rpc
-            .create_wallet(wallet_name, None, None, None, None)?;
+        let wallet_type = self.wallet_type().await?;
+        info!("Using {:?} wallet {}", wallet_type, wallet_name);
+
+        if let Some(ref vault_key) = self.vault_key {
+            self.import_key(&vault_key.vault_private_key(), "", false)
+                .await?;
+        }
+
+        if let Some(ref signer) = self.signer {
+            // the keys of the signer may have changed since the wallet was created
+            for public_key in signer.public_keys() {
+                match wallet_type {
+                    WalletType::Legacy => self.rpc.import_public_key(&public_key, "").await?,
+                    WalletType::Descriptor => {
+                        let descriptor = self
+                            .rpc
+                            .add_descriptor_checksum(&descriptor::wpkh(public_key))
+                            .await?;
+                        self.rpc.import_descriptor(&descriptor, "", false).await?
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
@@ -704,7 +1813,7 @@ impl BitcoinCoreApi for BitcoinCore {
                 self.network,
             )
             .map_err(ConversionError::from)?;
-            let address_info = self.rpc.get_address_info(&address)?;
+            let address_info = self.rpc.get_address_info(&address).await?;
             let wallet_pubkey = address_info.pubkey.ok_or(Error::MissingPublicKey)?;
             Ok(P::from(wallet_pubkey.key.serialize()) == public_key)
         })
@@ -712,12 +1821,12 @@ impl BitcoinCoreApi for BitcoinCore {
     }
 
     async fn import_private_key(&self, privkey: PrivateKey) -> Result<(), Error> {
-        self.with_wallet(|| async { Ok(self.rpc.import_private_key(&privkey, None, None)?) })
+        self.with_wallet(|| async { self.import_key(&privkey, "", true).await })
             .await
     }
 
     async fn rescan_blockchain(&self, start_height: usize) -> Result<(), Error> {
-        self.rpc.rescan_blockchain(Some(start_height), None)?;
+        self.rpc.rescan_blockchain(start_height).await?;
         Ok(())
     }
 }
@@ -0,0 +1,168 @@
+//! Backends of the lock held from funding a wallet transaction until it is broadcast, so that
+//! no two transactions spend the same outputs. [`ProcessLock`] (the default) only excludes
+//! callers within the same process, the other backends extend the guarantee to every process
+//! using the wallet.
+
+use crate::{Error, OutPoint};
+use async_trait::async_trait;
+use std::{path::PathBuf, sync::Arc};
+use tokio::sync::Mutex;
+
+/// Proof of holding a [`TransactionLock`], which is released on drop.
+pub struct TransactionLockGuard(#[allow(dead_code)] Box<dyn Send + Sync>);
+
+impl TransactionLockGuard {
+    pub fn new<G: Send + Sync + 'static>(guard: G) -> Self {
+        Self(Box::new(guard))
+    }
+}
+
+#[async_trait]
+pub trait TransactionLock: Send + Sync {
+    /// Wait until the lock is free. It is held until the returned guard is dropped.
+    async fn acquire(&self) -> Result<TransactionLockGuard, Error>;
+}
+
+/// Excludes the callers in this process that share the lock, e.g. all clones of a
+/// `BitcoinCore`.
+#[derive(Clone, Default)]
+pub struct ProcessLock(Arc<Mutex<()>>);
+
+#[async_trait]
+impl TransactionLock for ProcessLock {
+    async fn acquire(&self) -> Result<TransactionLockGuard, Error> {
+        Ok(TransactionLockGuard::new(self.0.clone().lock_owned().await))
+    }
+}
+
+/// Excludes every process that locks the same file, e.g. a vault and an operator tool
+/// running on the same host. The lock is an advisory `flock`, so it is released by the
+/// operating system if the holder crashes.
+#[cfg(unix)]
+#[derive(Clone)]
+pub struct FileLock {
+    path: PathBuf,
+    /// Avoids polling the file while another task of this process holds it.
+    local: ProcessLock,
+}
+
+#[cfg(unix)]
+impl FileLock {
+    /// Lock the file at `path`, which is created if it does not exist.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            local: ProcessLock::default(),
+        }
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl TransactionLock for FileLock {
+    async fn acquire(&self) -> Result<TransactionLockGuard, Error> {
+        use std::{fs::OpenOptions, io, os::unix::io::AsRawFd};
+
+        let local = self.local.acquire().await?;
+        let path = self.path.clone();
+        // waiting for another process blocks the thread, so keep it off the executor
+        let file = tokio::task::spawn_blocking(move || {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)?;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(file)
+        })
+        .await
+        .map_err(io::Error::other)
+        .and_then(|result| result)
+        .map_err(Error::TransactionLockError)?;
+        // closing the file releases the lock
+        Ok(TransactionLockGuard::new((local, file)))
+    }
+}
+
+/// Does not make transactions wait for each other. Instead, every process relies on bitcoind
+/// reserving the inputs of a funded transaction with `lockunspent`, which holds for all
+/// processes using the wallet, even on different hosts. Funding fails with an invalid
+/// parameter error if explicitly selected outputs were reserved concurrently, see
+/// [`FeePolicy::coin_selection`](crate::FeePolicy::coin_selection).
+#[derive(Clone, Default)]
+pub struct UtxoReservation;
+
+#[async_trait]
+impl TransactionLock for UtxoReservation {
+    async fn acquire(&self) -> Result<TransactionLockGuard, Error> {
+        Ok(TransactionLockGuard::new(()))
+    }
+}
+
+/// Outputs locked with `lockunspent` while funding a transaction that has not been broadcast
+/// yet. They are unlocked when the reservation is dropped, e.g. along with a
+/// [`LockedTransaction`](crate::LockedTransaction) that is abandoned, unless it was disarmed
+/// because the transaction was sent or the outputs were unlocked already.
+pub struct InputReservation {
+    outpoints: Vec<OutPoint>,
+    unlock: Option<Box<dyn FnOnce(Vec<OutPoint>) + Send + Sync>>,
+}
+
+impl InputReservation {
+    /// Reserve `outpoints`, calling `unlock` with them on drop. It must not block, as it may
+    /// be called from async code.
+    pub fn new<F>(outpoints: Vec<OutPoint>, unlock: F) -> Self
+    where
+        F: FnOnce(Vec<OutPoint>) + Send + Sync + 'static,
+    {
+        Self {
+            outpoints,
+            unlock: Some(Box::new(unlock)),
+        }
+    }
+
+    pub fn outpoints(&self) -> &[OutPoint] {
+        &self.outpoints
+    }
+
+    /// Keep the outputs locked on drop.
+    pub fn disarm(&mut self) {
+        self.unlock = None;
+    }
+}
+
+impl Drop for InputReservation {
+    fn drop(&mut self) {
+        if let Some(unlock) = self.unlock.take() {
+            unlock(std::mem::take(&mut self.outpoints));
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_file_lock_excludes_other_holders() {
+        let path = std::env::temp_dir().join(format!("bitcoin-lock-{}", std::process::id()));
+        // a different instance behaves like another process
+        let (first, second) = (FileLock::new(&path), FileLock::new(&path));
+
+        let guard = first.acquire().await.unwrap();
+        assert!(timeout(Duration::from_millis(300), second.acquire())
+            .await
+            .is_err());
+
+        drop(guard);
+        assert!(timeout(Duration::from_secs(1), second.acquire())
+            .await
+            .unwrap()
+            .is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+}
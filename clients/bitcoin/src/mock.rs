@@ -0,0 +1,2373 @@
+//! In-memory implementation of [`BitcoinCoreApi`] that simulates a single-node chain, mempool
+//! and wallet, so that vault and relayer logic can be tested without a running bitcoind.
+
+use crate::{
+    addr::{self, H256},
+    batch::{self, Payout},
+    coin_selection::Utxo,
+    deserialize,
+    fee::{self, ChildPaysForParent, FeePolicy, DEFAULT_CONF_TARGET, DUST_LIMIT, RBF_SEQUENCE},
+    journal::{self, JournalEntry, TransactionJournal, TransactionStatus},
+    json::GetBlockResult,
+    lock::{InputReservation, ProcessLock, TransactionLock, TransactionLockGuard},
+    policy::{BroadcastPolicy, MempoolAcceptance},
+    psbt::{LockedPsbt, PartiallySignedTransaction},
+    serialize, serialize_without_witness,
+    signer::Signer,
+    Address, Amount, BitcoinCoreApi, BitcoinError, BitcoinRpcError, Block, BlockHash, BlockHeader,
+    Error, JsonRpcError, LockedTransaction, Network, Payload, PrivateKey, PublicKey, RpcError,
+    Script, SecretKey, Transaction, TransactionExt, TransactionMetadata, TxIn, Txid,
+    PUBLIC_KEY_SIZE,
+};
+use async_trait::async_trait;
+use bitcoincore_rpc::bitcoin::{
+    blockdata::{constants::genesis_block, transaction::SigHashType},
+    hashes::{sha256, Hash},
+    secp256k1::{Message, Secp256k1, Signature},
+    util::{bip143::SigHashCache, merkleblock::MerkleBlock},
+    OutPoint, TxOut,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex as StdMutex, MutexGuard},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+/// How often the waiting methods re-check the simulated chain.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Fee rate (sat/vB) used when funding wallet transactions.
+pub const DEFAULT_FEE_RATE: u64 = 10;
+
+/// Minimum increase of the fee rate (sat/vB) for a replacement to be accepted.
+const INCREMENTAL_RELAY_FEE: u64 = 1;
+
+/// Compact target used for all simulated blocks (the regtest minimum difficulty).
+const REGTEST_BITS: u32 = 0x207fffff;
+
+/// Reward paid by the coinbase of every simulated block.
+const BLOCK_REWARD: u64 = 50 * 100_000_000;
+
+/// An unspent output that can be spent by the simulated wallet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockUtxo {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+    pub confirmations: u32,
+}
+
+#[derive(Default)]
+struct MockState {
+    /// Blocks of the main chain, indexed by height.
+    chain: Vec<Block>,
+    /// Transactions that have been accepted but not yet mined, in submission order.
+    mempool: Vec<Transaction>,
+    /// Wallet keys, indexed by the p2wpkh script they can spend.
+    keys: HashMap<Script, PrivateKey>,
+    /// Counter used to deterministically derive new wallet keys.
+    key_index: u64,
+    /// Counter used to make faucet transactions unique.
+    faucet_index: u32,
+    /// Blocks disconnected by [`MockBitcoinCore::invalidate_block`].
+    stale: Vec<Block>,
+    /// Fee rate in sat/vB returned by `estimate_fee_rate`.
+    fee_estimate: Option<u64>,
+    /// Whether mined transactions can be looked up by txid, like `-txindex`.
+    txindex: bool,
+    /// Scripts of the change outputs created by the wallet.
+    change: HashSet<Script>,
+    /// Scripts of the deposit addresses added by `add_new_deposit_key`.
+    deposits: HashSet<Script>,
+    /// Outputs excluded from funding, like `lockunspent`.
+    locked: HashSet<OutPoint>,
+    /// Scripts of the signer's keys, which the wallet tracks but cannot sign for.
+    watched: HashSet<Script>,
+}
+
+/// Simulated bitcoind: blocks are only produced when [`MockBitcoinCore::mine_block`] is called,
+/// and every transaction created by the wallet is properly signed (p2wpkh).
+#[derive(Clone)]
+pub struct MockBitcoinCore {
+    network: Network,
+    state: Arc<StdMutex<MockState>>,
+    transaction_creation_lock: Arc<dyn TransactionLock>,
+    signer: Option<Arc<dyn Signer>>,
+    idempotent_payments: bool,
+    journal: Option<Arc<dyn TransactionJournal>>,
+    broadcast_policy: Option<BroadcastPolicy>,
+}
+
+/// Builds an error that is indistinguishable from the one bitcoind would return, so that
+/// the helpers on [`Error`] (e.g. `is_invalid_parameter`) behave the same for the mock.
+fn rpc_error(code: BitcoinRpcError, message: &str) -> Error {
+    Error::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Rpc(RpcError {
+        code: code as i32,
+        message: message.to_string(),
+        data: None,
+    })))
+}
+
+fn p2wpkh_script(public_key: &PublicKey) -> Script {
+    Script::new_v0_wpkh(
+        &public_key
+            .wpubkey_hash()
+            .expect("wallet keys are compressed"),
+    )
+}
+
+/// Rough virtual size of a p2wpkh spending transaction, used for fee calculation.
+fn estimate_vsize(num_inputs: usize, num_outputs: usize) -> u64 {
+    (11 + num_inputs * 68 + num_outputs * 31) as u64
+}
+
+impl MockState {
+    fn tip_height(&self) -> u32 {
+        (self.chain.len() - 1) as u32
+    }
+
+    fn height_of(&self, block_hash: &BlockHash) -> Option<u32> {
+        self.chain
+            .iter()
+            .position(|block| &block.block_hash() == block_hash)
+            .map(|height| height as u32)
+    }
+
+    fn block(&self, block_hash: &BlockHash) -> Result<&Block, Error> {
+        self.chain
+            .iter()
+            .find(|block| &block.block_hash() == block_hash)
+            .ok_or_else(|| rpc_error(BitcoinRpcError::RpcInvalidAddressOrKey, "Block not found"))
+    }
+
+    fn confirmations(&self, height: u32) -> u32 {
+        self.tip_height() + 1 - height
+    }
+
+    /// Find a mined transaction, returning it together with its block height.
+    fn find_mined(&self, txid: &Txid) -> Option<(u32, &Transaction)> {
+        self.chain.iter().enumerate().find_map(|(height, block)| {
+            block
+                .txdata
+                .iter()
+                .find(|tx| &tx.txid() == txid)
+                .map(|tx| (height as u32, tx))
+        })
+    }
+
+    fn find_in_mempool(&self, txid: &Txid) -> Option<&Transaction> {
+        self.mempool.iter().find(|tx| &tx.txid() == txid)
+    }
+
+    fn transactions(&self) -> impl Iterator<Item = (Option<u32>, &Transaction)> {
+        self.chain
+            .iter()
+            .enumerate()
+            .flat_map(|(height, block)| {
+                block.txdata.iter().map(move |tx| (Some(height as u32), tx))
+            })
+            .chain(self.mempool.iter().map(|tx| (None, tx)))
+    }
+
+    fn new_key(&mut self, network: Network) -> PrivateKey {
+        let secp = Secp256k1::new();
+        loop {
+            self.key_index += 1;
+            let seed = sha256::Hash::hash(&self.key_index.to_be_bytes());
+            if let Ok(key) = SecretKey::from_slice(&seed[..]) {
+                let private_key = PrivateKey {
+                    compressed: true,
+                    network,
+                    key,
+                };
+                let public_key = PublicKey::from_private_key(&secp, &private_key);
+                self.keys.insert(p2wpkh_script(&public_key), private_key);
+                return private_key;
+            }
+        }
+    }
+
+    fn find_key(&self, public_key: &PublicKey) -> Option<&PrivateKey> {
+        self.keys.get(&p2wpkh_script(public_key))
+    }
+
+    fn utxos(&self) -> Vec<MockUtxo> {
+        let spent: HashSet<OutPoint> = self
+            .transactions()
+            .flat_map(|(_, tx)| tx.input.iter().map(|input| input.previous_output))
+            .collect();
+        self.transactions()
+            .flat_map(|(height, tx)| {
+                let txid = tx.txid();
+                tx.output
+                    .iter()
+                    .enumerate()
+                    .map(move |(vout, txout)| (height, OutPoint::new(txid, vout as u32), txout))
+            })
+            .filter(|(_, outpoint, txout)| {
+                !spent.contains(outpoint)
+                    && (self.keys.contains_key(&txout.script_pubkey)
+                        || self.watched.contains(&txout.script_pubkey))
+            })
+            .map(|(height, outpoint, txout)| MockUtxo {
+                outpoint,
+                txout: txout.clone(),
+                confirmations: height.map(|height| self.confirmations(height)).unwrap_or(0),
+            })
+            .collect()
+    }
+
+    /// Unspent outputs that are not locked.
+    fn spendable(&self) -> Vec<Utxo> {
+        self.utxos()
+            .into_iter()
+            .filter(|utxo| !self.locked.contains(&utxo.outpoint))
+            .map(|utxo| Utxo {
+                outpoint: utxo.outpoint,
+                amount: utxo.txout.value,
+                is_deposit: self.deposits.contains(&utxo.txout.script_pubkey),
+                script_pubkey: utxo.txout.script_pubkey,
+                confirmations: utxo.confirmations,
+            })
+            .collect()
+    }
+
+    fn unlock_inputs(&mut self, transaction: &Transaction) {
+        for input in transaction.input.iter() {
+            self.locked.remove(&input.previous_output);
+        }
+    }
+
+    /// Sign all inputs of `transaction`, which must spend wallet p2wpkh outputs.
+    fn sign(&self, transaction: &mut Transaction) -> Result<(), Error> {
+        let secp = Secp256k1::new();
+        let prevouts = transaction
+            .input
+            .iter()
+            .map(|input| self.prevout(&input.previous_output))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut cache = SigHashCache::new(transaction);
+        for (index, prevout) in prevouts.into_iter().enumerate() {
+            let private_key = self
+                .keys
+                .get(&prevout.script_pubkey)
+                .ok_or(Error::TransactionSigningError)?;
+            let public_key = PublicKey::from_private_key(&secp, private_key);
+            let script_code = Script::new_p2pkh(&public_key.pubkey_hash());
+            let sighash =
+                cache.signature_hash(index, &script_code, prevout.value, SigHashType::All);
+            let signature = secp.sign(&Message::from_slice(&sighash[..])?, &private_key.key);
+
+            let mut signature = signature.serialize_der().to_vec();
+            signature.push(SigHashType::All as u8);
+            *cache.access_witness(index) = vec![signature, public_key.to_bytes()];
+        }
+        Ok(())
+    }
+
+    /// Fee paid by `transaction`, all of its inputs must be known.
+    fn fee(&self, transaction: &Transaction) -> Result<u64, Error> {
+        let input_value = transaction
+            .input
+            .iter()
+            .map(|input| Ok(self.prevout(&input.previous_output)?.value))
+            .sum::<Result<u64, Error>>()?;
+        let output_value: u64 = transaction.output.iter().map(|output| output.value).sum();
+        input_value.checked_sub(output_value).ok_or_else(|| {
+            rpc_error(
+                BitcoinRpcError::RpcVerifyRejected,
+                "bad-txns-in-belowout, value in < value out",
+            )
+        })
+    }
+
+    fn prevout(&self, outpoint: &OutPoint) -> Result<TxOut, Error> {
+        self.transactions()
+            .find(|(_, tx)| tx.txid() == outpoint.txid)
+            .and_then(|(_, tx)| tx.output.get(outpoint.vout as usize).cloned())
+            .ok_or_else(|| {
+                rpc_error(
+                    BitcoinRpcError::RpcInvalidAddressOrKey,
+                    "Input not found or already spent",
+                )
+            })
+    }
+
+    /// Emulates `testmempoolaccept`: only transactions spending unknown outputs or more
+    /// than their inputs are rejected.
+    fn test_mempool_accept(&self, transaction: &Transaction) -> MempoolAcceptance {
+        let rejected = |reason: &str| MempoolAcceptance {
+            allowed: false,
+            reject_reason: Some(reason.to_string()),
+            fee: None,
+        };
+        let input_value = match transaction
+            .input
+            .iter()
+            .map(|input| self.prevout(&input.previous_output))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(prevouts) => prevouts.iter().map(|prevout| prevout.value).sum::<u64>(),
+            Err(_) => return rejected("missing-inputs"),
+        };
+        let output_value = transaction.output.iter().map(|output| output.value).sum();
+        match input_value.checked_sub(output_value) {
+            Some(fee) => MempoolAcceptance {
+                allowed: true,
+                reject_reason: None,
+                fee: Some(fee),
+            },
+            None => rejected("bad-txns-in-belowout"),
+        }
+    }
+
+    fn append_block(&mut self, txdata: Vec<Transaction>, coinbase_script: Script) -> BlockHash {
+        let height = self.chain.len() as u32;
+        let tip = self
+            .chain
+            .last()
+            .expect("chain always contains genesis")
+            .header;
+
+        // BIP34-style height push to make every coinbase unique
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::from([&[4u8][..], &height.to_le_bytes()[..]].concat()),
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: BLOCK_REWARD,
+                script_pubkey: coinbase_script,
+            }],
+        };
+
+        let mut block = Block {
+            header: BlockHeader {
+                version: 4,
+                prev_blockhash: tip.block_hash(),
+                merkle_root: Default::default(),
+                time: tip.time + 600,
+                bits: REGTEST_BITS,
+                // blocks replacing invalidated ones must not have the same hash
+                nonce: self.stale.len() as u32,
+            },
+            txdata: std::iter::once(coinbase).chain(txdata).collect(),
+        };
+        block.header.merkle_root = block.merkle_root();
+        let block_hash = block.block_hash();
+        self.chain.push(block);
+        block_hash
+    }
+}
+
+impl MockBitcoinCore {
+    /// Create a new simulated node whose chain only contains the genesis block of `network`.
+    pub fn new(network: Network) -> Self {
+        let state = MockState {
+            chain: vec![genesis_block(network)],
+            fee_estimate: Some(DEFAULT_FEE_RATE),
+            ..Default::default()
+        };
+        Self {
+            network,
+            state: Arc::new(StdMutex::new(state)),
+            transaction_creation_lock: Arc::new(ProcessLock::default()),
+            signer: None,
+            idempotent_payments: false,
+            journal: None,
+            broadcast_policy: None,
+        }
+    }
+
+    /// Make the wallet watch-only, like [`BitcoinCore::with_signer`](crate::BitcoinCore::with_signer):
+    /// new addresses and change pay to the first key of `signer`, which signs all wallet
+    /// transactions.
+    pub fn with_signer<S: Signer + 'static>(mut self, signer: S) -> Self {
+        self.state()
+            .watched
+            .extend(signer.public_keys().iter().map(p2wpkh_script));
+        self.signer = Some(Arc::new(signer));
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().expect("mock state poisoned")
+    }
+
+    /// `create_batch_transaction`, holding `lock` already.
+    async fn create_batch_transaction_locked(
+        &self,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+        lock: TransactionLockGuard,
+    ) -> Result<LockedTransaction, Error> {
+        if let Some(ref signer) = self.signer {
+            let mut psbt = self
+                .create_psbt_locked(payouts, request_id, fee_policy, lock)
+                .await?;
+            if let Err(err) = signer.sign_psbt(&mut psbt.psbt).await {
+                self.state().unlock_inputs(&psbt.psbt.global.unsigned_tx);
+                return Err(err);
+            }
+            return self.finalize_psbt(psbt).await;
+        }
+
+        batch::check_payouts(&payouts)?;
+        // without an explicit fee rate, bitcoind falls back to its own estimate
+        let fee_rate = fee_policy
+            .resolve_fee_rate(self)
+            .await?
+            .unwrap_or(DEFAULT_FEE_RATE);
+        let mut transaction = self.fund(&payouts, request_id, fee_rate, &fee_policy)?;
+        let mut state = self.state();
+        if let Err(err) = state.sign(&mut transaction) {
+            state.unlock_inputs(&transaction);
+            return Err(err);
+        }
+        let reservation = self.reserve_inputs(&transaction);
+        Ok(
+            LockedTransaction::new(transaction, payouts, request_id, Some(lock))
+                .with_reservation(Some(reservation)),
+        )
+    }
+
+    /// `create_psbt`, holding `lock` already.
+    async fn create_psbt_locked(
+        &self,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+        lock: TransactionLockGuard,
+    ) -> Result<LockedPsbt, Error> {
+        batch::check_payouts(&payouts)?;
+        let fee_rate = fee_policy
+            .resolve_fee_rate(self)
+            .await?
+            .unwrap_or(DEFAULT_FEE_RATE);
+        let transaction = self.fund(&payouts, request_id, fee_rate, &fee_policy)?;
+        let state = self.state();
+        let witness_utxos = transaction
+            .input
+            .iter()
+            .map(|input| state.prevout(&input.previous_output))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(transaction)?;
+        for (input, witness_utxo) in psbt.inputs.iter_mut().zip(witness_utxos) {
+            input.witness_utxo = Some(witness_utxo);
+        }
+        let reservation = self.reserve_inputs(&psbt.global.unsigned_tx);
+        Ok(LockedPsbt::new(psbt, payouts, request_id, Some(lock))
+            .with_reservation(Some(reservation)))
+    }
+
+    /// Unlock the inputs of `transaction` if it is dropped without being sent, like
+    /// `BitcoinCore` does.
+    fn reserve_inputs(&self, transaction: &Transaction) -> InputReservation {
+        let outpoints = transaction
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+            .collect();
+        let state = self.state.clone();
+        InputReservation::new(outpoints, move |outpoints| {
+            let mut state = state.lock().expect("mock state poisoned");
+            for outpoint in outpoints {
+                state.locked.remove(&outpoint);
+            }
+        })
+    }
+
+    /// A new key of the wallet, or the first key of the signer if the wallet is watch-only.
+    fn new_public_key(&self, state: &mut MockState) -> Result<PublicKey, Error> {
+        match self.signer {
+            Some(ref signer) => signer
+                .public_keys()
+                .into_iter()
+                .next()
+                .ok_or(Error::MissingPublicKey),
+            None => {
+                let private_key = state.new_key(self.network);
+                Ok(PublicKey::from_private_key(&Secp256k1::new(), &private_key))
+            }
+        }
+    }
+
+    /// Mine a block containing all mempool transactions.
+    pub fn mine_block(&self) -> BlockHash {
+        let mut state = self.state();
+        let txdata = std::mem::take(&mut state.mempool);
+        // the coinbase is not spendable by the wallet, so it does not change the balance
+        state.append_block(txdata, Script::new_op_return(&[]))
+    }
+
+    /// Mine `count` blocks, returning their hashes in order.
+    pub fn mine_blocks(&self, count: u32) -> Vec<BlockHash> {
+        (0..count).map(|_| self.mine_block()).collect()
+    }
+
+    /// Set the fee rate (in sat/vB) returned by `estimate_fee_rate`, `None` simulates a node
+    /// without enough data for an estimate.
+    pub fn set_fee_estimate(&self, fee_rate: Option<u64>) {
+        self.state().fee_estimate = fee_rate;
+    }
+
+    /// Return earlier payments of a request instead of paying again, like
+    /// [`BitcoinCore::with_idempotent_payments`](crate::BitcoinCore::with_idempotent_payments).
+    pub fn with_idempotent_payments(mut self, idempotent_payments: bool) -> Self {
+        self.idempotent_payments = idempotent_payments;
+        self
+    }
+
+    /// Record sent transactions in `journal`, like
+    /// [`BitcoinCore::with_journal`](crate::BitcoinCore::with_journal).
+    pub fn with_journal<J: TransactionJournal + 'static>(mut self, journal: J) -> Self {
+        self.journal = Some(Arc::new(journal));
+        self
+    }
+
+    /// Check transactions against `policy` before they are broadcast, like
+    /// [`BitcoinCore::with_broadcast_policy`](crate::BitcoinCore::with_broadcast_policy).
+    pub fn with_broadcast_policy(mut self, policy: BroadcastPolicy) -> Self {
+        self.broadcast_policy = Some(policy);
+        self
+    }
+
+    /// Index mined transactions for `find_transaction_block`, like `-txindex`. Disabled by
+    /// default.
+    pub fn set_txindex(&self, txindex: bool) {
+        self.state().txindex = txindex;
+    }
+
+    /// Disconnect the block and all its descendants from the main chain, like `invalidateblock`.
+    /// Their transactions are returned to the mempool, so mining replaces the stale blocks.
+    pub fn invalidate_block(&self, block_hash: &BlockHash) {
+        let mut state = self.state();
+        let height = state
+            .height_of(block_hash)
+            .expect("block is in the main chain");
+        assert!(height > 0, "cannot invalidate the genesis block");
+        let disconnected = state.chain.split_off(height as usize);
+        let mut mempool: Vec<_> = disconnected
+            .iter()
+            .flat_map(|block| block.txdata.iter().skip(1).cloned())
+            .collect();
+        mempool.append(&mut state.mempool);
+        state.mempool = mempool;
+        state.stale.extend(disconnected);
+    }
+
+    /// Credit `sat` to a fresh wallet address (the signer's, if watch-only) by mining a block
+    /// containing a faucet transaction. Returns the id of the faucet transaction.
+    pub fn fund_wallet(&self, sat: u64) -> Txid {
+        let mut state = self.state();
+        let public_key = self.new_public_key(&mut state).expect("signer has no keys");
+
+        state.faucet_index += 1;
+        let faucet_tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::default(), state.faucet_index),
+                script_sig: Script::new(),
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: sat,
+                script_pubkey: p2wpkh_script(&public_key),
+            }],
+        };
+        let txid = faucet_tx.txid();
+
+        let mut txdata = std::mem::take(&mut state.mempool);
+        txdata.push(faucet_tx);
+        state.append_block(txdata, Script::new_op_return(&[]));
+        txid
+    }
+
+    /// Add a transaction that was created outside of the wallet (e.g. a user's deposit)
+    /// to the mempool. Inputs are not validated.
+    pub fn add_to_mempool(&self, transaction: Transaction) -> Txid {
+        let txid = transaction.txid();
+        self.state().mempool.push(transaction);
+        txid
+    }
+
+    /// Unspent outputs owned by the wallet, including unconfirmed ones.
+    pub fn list_unspent(&self) -> Vec<MockUtxo> {
+        self.state().utxos()
+    }
+
+    /// Total value of the wallet's unspent outputs.
+    pub fn get_balance(&self) -> Amount {
+        Amount::from_sat(
+            self.list_unspent()
+                .iter()
+                .map(|utxo| utxo.txout.value)
+                .sum(),
+        )
+    }
+
+    /// Fund a transaction paying `payouts` (and the OP_RETURN of `request_id`) at `fee_rate`
+    /// sat/vB, adding a change output (at the position requested by the policy) if needed. The
+    /// inputs are locked but not signed.
+    fn fund(
+        &self,
+        payouts: &[Payout],
+        request_id: Option<H256>,
+        fee_rate: u64,
+        fee_policy: &FeePolicy,
+    ) -> Result<Transaction, Error> {
+        let mut outputs: Vec<_> = payouts
+            .iter()
+            .map(|payout| TxOut {
+                value: payout.sat,
+                script_pubkey: payout.address.script_pubkey(),
+            })
+            .collect();
+        if let Some(request_id) = request_id {
+            outputs.insert(
+                1,
+                TxOut {
+                    value: 0,
+                    script_pubkey: Script::new_op_return(request_id.as_bytes()),
+                },
+            );
+        }
+        if let Some(change_position) = fee_policy.change_position {
+            if change_position as usize > outputs.len() {
+                return Err(rpc_error(
+                    BitcoinRpcError::RpcInvalidParameter,
+                    "changePosition out of bounds",
+                ));
+            }
+        }
+
+        let mut state = self.state();
+        let mut utxos = state.spendable();
+        let target: u64 = outputs.iter().map(|output| output.value).sum();
+        let selected = match fee_policy.coin_selection {
+            Some(ref coin_selection) => coin_selection.select(
+                utxos,
+                target + fee_rate * estimate_vsize(0, outputs.len()),
+                fee_rate,
+            )?,
+            None => {
+                // largest first, to keep the number of inputs low
+                utxos.sort_by_key(|utxo| std::cmp::Reverse(utxo.amount));
+                let mut selected = Vec::new();
+                let mut total = 0;
+                for utxo in utxos {
+                    if total
+                        >= target + fee_rate * estimate_vsize(selected.len(), outputs.len() + 1)
+                    {
+                        break;
+                    }
+                    total += utxo.amount;
+                    selected.push(utxo);
+                }
+                selected
+            }
+        };
+
+        let total: u64 = selected.iter().map(|utxo| utxo.amount).sum();
+        if total < target + fee_rate * estimate_vsize(selected.len(), outputs.len()) {
+            return Err(rpc_error(
+                BitcoinRpcError::RpcWalletInsufficientFunds,
+                "Insufficient funds",
+            ));
+        }
+
+        // without change, the excess goes to the fee
+        let fee = fee_rate * estimate_vsize(selected.len(), outputs.len() + 1);
+        let change = total.saturating_sub(target + fee);
+        if change >= DUST_LIMIT {
+            let public_key = self.new_public_key(&mut state)?;
+            let change_position = fee_policy
+                .change_position
+                .map_or(outputs.len(), |position| position as usize);
+            state.change.insert(p2wpkh_script(&public_key));
+            outputs.insert(
+                change_position,
+                TxOut {
+                    value: change,
+                    script_pubkey: p2wpkh_script(&public_key),
+                },
+            );
+        }
+
+        let sequence = if fee_policy.replaceable == Some(true) {
+            RBF_SEQUENCE
+        } else {
+            u32::MAX
+        };
+
+        let transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: selected
+                .into_iter()
+                .map(|utxo| TxIn {
+                    previous_output: utxo.outpoint,
+                    script_sig: Script::new(),
+                    sequence,
+                    witness: vec![],
+                })
+                .collect(),
+            output: outputs,
+        };
+        // like `lockUnspents`, until the transaction is sent
+        state
+            .locked
+            .extend(transaction.input.iter().map(|input| input.previous_output));
+        Ok(transaction)
+    }
+}
+
+#[async_trait]
+impl BitcoinCoreApi for MockBitcoinCore {
+    async fn wait_for_block(&self, height: u32, num_confirmations: i32) -> Result<Block, Error> {
+        loop {
+            {
+                let state = self.state();
+                if let Some(block) = state.chain.get(height as usize) {
+                    if state.confirmations(height) as i32 >= num_confirmations {
+                        return Ok(block.clone());
+                    }
+                }
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn get_block_count(&self) -> Result<u64, Error> {
+        Ok(self.state().tip_height() as u64)
+    }
+
+    async fn get_raw_tx(&self, txid: &Txid, block_hash: &BlockHash) -> Result<Vec<u8>, Error> {
+        let state = self.state();
+        state
+            .block(block_hash)?
+            .txdata
+            .iter()
+            .find(|tx| &tx.txid() == txid)
+            .map(serialize)
+            .ok_or_else(|| {
+                rpc_error(
+                    BitcoinRpcError::RpcInvalidAddressOrKey,
+                    "No such transaction found in the provided block",
+                )
+            })
+    }
+
+    async fn get_proof(&self, txid: Txid, block_hash: &BlockHash) -> Result<Vec<u8>, Error> {
+        let state = self.state();
+        let block = state.block(block_hash)?;
+        if !block.txdata.iter().any(|tx| tx.txid() == txid) {
+            return Err(rpc_error(
+                BitcoinRpcError::RpcInvalidAddressOrKey,
+                "Not all transactions found in specified or retrieved block",
+            ));
+        }
+        let txids = std::iter::once(txid).collect();
+        Ok(serialize(&MerkleBlock::from_block(block, &txids)))
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<BlockHash, Error> {
+        self.state()
+            .chain
+            .get(height as usize)
+            .map(Block::block_hash)
+            .ok_or(Error::InvalidBitcoinHeight)
+    }
+
+    async fn get_block_hashes(&self, heights: &[u32]) -> Result<Vec<BlockHash>, Error> {
+        let state = self.state();
+        heights
+            .iter()
+            .map(|height| {
+                state
+                    .chain
+                    .get(*height as usize)
+                    .map(Block::block_hash)
+                    .ok_or(Error::InvalidBitcoinHeight)
+            })
+            .collect()
+    }
+
+    async fn is_block_known(&self, block_hash: BlockHash) -> Result<bool, Error> {
+        Ok(self.state().height_of(&block_hash).is_some())
+    }
+
+    async fn get_new_address(&self) -> Result<Address, Error> {
+        let public_key = self.new_public_key(&mut self.state())?;
+        Ok(Address::p2wpkh(&public_key, self.network).map_err(crate::ConversionError::from)?)
+    }
+
+    async fn get_new_public_key<P: From<[u8; PUBLIC_KEY_SIZE]> + 'static>(
+        &self,
+    ) -> Result<P, Error> {
+        let public_key = self.new_public_key(&mut self.state())?;
+        Ok(P::from(public_key.key.serialize()))
+    }
+
+    async fn add_new_deposit_key<P: Into<[u8; PUBLIC_KEY_SIZE]> + Send + Sync + 'static>(
+        &self,
+        public_key: P,
+        secret_key: Vec<u8>,
+    ) -> Result<(), Error> {
+        let public_key = PublicKey::from_slice(&public_key.into())?;
+        let mut state = self.state();
+        let private_key = *state.find_key(&public_key).ok_or_else(|| {
+            rpc_error(
+                BitcoinRpcError::RpcWalletError,
+                "Private key for address is not known",
+            )
+        })?;
+        let deposit_secret_key = addr::calculate_deposit_secret_key(
+            private_key.key,
+            SecretKey::from_slice(&secret_key)?,
+        )?;
+        let deposit_key = PrivateKey {
+            key: deposit_secret_key,
+            ..private_key
+        };
+        let deposit_public_key = PublicKey::from_private_key(&Secp256k1::new(), &deposit_key);
+        let script = p2wpkh_script(&deposit_public_key);
+        state.deposits.insert(script.clone());
+        state.keys.insert(script, deposit_key);
+        Ok(())
+    }
+
+    async fn get_best_block_hash(&self) -> Result<BlockHash, Error> {
+        let state = self.state();
+        Ok(state.chain[state.tip_height() as usize].block_hash())
+    }
+
+    async fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        self.state().block(hash).cloned()
+    }
+
+    async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, Error> {
+        let state = self.state();
+        // like bitcoind, headers of stale blocks remain available
+        match state.stale.iter().find(|block| &block.block_hash() == hash) {
+            Some(block) => Ok(block.header),
+            None => state.block(hash).map(|block| block.header),
+        }
+    }
+
+    async fn get_block_info(&self, hash: &BlockHash) -> Result<GetBlockResult, Error> {
+        let state = self.state();
+        let block = state.block(hash)?;
+        let height = state.height_of(hash).expect("block is in the chain");
+        Ok(GetBlockResult {
+            hash: *hash,
+            confirmations: state.confirmations(height) as i32,
+            size: block.get_size(),
+            strippedsize: None,
+            weight: block.get_weight(),
+            height: height as usize,
+            version: block.header.version,
+            version_hex: None,
+            merkleroot: block.header.merkle_root,
+            tx: block.txdata.iter().map(Transaction::txid).collect(),
+            time: block.header.time as usize,
+            mediantime: None,
+            nonce: block.header.nonce,
+            bits: format!("{:08x}", block.header.bits),
+            difficulty: 0.0,
+            chainwork: vec![],
+            n_tx: block.txdata.len(),
+            previousblockhash: height.checked_sub(1).map(|_| block.header.prev_blockhash),
+            nextblockhash: state.chain.get(height as usize + 1).map(Block::block_hash),
+        })
+    }
+
+    async fn get_mempool_transactions<'a>(
+        &'a self,
+    ) -> Result<Box<dyn Iterator<Item = Result<Transaction, Error>> + Send + 'a>, Error> {
+        let mempool = self.state().mempool.clone();
+        Ok(Box::new(mempool.into_iter().map(Ok)))
+    }
+
+    async fn get_transactions(&self, txids: &[Txid]) -> Result<Vec<Option<Transaction>>, Error> {
+        // mined transactions are only found with -txindex, see `set_txindex`
+        let state = self.state();
+        Ok(txids
+            .iter()
+            .map(|txid| {
+                state
+                    .find_in_mempool(txid)
+                    .or_else(|| {
+                        state
+                            .find_mined(txid)
+                            .filter(|_| state.txindex)
+                            .map(|(_, tx)| tx)
+                    })
+                    .cloned()
+            })
+            .collect())
+    }
+
+    async fn find_transaction_block(&self, txid: &Txid) -> Result<Option<BlockHash>, Error> {
+        let state = self.state();
+        if !state.txindex {
+            return Ok(None);
+        }
+        Ok(state
+            .find_mined(txid)
+            .map(|(height, _)| state.chain[height as usize].block_hash()))
+    }
+
+    async fn find_request_payment(&self, request_id: H256) -> Result<Option<Txid>, Error> {
+        let state = self.state();
+        // like `listtransactions`, only transactions spending wallet outputs were sent by it
+        let sent = |tx: &Transaction| {
+            tx.input.iter().any(|input| {
+                matches!(state.prevout(&input.previous_output),
+                    Ok(prevout) if state.keys.contains_key(&prevout.script_pubkey))
+            })
+        };
+        let txid = state
+            .transactions()
+            .find(|(_, tx)| tx.get_op_return() == Some(request_id) && sent(tx))
+            .map(|(_, tx)| tx.txid());
+        Ok(txid)
+    }
+
+    async fn wait_for_payment(
+        &self,
+        payload: Payload,
+        min_amount: Option<u64>,
+        since_height: u32,
+        num_confirmations: u32,
+    ) -> Result<Vec<TransactionMetadata>, Error> {
+        crate::tracker::wait_for_payment(self, payload, min_amount, since_height, num_confirmations)
+            .await
+    }
+
+    async fn wait_for_transaction_metadata(
+        &self,
+        txid: Txid,
+        num_confirmations: u32,
+    ) -> Result<TransactionMetadata, Error> {
+        let (block_height, block_hash) = loop {
+            {
+                let state = self.state();
+                if let Some((height, _)) = state.find_mined(&txid) {
+                    if state.confirmations(height) >= num_confirmations {
+                        break (height, state.chain[height as usize].block_hash());
+                    }
+                }
+            }
+            sleep(POLL_INTERVAL).await;
+        };
+
+        journal::update_status(&self.journal, &txid, TransactionStatus::Confirmed).await;
+        let raw_tx = self.get_raw_tx(&txid, &block_hash).await?;
+        Ok(TransactionMetadata {
+            txid,
+            proof: self.get_proof(txid, &block_hash).await?,
+            stripped_raw_tx: serialize_without_witness(&deserialize(&raw_tx)?),
+            raw_tx,
+            block_height,
+            block_hash,
+        })
+    }
+
+    async fn estimate_fee_rate(&self, _conf_target: u16) -> Result<Option<u64>, Error> {
+        Ok(self.state().fee_estimate)
+    }
+
+    async fn list_utxos(&self, min_confirmations: u32) -> Result<Vec<Utxo>, Error> {
+        let mut utxos = self.state().spendable();
+        utxos.retain(|utxo| utxo.confirmations >= min_confirmations);
+        Ok(utxos)
+    }
+
+    async fn lock_utxos(&self, outpoints: &[OutPoint]) -> Result<(), Error> {
+        let mut state = self.state();
+        let unspent: HashSet<_> = state
+            .utxos()
+            .into_iter()
+            .map(|utxo| utxo.outpoint)
+            .collect();
+        if !outpoints.iter().all(|outpoint| unspent.contains(outpoint)) {
+            return Err(rpc_error(
+                BitcoinRpcError::RpcInvalidParameter,
+                "Invalid parameter, expected unspent output",
+            ));
+        }
+        if outpoints
+            .iter()
+            .any(|outpoint| state.locked.contains(outpoint))
+        {
+            // e.g. selected concurrently by another process using the wallet
+            return Err(rpc_error(
+                BitcoinRpcError::RpcInvalidParameter,
+                "Invalid parameter, output already locked",
+            ));
+        }
+        state.locked.extend(outpoints.iter().copied());
+        Ok(())
+    }
+
+    async fn unlock_utxos(&self, outpoints: &[OutPoint]) -> Result<(), Error> {
+        let mut state = self.state();
+        if !outpoints
+            .iter()
+            .all(|outpoint| state.locked.contains(outpoint))
+        {
+            return Err(rpc_error(
+                BitcoinRpcError::RpcInvalidParameter,
+                "Invalid parameter, expected locked output",
+            ));
+        }
+        for outpoint in outpoints {
+            state.locked.remove(outpoint);
+        }
+        Ok(())
+    }
+
+    async fn create_transaction(
+        &self,
+        address: Address,
+        sat: u64,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+    ) -> Result<LockedTransaction, Error> {
+        self.create_batch_transaction(vec![Payout::new(address, sat)], request_id, fee_policy)
+            .await
+    }
+
+    async fn create_batch_transaction(
+        &self,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+    ) -> Result<LockedTransaction, Error> {
+        let lock = self.transaction_creation_lock.acquire().await?;
+        self.create_batch_transaction_locked(payouts, request_id, fee_policy, lock)
+            .await
+    }
+
+    async fn create_psbt(
+        &self,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+    ) -> Result<LockedPsbt, Error> {
+        let lock = self.transaction_creation_lock.acquire().await?;
+        self.create_psbt_locked(payouts, request_id, fee_policy, lock)
+            .await
+    }
+
+    async fn finalize_psbt(&self, mut psbt: LockedPsbt) -> Result<LockedTransaction, Error> {
+        let unsigned_tx = psbt.psbt.global.unsigned_tx.clone();
+        let mut cache = SigHashCache::new(&unsigned_tx);
+        for (index, input) in psbt.psbt.inputs.iter_mut().enumerate() {
+            // like bitcoind, only finalize inputs whose signature is valid
+            let witness = input.witness_utxo.as_ref().and_then(|prevout| {
+                let (public_key, signature) = input
+                    .partial_sigs
+                    .iter()
+                    .find(|(public_key, _)| p2wpkh_script(public_key) == prevout.script_pubkey)?;
+                let script_code = Script::new_p2pkh(&public_key.pubkey_hash());
+                let sighash =
+                    cache.signature_hash(index, &script_code, prevout.value, SigHashType::All);
+                let (sighash_type, der) = signature.split_last()?;
+                Secp256k1::verification_only()
+                    .verify(
+                        &Message::from_slice(&sighash[..]).ok()?,
+                        &Signature::from_der(der).ok()?,
+                        &public_key.key,
+                    )
+                    .ok()
+                    .filter(|_| *sighash_type == SigHashType::All as u8)?;
+                Some(vec![signature.clone(), public_key.to_bytes()])
+            });
+            match witness {
+                Some(witness) => {
+                    input.final_script_witness = Some(witness);
+                    input.partial_sigs.clear();
+                }
+                None => {
+                    self.state().unlock_inputs(&unsigned_tx);
+                    return Err(Error::IncompletePsbt);
+                }
+            }
+        }
+        let transaction = psbt.psbt.clone().extract_tx();
+        Ok(psbt.finalized(transaction))
+    }
+
+    async fn send_transaction(&self, mut transaction: LockedTransaction) -> Result<Txid, Error> {
+        if let Some(ref policy) = self.broadcast_policy {
+            let mut state = self.state();
+            let acceptance = state.test_mempool_accept(&transaction.transaction);
+            if let Err(err) = policy.check(&transaction, &acceptance) {
+                state.unlock_inputs(&transaction.transaction);
+                return Err(err.into());
+            }
+        }
+        if let Some(ref journal) = self.journal {
+            journal.record(JournalEntry::pending(&transaction)).await?;
+        }
+        let txid = transaction.transaction.txid();
+        transaction.disarm_reservation();
+        let mut state = self.state();
+        if state.find_mined(&txid).is_none() {
+            if state.find_in_mempool(&txid).is_none() {
+                // like bitcoind, replace the transactions it conflicts with, e.g. on a fee bump
+                let spent: Vec<_> = transaction
+                    .transaction
+                    .input
+                    .iter()
+                    .map(|input| input.previous_output)
+                    .collect();
+                state.mempool.retain(|tx| {
+                    !tx.input
+                        .iter()
+                        .any(|input| spent.contains(&input.previous_output))
+                });
+                state.mempool.push(transaction.transaction);
+            }
+            return Ok(txid);
+        }
+        // not a rejection, the transaction is confirmed once `rebroadcast_pending` runs
+        Err(rpc_error(
+            BitcoinRpcError::RpcVerifyAlreadyInChain,
+            "Transaction already in block chain",
+        ))
+    }
+
+    async fn rebroadcast_pending(&self) -> Result<Vec<Txid>, Error> {
+        let journal = match self.journal {
+            Some(ref journal) => journal,
+            None => return Ok(Vec::new()),
+        };
+        let mut rebroadcast = Vec::new();
+        for entry in journal.pending().await? {
+            let txid = entry.txid();
+            let status = {
+                let mut state = self.state();
+                let conflicts = |tx: &Transaction| {
+                    tx.txid() != txid
+                        && tx.input.iter().any(|input| {
+                            entry
+                                .transaction
+                                .input
+                                .iter()
+                                .any(|spent| spent.previous_output == input.previous_output)
+                        })
+                };
+                if state.find_mined(&txid).is_some() {
+                    Some(TransactionStatus::Confirmed)
+                } else if state.transactions().any(|(_, tx)| conflicts(tx)) {
+                    Some(TransactionStatus::Conflicted)
+                } else {
+                    if state.find_in_mempool(&txid).is_none() {
+                        state.mempool.push(entry.transaction.clone());
+                    }
+                    None
+                }
+            };
+            match status {
+                Some(status) => journal.set_status(&txid, status).await?,
+                None => rebroadcast.push(txid),
+            }
+        }
+        Ok(rebroadcast)
+    }
+
+    async fn create_and_send_transaction(
+        &self,
+        address: Address,
+        sat: u64,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+    ) -> Result<Txid, Error> {
+        self.create_and_send_batch_transaction(
+            vec![Payout::new(address, sat)],
+            request_id,
+            fee_policy,
+        )
+        .await
+    }
+
+    async fn create_and_send_batch_transaction(
+        &self,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        fee_policy: FeePolicy,
+    ) -> Result<Txid, Error> {
+        let lock = self.transaction_creation_lock.acquire().await?;
+        if let (true, Some(request_id)) = (self.idempotent_payments, request_id) {
+            if let Some(txid) = self.find_request_payment(request_id).await? {
+                return Ok(txid);
+            }
+        }
+        let tx = self
+            .create_batch_transaction_locked(payouts, request_id, fee_policy, lock)
+            .await?;
+        self.send_transaction(tx).await
+    }
+
+    async fn send_to_address(
+        &self,
+        address: Address,
+        sat: u64,
+        request_id: Option<H256>,
+        num_confirmations: u32,
+        fee_policy: FeePolicy,
+    ) -> Result<TransactionMetadata, Error> {
+        let txid = self
+            .create_and_send_transaction(address, sat, request_id, fee_policy)
+            .await?;
+        self.wait_for_transaction_metadata(txid, num_confirmations)
+            .await
+    }
+
+    async fn bump_fee(&self, txid: &Txid, fee_rate: Option<u64>) -> Result<Txid, Error> {
+        let estimate = self.estimate_fee_rate(DEFAULT_CONF_TARGET).await?;
+        let lock = self.transaction_creation_lock.acquire().await?;
+        let (original, replacement, payouts) = {
+            let state = self.state();
+            if state.find_mined(txid).is_some() {
+                return Err(rpc_error(
+                    BitcoinRpcError::RpcWalletError,
+                    "Transaction has been mined, or is conflicted with a mined transaction",
+                ));
+            }
+            let original = state.find_in_mempool(txid).cloned().ok_or_else(|| {
+                rpc_error(
+                    BitcoinRpcError::RpcInvalidAddressOrKey,
+                    "Invalid or non-wallet transaction id",
+                )
+            })?;
+            if !original.is_explicitly_rbf() {
+                return Err(rpc_error(
+                    BitcoinRpcError::RpcWalletError,
+                    "Transaction is not BIP 125 replaceable",
+                ));
+            }
+            let has_descendants = state.mempool.iter().any(|tx| {
+                tx.input
+                    .iter()
+                    .any(|input| &input.previous_output.txid == txid)
+            });
+            if has_descendants {
+                return Err(rpc_error(
+                    BitcoinRpcError::RpcWalletError,
+                    "Transaction has descendants in the wallet",
+                ));
+            }
+            let change_index = original
+                .output
+                .iter()
+                .position(|output| state.change.contains(&output.script_pubkey))
+                .ok_or_else(|| {
+                    rpc_error(
+                        BitcoinRpcError::RpcWalletError,
+                        "Transaction does not have a change output",
+                    )
+                })?;
+
+            let old_fee = state.fee(&original)?;
+            let vsize = estimate_vsize(original.input.len(), original.output.len());
+            let min_fee_rate = old_fee / vsize + INCREMENTAL_RELAY_FEE;
+            let fee_rate =
+                fee_rate.unwrap_or_else(|| estimate.unwrap_or(DEFAULT_FEE_RATE).max(min_fee_rate));
+            if fee_rate < min_fee_rate {
+                return Err(rpc_error(
+                    BitcoinRpcError::RpcInvalidParameter,
+                    "Insufficient total fee",
+                ));
+            }
+
+            // only the change pays for the higher fee, all other outputs are kept as they are
+            let payouts = original
+                .output
+                .iter()
+                .enumerate()
+                .filter(|(index, output)| {
+                    *index != change_index && !output.script_pubkey.is_op_return()
+                })
+                .filter_map(|(_, output)| {
+                    let address = Address::from_script(&output.script_pubkey, self.network)?;
+                    Some(Payout::new(address, output.value))
+                })
+                .collect();
+            let mut replacement = original.clone();
+            let change = &mut replacement.output[change_index];
+            change.value = change
+                .value
+                .checked_sub(fee_rate * vsize - old_fee)
+                .filter(|value| *value >= DUST_LIMIT)
+                .ok_or_else(|| {
+                    rpc_error(
+                        BitcoinRpcError::RpcWalletInsufficientFunds,
+                        "Change output is too small to bump the fee",
+                    )
+                })?;
+            state.sign(&mut replacement)?;
+            (original, replacement, payouts)
+        };
+
+        let request_id = journal::request_id(&self.journal, &original).await?;
+        let replacement_txid = self
+            .send_transaction(LockedTransaction::new(
+                replacement,
+                payouts,
+                request_id,
+                Some(lock),
+            ))
+            .await?;
+        journal::update_status(&self.journal, txid, TransactionStatus::Conflicted).await;
+        Ok(replacement_txid)
+    }
+
+    async fn bump_fee_with_child(
+        &self,
+        txid: &Txid,
+        fee_rate: u64,
+    ) -> Result<ChildPaysForParent, Error> {
+        let lock = self.transaction_creation_lock.acquire().await?;
+        let (child, payout) = {
+            let mut state = self.state();
+            let parent = state.find_in_mempool(txid).cloned().ok_or_else(|| {
+                rpc_error(
+                    BitcoinRpcError::RpcInvalidAddressOrKey,
+                    "Transaction not in mempool",
+                )
+            })?;
+            let utxo = state
+                .utxos()
+                .into_iter()
+                .filter(|utxo| &utxo.outpoint.txid == txid)
+                .max_by_key(|utxo| utxo.txout.value)
+                .ok_or(Error::NoSpendableOutput)?;
+
+            // unconfirmed ancestors of the parent are not simulated
+            let child_fee = fee::child_fee(
+                fee_rate,
+                state.fee(&parent)?,
+                estimate_vsize(parent.input.len(), parent.output.len()),
+                estimate_vsize(1, 1),
+            );
+            let value = utxo
+                .txout
+                .value
+                .checked_sub(child_fee)
+                .filter(|value| *value >= DUST_LIMIT)
+                .ok_or(Error::CpfpOutputTooSmall)?;
+
+            let private_key = state.new_key(self.network);
+            let public_key = PublicKey::from_private_key(&Secp256k1::new(), &private_key);
+            state.change.insert(p2wpkh_script(&public_key));
+            let mut child = Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![TxIn {
+                    previous_output: utxo.outpoint,
+                    script_sig: Script::new(),
+                    sequence: u32::MAX,
+                    witness: vec![],
+                }],
+                output: vec![TxOut {
+                    value,
+                    script_pubkey: p2wpkh_script(&public_key),
+                }],
+            };
+            state.sign(&mut child)?;
+            let address =
+                Address::p2wpkh(&public_key, self.network).map_err(crate::ConversionError::from)?;
+            (child, Payout::new(address, value))
+        };
+
+        let child_txid = self
+            .send_transaction(LockedTransaction::new(
+                child,
+                vec![payout],
+                None,
+                Some(lock),
+            ))
+            .await?;
+        Ok(ChildPaysForParent {
+            parent_txid: *txid,
+            child_txid,
+        })
+    }
+
+    async fn create_or_load_wallet(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn wallet_has_public_key<P>(&self, public_key: P) -> Result<bool, Error>
+    where
+        P: Into<[u8; PUBLIC_KEY_SIZE]>
+            + From<[u8; PUBLIC_KEY_SIZE]>
+            + Clone
+            + PartialEq
+            + Send
+            + Sync
+            + 'static,
+    {
+        let public_key = PublicKey::from_slice(&public_key.into())?;
+        Ok(self.state().find_key(&public_key).is_some())
+    }
+
+    async fn import_private_key(&self, privkey: PrivateKey) -> Result<(), Error> {
+        let public_key = PublicKey::from_private_key(&Secp256k1::new(), &privkey);
+        self.state()
+            .keys
+            .insert(p2wpkh_script(&public_key), privkey);
+        Ok(())
+    }
+
+    async fn rescan_blockchain(&self, _start_height: usize) -> Result<(), Error> {
+        // wallet outputs are always derived from the full chain
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CoinSelection, FileJournal, PolicyError, SoftwareSigner};
+    use bitcoincore_rpc::bitcoin::util::merkleblock::MerkleBlock;
+
+    fn new_mock() -> MockBitcoinCore {
+        MockBitcoinCore::new(Network::Regtest)
+    }
+
+    #[tokio::test]
+    async fn test_mine_blocks() {
+        let btc_rpc = new_mock();
+        assert_eq!(btc_rpc.get_block_count().await.unwrap(), 0);
+
+        let hashes = btc_rpc.mine_blocks(3);
+        assert_eq!(btc_rpc.get_block_count().await.unwrap(), 3);
+        assert_eq!(btc_rpc.get_best_block_hash().await.unwrap(), hashes[2]);
+
+        let header = btc_rpc.get_block_header(&hashes[2]).await.unwrap();
+        assert_eq!(header.prev_blockhash, hashes[1]);
+        assert_eq!(btc_rpc.get_block_hash(1).await.unwrap(), hashes[0]);
+        assert_eq!(
+            btc_rpc.get_block_hashes(&[3, 1]).await.unwrap(),
+            vec![hashes[2], hashes[0]]
+        );
+        assert!(btc_rpc.get_block_hashes(&[1, 4]).await.is_err());
+        assert!(btc_rpc
+            .get_block_hash(4)
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("height"));
+
+        let info = btc_rpc.get_block_info(&hashes[0]).await.unwrap();
+        assert_eq!(info.height, 1);
+        assert_eq!(info.confirmations, 3);
+        assert_eq!(info.nextblockhash, Some(hashes[1]));
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_block() {
+        let btc_rpc = new_mock();
+        let hashes = btc_rpc.mine_blocks(3);
+        let txid = btc_rpc.fund_wallet(100_000);
+
+        btc_rpc.invalidate_block(&hashes[1]);
+        assert_eq!(btc_rpc.get_block_count().await.unwrap(), 1);
+        // the funding transaction is back in the mempool
+        assert_eq!(btc_rpc.list_unspent()[0].outpoint.txid, txid);
+        assert_eq!(btc_rpc.list_unspent()[0].confirmations, 0);
+        // stale headers can still be queried
+        assert!(btc_rpc.get_block_header(&hashes[2]).await.is_ok());
+
+        let replacements = btc_rpc.mine_blocks(2);
+        assert_ne!(replacements[0], hashes[1]);
+        assert_eq!(
+            btc_rpc.get_best_block_hash().await.unwrap(),
+            replacements[1]
+        );
+        assert_eq!(btc_rpc.list_unspent()[0].confirmations, 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_to_address() {
+        let btc_rpc = new_mock();
+        btc_rpc.fund_wallet(100_000);
+
+        let recipient = Address::p2wpkh(
+            &PublicKey::from_private_key(
+                &Secp256k1::new(),
+                &PrivateKey::from_wif("cNfmpdkMyUwQGEZgqiqu1RPhhrjwGsp5VSJhEnFEfU533KwTnuYj")
+                    .unwrap(),
+            ),
+            Network::Regtest,
+        )
+        .unwrap();
+        let request_id = H256::repeat_byte(0x42);
+
+        let txid = btc_rpc
+            .create_and_send_transaction(
+                recipient.clone(),
+                40_000,
+                Some(request_id),
+                FeePolicy::default(),
+            )
+            .await
+            .unwrap();
+
+        let mempool = btc_rpc
+            .get_mempool_transactions()
+            .await
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool[0].txid(), txid);
+        assert_eq!(mempool[0].get_op_return(), Some(request_id));
+        assert_eq!(
+            btc_rpc
+                .get_transactions(&[Txid::default(), txid])
+                .await
+                .unwrap(),
+            vec![None, Some(mempool[0].clone())]
+        );
+        assert_eq!(
+            mempool[0].get_payment_amount_to(recipient.payload.clone()),
+            Some(40_000)
+        );
+        // change is spendable while unconfirmed
+        assert!(btc_rpc.get_balance().as_sat() > 50_000);
+        let mut overspending = mempool[0].clone();
+        overspending.output[0].value += 100_000;
+        assert!(btc_rpc.state().fee(&overspending).is_err());
+
+        let miner = btc_rpc.clone();
+        let mining = tokio::spawn(async move {
+            for _ in 0..2 {
+                sleep(POLL_INTERVAL * 2).await;
+                miner.mine_block();
+            }
+        });
+        let metadata = btc_rpc
+            .wait_for_transaction_metadata(txid, 2)
+            .await
+            .unwrap();
+        mining.await.unwrap();
+
+        assert_eq!(metadata.block_height, 2);
+        let tx: Transaction = crate::deserialize(&metadata.raw_tx).unwrap();
+        assert_eq!(tx.txid(), txid);
+        // mined transactions are only found with -txindex
+        assert_eq!(btc_rpc.get_transactions(&[txid]).await.unwrap(), vec![None]);
+        btc_rpc.set_txindex(true);
+        assert_eq!(
+            btc_rpc.get_transactions(&[txid]).await.unwrap(),
+            vec![Some(tx.clone())]
+        );
+
+        let merkle_block: MerkleBlock = crate::deserialize(&metadata.proof).unwrap();
+        let mut matches = vec![];
+        let mut indexes = vec![];
+        assert_eq!(
+            merkle_block
+                .txn
+                .extract_matches(&mut matches, &mut indexes)
+                .unwrap(),
+            merkle_block.header.merkle_root
+        );
+        assert_eq!(matches, vec![txid]);
+        assert!(btc_rpc
+            .list_unspent()
+            .iter()
+            .all(|utxo| utxo.confirmations > 0));
+    }
+
+    #[tokio::test]
+    async fn test_create_transaction_with_fee_policy() {
+        let btc_rpc = new_mock();
+        btc_rpc.fund_wallet(100_000);
+        let address = btc_rpc.get_new_address().await.unwrap();
+
+        let fee_policy = FeePolicy {
+            fee_rate: Some(25),
+            replaceable: Some(true),
+            change_position: Some(0),
+            ..Default::default()
+        };
+        let tx = btc_rpc
+            .create_transaction(address.clone(), 10_000, None, fee_policy)
+            .await
+            .unwrap()
+            .transaction;
+
+        assert!(tx.is_explicitly_rbf());
+        assert_eq!(tx.output[1].script_pubkey, address.script_pubkey());
+        let fee = 100_000 - tx.output.iter().map(|output| output.value).sum::<u64>();
+        assert_eq!(fee, 25 * estimate_vsize(1, 2));
+
+        let fee_policy = FeePolicy {
+            change_position: Some(2),
+            ..Default::default()
+        };
+        assert!(btc_rpc
+            .create_transaction(address, 10_000, None, fee_policy)
+            .await
+            .err()
+            .unwrap()
+            .is_invalid_parameter());
+    }
+
+    #[tokio::test]
+    async fn test_bump_fee() {
+        let btc_rpc = new_mock();
+        btc_rpc.fund_wallet(100_000);
+        let address = btc_rpc.get_new_address().await.unwrap();
+        let request_id = H256::repeat_byte(0x42);
+
+        let fee_policy = FeePolicy {
+            fee_rate: Some(5),
+            replaceable: Some(true),
+            ..Default::default()
+        };
+        let txid = btc_rpc
+            .create_and_send_transaction(address.clone(), 10_000, Some(request_id), fee_policy)
+            .await
+            .unwrap();
+        let original = btc_rpc.get_transactions(&[txid]).await.unwrap()[0]
+            .clone()
+            .unwrap();
+
+        // the fee rate must increase
+        assert!(btc_rpc
+            .bump_fee(&txid, Some(5))
+            .await
+            .err()
+            .unwrap()
+            .is_invalid_parameter());
+
+        let replacement_txid = btc_rpc.bump_fee(&txid, Some(20)).await.unwrap();
+        let transactions = btc_rpc
+            .get_transactions(&[txid, replacement_txid])
+            .await
+            .unwrap();
+        assert!(transactions[0].is_none());
+        let replacement = transactions[1].clone().unwrap();
+
+        let spent = |tx: &Transaction| {
+            tx.input
+                .iter()
+                .map(|input| input.previous_output)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(spent(&replacement), spent(&original));
+        assert_eq!(replacement.get_op_return(), Some(request_id));
+        assert_eq!(
+            replacement.get_payment_amount_to(address.payload),
+            Some(10_000)
+        );
+        let fee = 100_000
+            - replacement
+                .output
+                .iter()
+                .map(|output| output.value)
+                .sum::<u64>();
+        assert_eq!(fee, 20 * estimate_vsize(1, 3));
+
+        btc_rpc.mine_block();
+        assert!(btc_rpc
+            .bump_fee(&replacement_txid, Some(30))
+            .await
+            .err()
+            .unwrap()
+            .is_wallet_error());
+    }
+
+    #[tokio::test]
+    async fn test_bump_fee_requires_rbf() {
+        let btc_rpc = new_mock();
+        btc_rpc.fund_wallet(100_000);
+        let address = btc_rpc.get_new_address().await.unwrap();
+        let txid = btc_rpc
+            .create_and_send_transaction(address, 10_000, None, FeePolicy::default())
+            .await
+            .unwrap();
+
+        assert!(btc_rpc
+            .bump_fee(&txid, None)
+            .await
+            .err()
+            .unwrap()
+            .is_wallet_error());
+    }
+
+    #[tokio::test]
+    async fn test_bump_fee_with_child() {
+        let btc_rpc = new_mock();
+        btc_rpc.fund_wallet(100_000);
+        let address = btc_rpc.get_new_address().await.unwrap();
+        let txid = btc_rpc
+            .create_and_send_transaction(address, 10_000, None, FeePolicy::with_fee_rate(2))
+            .await
+            .unwrap();
+
+        let package = btc_rpc.bump_fee_with_child(&txid, 20).await.unwrap();
+        assert_eq!(package.parent_txid, txid);
+        let transactions = btc_rpc
+            .get_transactions(&[package.parent_txid, package.child_txid])
+            .await
+            .unwrap();
+        let (parent, child) = (
+            transactions[0].clone().unwrap(),
+            transactions[1].clone().unwrap(),
+        );
+        assert_eq!(child.input[0].previous_output.txid, txid);
+
+        let package_fee = 100_000 - child.output[0].value - 10_000;
+        let package_vsize = estimate_vsize(parent.input.len(), parent.output.len())
+            + estimate_vsize(child.input.len(), child.output.len());
+        assert_eq!(package_fee, 20 * package_vsize);
+
+        // the child is mined together with the parent
+        btc_rpc.mine_block();
+        assert!(btc_rpc
+            .list_unspent()
+            .iter()
+            .all(|utxo| utxo.confirmations == 1));
+    }
+
+    #[tokio::test]
+    async fn test_bump_fee_with_child_requires_wallet_output() {
+        let btc_rpc = new_mock();
+        let payment = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::new_op_return(&[]),
+            }],
+        };
+        let txid = btc_rpc.add_to_mempool(payment);
+
+        assert!(matches!(
+            btc_rpc.bump_fee_with_child(&txid, 20).await,
+            Err(Error::NoSpendableOutput)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_batch_transaction() {
+        let btc_rpc = new_mock();
+        btc_rpc.fund_wallet(100_000);
+        let mut payouts = Vec::new();
+        for sat in &[10_000, 20_000, 30_000] {
+            payouts.push(Payout::new(btc_rpc.get_new_address().await.unwrap(), *sat));
+        }
+        let request_id = H256::repeat_byte(0x42);
+
+        let tx = btc_rpc
+            .create_batch_transaction(payouts.clone(), Some(request_id), FeePolicy::default())
+            .await
+            .unwrap()
+            .transaction;
+
+        assert_eq!(tx.input.len(), 1);
+        assert_eq!(
+            tx.output[0].script_pubkey,
+            payouts[0].address.script_pubkey()
+        );
+        assert_eq!(tx.get_op_return(), Some(request_id));
+        for payout in payouts.iter() {
+            assert!(tx.output.contains(&TxOut {
+                value: payout.sat,
+                script_pubkey: payout.address.script_pubkey(),
+            }));
+        }
+        // three payments, the op_return and the change
+        let fee = 100_000 - tx.output.iter().map(|output| output.value).sum::<u64>();
+        assert_eq!(fee, DEFAULT_FEE_RATE * estimate_vsize(1, 5));
+
+        assert!(matches!(
+            btc_rpc
+                .create_batch_transaction(vec![], None, FeePolicy::default())
+                .await,
+            Err(Error::EmptyBatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_psbt_signed_externally() {
+        let btc_rpc = new_mock();
+        let private_key = PrivateKey {
+            compressed: true,
+            network: Network::Regtest,
+            key: SecretKey::from_slice(&[0x42; 32]).unwrap(),
+        };
+        // stand-in for an offline signer
+        let signer = SoftwareSigner::new(private_key);
+        let public_key = signer.public_key();
+        btc_rpc.import_private_key(private_key).await.unwrap();
+        btc_rpc.add_to_mempool(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: p2wpkh_script(&public_key),
+            }],
+        });
+        btc_rpc.mine_block();
+
+        let address = btc_rpc.get_new_address().await.unwrap();
+        let request_id = H256::repeat_byte(0x42);
+        let create_psbt = || {
+            btc_rpc.create_psbt(
+                vec![Payout::new(address.clone(), 20_000)],
+                Some(request_id),
+                FeePolicy::default(),
+            )
+        };
+
+        // unsigned inputs cannot be finalized, and are released
+        let locked = create_psbt().await.unwrap();
+        assert!(btc_rpc.list_utxos(0).await.unwrap().is_empty());
+        assert!(matches!(
+            btc_rpc.finalize_psbt(locked).await,
+            Err(Error::IncompletePsbt)
+        ));
+        assert_eq!(btc_rpc.list_utxos(0).await.unwrap().len(), 1);
+
+        let mut locked = create_psbt().await.unwrap();
+        // round trip through the signer
+        let mut signed = crate::psbt::decode(&crate::psbt::encode(&locked.psbt)).unwrap();
+        signer.sign_psbt(&mut signed).await.unwrap();
+        locked.combine(signed).unwrap();
+
+        let transaction = btc_rpc.finalize_psbt(locked).await.unwrap();
+        let txid = btc_rpc.send_transaction(transaction).await.unwrap();
+        btc_rpc.mine_block();
+        let tx = btc_rpc
+            .wait_for_transaction_metadata(txid, 1)
+            .await
+            .unwrap();
+        let tx: Transaction = crate::deserialize(&tx.raw_tx).unwrap();
+        assert_eq!(tx.get_op_return(), Some(request_id));
+        assert_eq!(tx.get_payment_amount_to(address.payload), Some(20_000));
+    }
+
+    #[tokio::test]
+    async fn test_watch_only_wallet_with_signer() {
+        let signer = SoftwareSigner::new(PrivateKey {
+            compressed: true,
+            network: Network::Regtest,
+            key: SecretKey::from_slice(&[0x42; 32]).unwrap(),
+        });
+        let signer_script = p2wpkh_script(&signer.public_key());
+        let btc_rpc = new_mock().with_signer(signer);
+        btc_rpc.fund_wallet(100_000);
+        // the wallet holds no keys
+        assert!(btc_rpc.state().keys.is_empty());
+
+        let address = btc_rpc.get_new_address().await.unwrap();
+        assert_eq!(address.script_pubkey(), signer_script);
+        let recipient = new_mock().get_new_address().await.unwrap();
+        let tx = btc_rpc
+            .create_transaction(recipient.clone(), 20_000, None, FeePolicy::default())
+            .await
+            .unwrap();
+        let txid = btc_rpc.send_transaction(tx).await.unwrap();
+        btc_rpc.mine_block();
+
+        let metadata = btc_rpc
+            .wait_for_transaction_metadata(txid, 1)
+            .await
+            .unwrap();
+        let tx: Transaction = crate::deserialize(&metadata.raw_tx).unwrap();
+        assert_eq!(tx.get_payment_amount_to(recipient.payload), Some(20_000));
+        // the change is watched as well
+        assert_eq!(tx.output[1].script_pubkey, signer_script);
+        assert_eq!(btc_rpc.list_utxos(0).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stripped_raw_tx_hashes_to_txid() {
+        let btc_rpc = new_mock();
+        btc_rpc.fund_wallet(100_000);
+        let recipient = new_mock().get_new_address().await.unwrap();
+        let tx = btc_rpc
+            .create_transaction(recipient, 20_000, None, FeePolicy::default())
+            .await
+            .unwrap();
+        let txid = btc_rpc.send_transaction(tx).await.unwrap();
+        btc_rpc.mine_block();
+
+        let metadata = btc_rpc
+            .wait_for_transaction_metadata(txid, 1)
+            .await
+            .unwrap();
+        // a p2wpkh spend, whose witness is not committed to by the txid
+        let tx: Transaction = crate::deserialize(&metadata.raw_tx).unwrap();
+        assert!(tx.input.iter().all(|input| input.witness.len() == 2));
+        assert_ne!(Txid::hash(&metadata.raw_tx), txid);
+        assert_eq!(Txid::hash(&metadata.stripped_raw_tx), txid);
+
+        let stripped: Transaction = crate::deserialize(&metadata.stripped_raw_tx).unwrap();
+        assert!(stripped.input.iter().all(|input| input.witness.is_empty()));
+        assert_eq!(stripped.txid(), txid);
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_payments() {
+        let btc_rpc = new_mock().with_idempotent_payments(true);
+        btc_rpc.fund_wallet(100_000);
+        let recipient = new_mock().get_new_address().await.unwrap();
+        let request_id = H256::from_low_u64_be(1);
+        let pay = |request_id| {
+            btc_rpc.create_and_send_transaction(
+                recipient.clone(),
+                20_000,
+                Some(request_id),
+                FeePolicy::default(),
+            )
+        };
+
+        // anyone can broadcast a transaction carrying the request id
+        let spoofed = btc_rpc.add_to_mempool(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Default::default(), 0),
+                script_sig: Script::new(),
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: Script::new_op_return(request_id.as_bytes()),
+            }],
+        });
+        assert_eq!(
+            btc_rpc.find_request_payment(request_id).await.unwrap(),
+            None
+        );
+
+        // concurrent payments of the same request wait for each other
+        let (txid, concurrent) = tokio::join!(pay(request_id), pay(request_id));
+        let txid = txid.unwrap();
+        assert_ne!(txid, spoofed);
+        assert_eq!(concurrent.unwrap(), txid);
+        assert_eq!(btc_rpc.state().mempool.len(), 2);
+        // e.g. after a restart, while still in the mempool and once mined
+        assert_eq!(pay(request_id).await.unwrap(), txid);
+        btc_rpc.mine_block();
+        assert_eq!(pay(request_id).await.unwrap(), txid);
+        assert_eq!(
+            btc_rpc.find_request_payment(request_id).await.unwrap(),
+            Some(txid)
+        );
+
+        // other requests are paid
+        let other = pay(H256::from_low_u64_be(2)).await.unwrap();
+        assert_ne!(other, txid);
+        let btc_rpc = btc_rpc.with_idempotent_payments(false);
+        assert_ne!(
+            btc_rpc
+                .create_and_send_transaction(
+                    recipient.clone(),
+                    20_000,
+                    Some(request_id),
+                    FeePolicy::default(),
+                )
+                .await
+                .unwrap(),
+            txid
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dropped_transaction_unlocks_inputs() {
+        let btc_rpc = new_mock();
+        btc_rpc.fund_wallet(100_000);
+        let recipient = new_mock().get_new_address().await.unwrap();
+        let create =
+            || btc_rpc.create_transaction(recipient.clone(), 60_000, None, FeePolicy::default());
+
+        // abandoned, e.g. because the caller failed before sending it
+        drop(create().await.unwrap());
+        let psbt = btc_rpc
+            .create_psbt(
+                vec![Payout::new(recipient.clone(), 60_000)],
+                None,
+                FeePolicy::default(),
+            )
+            .await
+            .unwrap();
+        drop(psbt);
+
+        let transaction = create().await.unwrap();
+        let inputs: Vec<_> = transaction
+            .transaction
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+            .collect();
+        btc_rpc.send_transaction(transaction).await.unwrap();
+        // spent by the sent transaction, so they stay locked
+        let state = btc_rpc.state();
+        assert!(inputs.iter().all(|input| state.locked.contains(input)));
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_policy() {
+        let btc_rpc = new_mock().with_broadcast_policy(BroadcastPolicy::new(Some(5_000), None));
+        btc_rpc.fund_wallet(100_000);
+        let recipient = new_mock().get_new_address().await.unwrap();
+        let request_id = H256::from_low_u64_be(1);
+        let create = || {
+            btc_rpc.create_transaction(
+                recipient.clone(),
+                20_000,
+                Some(request_id),
+                FeePolicy::default(),
+            )
+        };
+
+        // the request id does not match the one the transaction was created for
+        let mut transaction = create().await.unwrap();
+        transaction.request_id = Some(H256::from_low_u64_be(2));
+        assert!(matches!(
+            btc_rpc.send_transaction(transaction).await,
+            Err(Error::PolicyError(PolicyError::RequestIdMismatch { .. }))
+        ));
+        assert!(btc_rpc.state().mempool.is_empty());
+
+        // the inputs were unlocked, so the funds can be spent again
+        let strict = btc_rpc
+            .clone()
+            .with_broadcast_policy(BroadcastPolicy::new(Some(100), None));
+        assert!(matches!(
+            strict.send_transaction(create().await.unwrap()).await,
+            Err(Error::PolicyError(PolicyError::FeeTooHigh {
+                max_fee: 100,
+                ..
+            }))
+        ));
+
+        let lenient = btc_rpc
+            .clone()
+            .with_broadcast_policy(BroadcastPolicy::new(Some(5_000), Some(10.0)));
+        let transaction = create().await.unwrap();
+        let txid = transaction.transaction.txid();
+        assert_eq!(lenient.send_transaction(transaction).await.unwrap(), txid);
+        assert_eq!(btc_rpc.state().mempool.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_journal_rebroadcast() {
+        let path = std::env::temp_dir().join(format!("mock-journal-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let btc_rpc = new_mock().with_journal(FileJournal::new(&path));
+        btc_rpc.fund_wallet(100_000);
+        let recipient = new_mock().get_new_address().await.unwrap();
+        let request_id = H256::from_low_u64_be(1);
+
+        // crashed after recording the transaction, before broadcasting it
+        let tx = btc_rpc
+            .create_transaction(recipient, 20_000, Some(request_id), FeePolicy::default())
+            .await
+            .unwrap();
+        let txid = tx.transaction.txid();
+        FileJournal::new(&path)
+            .record(JournalEntry::pending(&tx))
+            .await
+            .unwrap();
+        drop(tx);
+
+        assert_eq!(btc_rpc.rebroadcast_pending().await.unwrap(), vec![txid]);
+        assert!(btc_rpc.state().find_in_mempool(&txid).is_some());
+        let entries = FileJournal::new(&path).entries().await.unwrap();
+        assert_eq!(entries[0].request_id, Some(request_id));
+        assert_eq!(entries[0].status, TransactionStatus::Pending);
+
+        btc_rpc.mine_block();
+        btc_rpc
+            .wait_for_transaction_metadata(txid, 1)
+            .await
+            .unwrap();
+        let journal = FileJournal::new(&path);
+        assert_eq!(
+            journal.entries().await.unwrap()[0].status,
+            TransactionStatus::Confirmed
+        );
+        assert!(journal.pending().await.unwrap().is_empty());
+        assert!(btc_rpc.rebroadcast_pending().await.unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bump_fee_journal() {
+        let path = std::env::temp_dir().join(format!("mock-bump-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let btc_rpc = new_mock()
+            .with_journal(FileJournal::new(&path))
+            .with_broadcast_policy(BroadcastPolicy::new(Some(5_000), None));
+        btc_rpc.fund_wallet(100_000);
+        let recipient = new_mock().get_new_address().await.unwrap();
+        let request_id = H256::from_low_u64_be(1);
+        let fee_policy = FeePolicy {
+            fee_rate: Some(5),
+            replaceable: Some(true),
+            ..Default::default()
+        };
+        let txid = btc_rpc
+            .create_and_send_transaction(recipient, 20_000, Some(request_id), fee_policy)
+            .await
+            .unwrap();
+
+        // the replacement is checked like a new transaction
+        assert!(matches!(
+            btc_rpc.bump_fee(&txid, Some(40)).await,
+            Err(Error::PolicyError(PolicyError::FeeTooHigh { .. }))
+        ));
+        assert!(btc_rpc.state().find_in_mempool(&txid).is_some());
+
+        let replacement_txid = btc_rpc.bump_fee(&txid, Some(20)).await.unwrap();
+        let journal = FileJournal::new(&path);
+        let entries = journal.entries().await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].status, TransactionStatus::Conflicted);
+        assert_eq!(entries[1].txid(), replacement_txid);
+        assert_eq!(entries[1].request_id, Some(request_id));
+
+        // e.g. on startup, the replacement is still tracked instead of the original
+        assert_eq!(
+            btc_rpc.rebroadcast_pending().await.unwrap(),
+            vec![replacement_txid]
+        );
+        assert!(btc_rpc.state().find_in_mempool(&txid).is_none());
+
+        let package = btc_rpc
+            .bump_fee_with_child(&replacement_txid, 25)
+            .await
+            .unwrap();
+        let pending: Vec<_> = journal
+            .pending()
+            .await
+            .unwrap()
+            .iter()
+            .map(JournalEntry::txid)
+            .collect();
+        assert_eq!(pending, vec![replacement_txid, package.child_txid]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_mined_transaction_stays_pending() {
+        let path = std::env::temp_dir().join(format!("mock-mined-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let btc_rpc = new_mock().with_journal(FileJournal::new(&path));
+        btc_rpc.fund_wallet(100_000);
+        let recipient = new_mock().get_new_address().await.unwrap();
+
+        // e.g. broadcast before a timeout, and mined since
+        let tx = btc_rpc
+            .create_transaction(recipient, 20_000, None, FeePolicy::default())
+            .await
+            .unwrap();
+        let txid = btc_rpc.add_to_mempool(tx.transaction.clone());
+        btc_rpc.mine_block();
+        let err = btc_rpc.send_transaction(tx).await.unwrap_err();
+        assert!(!err.is_transaction_rejected());
+        assert!(rpc_error(BitcoinRpcError::RpcVerifyRejected, "").is_transaction_rejected());
+
+        let journal = FileJournal::new(&path);
+        assert_eq!(journal.pending().await.unwrap()[0].txid(), txid);
+        assert!(btc_rpc.rebroadcast_pending().await.unwrap().is_empty());
+        assert_eq!(
+            journal.entries().await.unwrap()[0].status,
+            TransactionStatus::Confirmed
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_combine_rejects_other_psbt() {
+        let btc_rpc = new_mock();
+        btc_rpc.fund_wallet(100_000);
+        btc_rpc.fund_wallet(100_000);
+        let address = btc_rpc.get_new_address().await.unwrap();
+        let payouts = vec![Payout::new(address, 20_000)];
+
+        let first = btc_rpc
+            .create_psbt(payouts.clone(), None, FeePolicy::default())
+            .await
+            .unwrap();
+        let psbt = first.psbt.clone();
+        drop(first);
+        let mut second = btc_rpc
+            .create_psbt(payouts, None, FeePolicy::default())
+            .await
+            .unwrap();
+        assert!(matches!(second.combine(psbt), Err(Error::PsbtError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_create_transaction_with_coin_selection() {
+        let btc_rpc = new_mock();
+        let large = btc_rpc.fund_wallet(50_000);
+        // pays 20_000 and the fee of a transaction without change
+        let exact = btc_rpc.fund_wallet(20_000 + DEFAULT_FEE_RATE * estimate_vsize(1, 1));
+
+        let vault_key: [u8; PUBLIC_KEY_SIZE] = btc_rpc.get_new_public_key().await.unwrap();
+        let vault_key = PublicKey::from_slice(&vault_key).unwrap().key;
+        let issue_id = H256::repeat_byte(0x13);
+        let scalar = crate::calculate_deposit_scalar(&vault_key, issue_id).unwrap();
+        btc_rpc
+            .add_new_deposit_key(vault_key.serialize(), scalar[..].to_vec())
+            .await
+            .unwrap();
+        let deposit_address =
+            crate::derive_deposit_address(&vault_key, issue_id, Network::Regtest).unwrap();
+        btc_rpc.add_to_mempool(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: deposit_address.script_pubkey(),
+            }],
+        });
+        btc_rpc.mine_block();
+
+        let address = btc_rpc.get_new_address().await.unwrap();
+        let fee_policy = FeePolicy {
+            coin_selection: Some(CoinSelection::default()),
+            ..Default::default()
+        };
+        let create_transaction =
+            || btc_rpc.create_transaction(address.clone(), 20_000, None, fee_policy.clone());
+
+        // branch and bound finds the output that pays exactly, without change
+        let locked = create_transaction().await.unwrap();
+        let tx = locked.transaction.clone();
+        assert_eq!(tx.input[0].previous_output.txid, exact);
+        assert_eq!(tx.output.len(), 1);
+
+        // the input stays locked until the transaction is sent or dropped
+        let utxos = btc_rpc.list_utxos(0).await.unwrap();
+        assert_eq!(utxos.len(), 2);
+        assert!(utxos.iter().any(|utxo| utxo.is_deposit));
+        drop(locked);
+        assert_eq!(btc_rpc.list_utxos(0).await.unwrap().len(), 3);
+
+        // as when another process reserved the output concurrently
+        btc_rpc
+            .lock_utxos(&[tx.input[0].previous_output])
+            .await
+            .unwrap();
+        let tx = create_transaction().await.unwrap().transaction;
+        assert_eq!(tx.input[0].previous_output.txid, large);
+        btc_rpc
+            .lock_utxos(&[tx.input[0].previous_output])
+            .await
+            .unwrap();
+
+        // only the deposit is left
+        assert!(matches!(
+            create_transaction().await,
+            Err(Error::InsufficientFunds)
+        ));
+
+        // outputs cannot be reserved twice
+        assert!(btc_rpc
+            .lock_utxos(&[tx.input[0].previous_output])
+            .await
+            .unwrap_err()
+            .is_invalid_parameter());
+
+        btc_rpc
+            .unlock_utxos(&[tx.input[0].previous_output])
+            .await
+            .unwrap();
+        assert_eq!(btc_rpc.list_utxos(0).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_create_transaction_fails_without_funds() {
+        let btc_rpc = new_mock();
+        let address = btc_rpc.get_new_address().await.unwrap();
+        let err = btc_rpc
+            .create_transaction(address, 1_000, None, FeePolicy::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            Error::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Rpc(RpcError { code, .. })))
+                if code == BitcoinRpcError::RpcWalletInsufficientFunds as i32
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_add_new_deposit_key() {
+        let btc_rpc = new_mock();
+        let vault_key: [u8; PUBLIC_KEY_SIZE] = btc_rpc.get_new_public_key().await.unwrap();
+        let secret_key = H256::repeat_byte(0x07);
+
+        btc_rpc
+            .add_new_deposit_key(vault_key, secret_key.as_bytes().to_vec())
+            .await
+            .unwrap();
+
+        let secp = Secp256k1::new();
+        let mut deposit_key = PublicKey::from_slice(&vault_key).unwrap();
+        deposit_key
+            .key
+            .mul_assign(&secp, secret_key.as_bytes())
+            .unwrap();
+        assert!(btc_rpc
+            .wallet_has_public_key(deposit_key.key.serialize())
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_add_new_deposit_key_for_issue() {
+        let btc_rpc = new_mock();
+        let vault_key: [u8; PUBLIC_KEY_SIZE] = btc_rpc.get_new_public_key().await.unwrap();
+        let vault_key = PublicKey::from_slice(&vault_key).unwrap().key;
+        let issue_id = H256::repeat_byte(0x13);
+
+        let scalar = crate::calculate_deposit_scalar(&vault_key, issue_id).unwrap();
+        btc_rpc
+            .add_new_deposit_key(vault_key.serialize(), scalar[..].to_vec())
+            .await
+            .unwrap();
+
+        // the user pays to the address computed by the contract
+        let deposit_address =
+            crate::derive_deposit_address(&vault_key, issue_id, Network::Regtest).unwrap();
+        btc_rpc.add_to_mempool(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 12_345,
+                script_pubkey: deposit_address.script_pubkey(),
+            }],
+        });
+        assert_eq!(btc_rpc.get_balance(), Amount::from_sat(12_345));
+    }
+}
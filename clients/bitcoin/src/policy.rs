@@ -0,0 +1,311 @@
+//! Checks run on a signed transaction before it is broadcast. Once a payment is in the
+//! mempool it can at best be replaced, so a transaction that pays the wrong output, carries
+//! the wrong request id or burns an unreasonable fee is rejected beforehand. Whether the
+//! node would accept the transaction at all is tested with `testmempoolaccept`, which also
+//! reports the fee: the inputs of wallet transactions are not part of the transaction, so
+//! the fee cannot be computed locally.
+
+use crate::{Amount, LockedTransaction, Payout, Transaction, H256};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Reasons for which a transaction is not broadcast.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum PolicyError {
+    #[error("Rejected by the mempool: {0}")]
+    MempoolRejected(String),
+    #[error("Fee of {fee} sat exceeds the limit of {max_fee} sat")]
+    FeeTooHigh { fee: u64, max_fee: u64 },
+    #[error("Fee of {fee} sat exceeds {max_percent}% of the {amount} sat paid")]
+    FeeTooHighForAmount {
+        fee: u64,
+        amount: u64,
+        max_percent: f64,
+    },
+    #[error("Fee of the transaction is unknown")]
+    FeeUnknown,
+    #[error("Transaction does not pay {sat} sat to {address}")]
+    PaymentMissing { address: String, sat: u64 },
+    #[error("Transaction carries request id {actual:?} instead of {expected:?}")]
+    RequestIdMismatch {
+        expected: Option<H256>,
+        actual: Option<H256>,
+    },
+}
+
+/// Result of `testmempoolaccept` for a single transaction.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MempoolAcceptance {
+    pub allowed: bool,
+    #[serde(rename = "reject-reason")]
+    pub reject_reason: Option<String>,
+    /// Only reported for allowed transactions.
+    #[serde(default, rename = "fees", deserialize_with = "deserialize_base_fee")]
+    pub fee: Option<u64>,
+}
+
+fn deserialize_base_fee<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    struct Fees {
+        #[serde(with = "bitcoincore_rpc::bitcoin::util::amount::serde::as_btc")]
+        base: Amount,
+    }
+
+    Ok(Option::<Fees>::deserialize(deserializer)?.map(|fees| fees.base.as_sat()))
+}
+
+/// Limits on the fee of a transaction, relative to the amount it pays. Either limit may be
+/// left unset; a transaction paying nothing (e.g. a fee bump) is only held to `max_fee`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BroadcastPolicy {
+    /// Highest fee in satoshis.
+    pub max_fee: Option<u64>,
+    /// Highest fee as a percentage of the sum of the payouts.
+    pub max_fee_percent: Option<f64>,
+}
+
+impl BroadcastPolicy {
+    pub fn new(max_fee: Option<u64>, max_fee_percent: Option<f64>) -> Self {
+        Self {
+            max_fee,
+            max_fee_percent,
+        }
+    }
+
+    /// Check the fee of a transaction paying `amount` satoshis to its recipients.
+    pub fn check_fee(&self, fee: Option<u64>, amount: u64) -> Result<(), PolicyError> {
+        if self.max_fee.is_none() && self.max_fee_percent.is_none() {
+            return Ok(());
+        }
+        let fee = fee.ok_or(PolicyError::FeeUnknown)?;
+        if let Some(max_fee) = self.max_fee {
+            if fee > max_fee {
+                return Err(PolicyError::FeeTooHigh { fee, max_fee });
+            }
+        }
+        if let Some(max_percent) = self.max_fee_percent {
+            if amount > 0 && fee as f64 > amount as f64 * max_percent / 100.0 {
+                return Err(PolicyError::FeeTooHighForAmount {
+                    fee,
+                    amount,
+                    max_percent,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Run all checks on `transaction`, given the result of `testmempoolaccept`.
+    pub fn check(
+        &self,
+        transaction: &LockedTransaction,
+        acceptance: &MempoolAcceptance,
+    ) -> Result<(), PolicyError> {
+        check_outputs(
+            &transaction.transaction,
+            &transaction.payouts,
+            transaction.request_id,
+        )?;
+        if !acceptance.allowed {
+            return Err(PolicyError::MempoolRejected(
+                acceptance.reject_reason.clone().unwrap_or_default(),
+            ));
+        }
+        let amount = transaction.payouts.iter().map(|payout| payout.sat).sum();
+        self.check_fee(acceptance.fee, amount)
+    }
+}
+
+/// Ensure `transaction` pays every payout in full and carries `request_id` in its OP_RETURN.
+/// Like `TxValidate`, only the first OP_RETURN output counts, wherever it is.
+pub fn check_outputs(
+    transaction: &Transaction,
+    payouts: &[Payout],
+    request_id: Option<H256>,
+) -> Result<(), PolicyError> {
+    for payout in payouts {
+        let script_pubkey = payout.address.script_pubkey();
+        if !transaction
+            .output
+            .iter()
+            .any(|output| output.script_pubkey == script_pubkey && output.value == payout.sat)
+        {
+            return Err(PolicyError::PaymentMissing {
+                address: payout.address.to_string(),
+                sat: payout.sat,
+            });
+        }
+    }
+    let actual = transaction
+        .output
+        .iter()
+        .find(|output| output.script_pubkey.is_op_return())
+        .and_then(|output| match output.script_pubkey.as_bytes() {
+            [0x6a, 32, rest @ ..] if rest.len() == 32 => Some(H256::from_slice(rest)),
+            _ => None,
+        });
+    if actual != request_id {
+        return Err(PolicyError::RequestIdMismatch {
+            expected: request_id,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, Network, OutPoint, PublicKey, Script, TxIn, TxOut};
+    use bitcoincore_rpc::bitcoin::{
+        blockdata::{opcodes, script::Builder},
+        secp256k1::{rand::rngs::OsRng, Secp256k1},
+    };
+
+    fn new_address() -> Address {
+        let secp = Secp256k1::new();
+        let (_, public_key) = secp.generate_keypair(&mut OsRng::new().unwrap());
+        let public_key = PublicKey {
+            compressed: true,
+            key: public_key,
+        };
+        Address::p2wpkh(&public_key, Network::Regtest).unwrap()
+    }
+
+    fn transaction(outputs: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Default::default(), 0),
+                script_sig: Script::new(),
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            output: outputs,
+        }
+    }
+
+    fn op_return(request_id: H256) -> TxOut {
+        TxOut {
+            value: 0,
+            script_pubkey: Builder::new()
+                .push_opcode(opcodes::all::OP_RETURN)
+                .push_slice(request_id.as_bytes())
+                .into_script(),
+        }
+    }
+
+    #[test]
+    fn test_check_outputs() {
+        let address = new_address();
+        let request_id = H256::from_low_u64_be(1);
+        let payouts = vec![Payout::new(address.clone(), 1_000)];
+        let paid = TxOut {
+            value: 1_000,
+            script_pubkey: address.script_pubkey(),
+        };
+        let tx = transaction(vec![paid.clone(), op_return(request_id)]);
+        assert_eq!(check_outputs(&tx, &payouts, Some(request_id)), Ok(()));
+
+        assert_eq!(
+            check_outputs(&tx, &payouts, Some(H256::from_low_u64_be(2))),
+            Err(PolicyError::RequestIdMismatch {
+                expected: Some(H256::from_low_u64_be(2)),
+                actual: Some(request_id),
+            })
+        );
+        assert!(matches!(
+            check_outputs(&tx, &payouts, None),
+            Err(PolicyError::RequestIdMismatch { .. })
+        ));
+
+        // the position does not matter, but only the first OP_RETURN counts
+        let change = || TxOut {
+            value: 5_000,
+            script_pubkey: new_address().script_pubkey(),
+        };
+        let late = transaction(vec![
+            change(),
+            change(),
+            paid.clone(),
+            op_return(request_id),
+        ]);
+        assert_eq!(check_outputs(&late, &payouts, Some(request_id)), Ok(()));
+        let shadowed = transaction(vec![
+            paid.clone(),
+            op_return(H256::from_low_u64_be(2)),
+            op_return(request_id),
+        ]);
+        assert!(matches!(
+            check_outputs(&shadowed, &payouts, Some(request_id)),
+            Err(PolicyError::RequestIdMismatch { .. })
+        ));
+
+        let underpaid = transaction(vec![
+            TxOut {
+                value: 999,
+                ..paid.clone()
+            },
+            op_return(request_id),
+        ]);
+        assert_eq!(
+            check_outputs(&underpaid, &payouts, Some(request_id)),
+            Err(PolicyError::PaymentMissing {
+                address: address.to_string(),
+                sat: 1_000,
+            })
+        );
+    }
+
+    #[test]
+    fn test_check_fee() {
+        assert_eq!(BroadcastPolicy::default().check_fee(None, 1_000), Ok(()));
+
+        let policy = BroadcastPolicy::new(Some(5_000), Some(1.0));
+        assert_eq!(policy.check_fee(Some(1_000), 100_000), Ok(()));
+        assert_eq!(
+            policy.check_fee(Some(6_000), 1_000_000),
+            Err(PolicyError::FeeTooHigh {
+                fee: 6_000,
+                max_fee: 5_000
+            })
+        );
+        assert!(matches!(
+            policy.check_fee(Some(1_001), 100_000),
+            Err(PolicyError::FeeTooHighForAmount { .. })
+        ));
+        // nothing paid, e.g. a fee bump
+        assert_eq!(policy.check_fee(Some(1_000), 0), Ok(()));
+        assert_eq!(policy.check_fee(None, 0), Err(PolicyError::FeeUnknown));
+    }
+
+    #[test]
+    fn test_deserialize_mempool_acceptance() {
+        let accepted: MempoolAcceptance = serde_json::from_value(serde_json::json!({
+            "txid": "0000000000000000000000000000000000000000000000000000000000000000",
+            "allowed": true,
+            "vsize": 141,
+            "fees": { "base": 0.00001410 }
+        }))
+        .unwrap();
+        assert_eq!(accepted.fee, Some(1_410));
+
+        let rejected: MempoolAcceptance = serde_json::from_value(serde_json::json!({
+            "txid": "0000000000000000000000000000000000000000000000000000000000000000",
+            "allowed": false,
+            "reject-reason": "min relay fee not met"
+        }))
+        .unwrap();
+        assert_eq!(
+            rejected,
+            MempoolAcceptance {
+                allowed: false,
+                reject_reason: Some("min relay fee not met".to_string()),
+                fee: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! Transactions that are signed outside of bitcoind, e.g. on an offline machine or a hardware
+//! wallet. The wallet funds a partially signed transaction (BIP 174) with
+//! `create_psbt`, which is exported with [`encode`] and handed to the signer. The signed PSBT
+//! is imported with [`decode`], merged with [`LockedPsbt::combine`] and turned into a
+//! broadcastable transaction by `finalize_psbt`.
+
+use crate::{
+    batch::{self, Payout},
+    ConversionError, Error, InputReservation, LockedTransaction, Transaction, TransactionLockGuard,
+    H256,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bitcoincore_rpc::bitcoin::consensus::encode::{deserialize, serialize};
+pub use bitcoincore_rpc::bitcoin::util::psbt::PartiallySignedTransaction;
+
+/// A funded PSBT, which holds the transaction creation lock like a [`LockedTransaction`]
+/// until it is finalized and sent, or dropped.
+pub struct LockedPsbt {
+    pub psbt: PartiallySignedTransaction,
+    pub recipient: String,
+    pub payouts: Vec<Payout>,
+    pub request_id: Option<H256>,
+    _lock: Option<TransactionLockGuard>,
+    reservation: Option<InputReservation>,
+}
+
+impl LockedPsbt {
+    pub fn new(
+        psbt: PartiallySignedTransaction,
+        payouts: Vec<Payout>,
+        request_id: Option<H256>,
+        lock: Option<TransactionLockGuard>,
+    ) -> Self {
+        LockedPsbt {
+            psbt,
+            recipient: batch::recipients(&payouts),
+            payouts,
+            request_id,
+            _lock: lock,
+            reservation: None,
+        }
+    }
+
+    /// Unlock the inputs in `reservation` if the PSBT is dropped without being sent.
+    pub fn with_reservation(mut self, reservation: Option<InputReservation>) -> Self {
+        self.reservation = reservation;
+        self
+    }
+
+    /// Keep the inputs locked when dropped, because they were unlocked explicitly.
+    pub(crate) fn disarm_reservation(&mut self) {
+        if let Some(ref mut reservation) = self.reservation {
+            reservation.disarm();
+        }
+    }
+
+    /// Merge the signatures (and any other data) of `signed`, which must be a copy of this
+    /// PSBT returned by a signer.
+    pub fn combine(&mut self, signed: PartiallySignedTransaction) -> Result<(), Error> {
+        self.psbt.merge(signed)?;
+        Ok(())
+    }
+
+    /// The finalized `transaction`, which keeps holding the lock and the reserved inputs.
+    pub(crate) fn finalized(self, transaction: Transaction) -> LockedTransaction {
+        LockedTransaction::new(transaction, self.payouts, self.request_id, self._lock)
+            .with_reservation(self.reservation)
+    }
+}
+
+/// Base64 encoding of the PSBT, as exchanged with bitcoind and most signers.
+pub fn encode(psbt: &PartiallySignedTransaction) -> String {
+    STANDARD.encode(serialize(psbt))
+}
+
+pub fn decode(psbt: &str) -> Result<PartiallySignedTransaction, Error> {
+    let bytes = STANDARD
+        .decode(psbt)
+        .map_err(ConversionError::Base64Error)?;
+    Ok(deserialize(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OutPoint, TxIn, TxOut};
+
+    #[test]
+    fn test_encode_decode() {
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Default::default(),
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 1_000,
+                script_pubkey: Default::default(),
+            }],
+        })
+        .unwrap();
+
+        let encoded = encode(&psbt);
+        // the magic bytes `psbt\xff`
+        assert!(encoded.starts_with("cHNidP8"));
+        assert_eq!(decode(&encoded).unwrap(), psbt);
+        assert!(decode("not base64!").is_err());
+    }
+}
@@ -0,0 +1,474 @@
+//! Conversion of bitcoind proofs into the format expected by the relay contract, i.e. the
+//! arguments of `OneBtc.executeIssue`.
+
+use crate::{
+    deserialize, serialize, serialize_without_witness, BlockHeader, Error, Hash, Script,
+    Transaction, TransactionMetadata, Txid, H160,
+};
+use bitcoincore_rpc::bitcoin::{consensus::Decodable, hash_types::TxMerkleNode};
+use std::io::Cursor;
+
+/// Merkle path of a single transaction, extracted from the partial merkle tree returned
+/// by `gettxoutproof`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    pub header: BlockHeader,
+    pub transaction_count: u32,
+    /// Position of the transaction in the block.
+    pub tx_index: u32,
+    /// Sibling hashes from the leaf up to, but excluding, the merkle root.
+    pub siblings: Vec<TxMerkleNode>,
+}
+
+impl MerkleProof {
+    /// Extract the path of `txid` from a serialized `MerkleBlock` (as returned by
+    /// [`get_proof`](crate::BitcoinCoreApi::get_proof)).
+    pub fn parse(proof: &[u8], txid: &Txid) -> Result<Self, Error> {
+        let mut cursor = Cursor::new(proof);
+        let header = BlockHeader::consensus_decode(&mut cursor)?;
+        let transaction_count = u32::consensus_decode(&mut cursor)?;
+        let hashes = Vec::<TxMerkleNode>::consensus_decode(&mut cursor)?;
+        let flags = Vec::<u8>::consensus_decode(&mut cursor)?;
+        if cursor.position() as usize != proof.len() || transaction_count == 0 {
+            return Err(Error::InvalidMerkleProof);
+        }
+
+        let mut tree = PartialMerkleTree {
+            transaction_count,
+            hashes,
+            bits: (0..flags.len() * 8)
+                .map(|i| flags[i / 8] & (1 << (i % 8)) != 0)
+                .collect(),
+            hashes_used: 0,
+            bits_used: 0,
+            txid: TxMerkleNode::from_inner(txid.into_inner()),
+            path: None,
+        };
+        let mut height = 0;
+        while tree.width(height) > 1 {
+            height += 1;
+        }
+        let merkle_root = tree.traverse(height, 0)?;
+
+        // all hashes and (up to padding) all bits must have been consumed
+        if merkle_root != header.merkle_root
+            || tree.hashes_used != tree.hashes.len()
+            || tree.bits_used.div_ceil(8) != flags.len()
+        {
+            return Err(Error::InvalidMerkleProof);
+        }
+        let (tx_index, siblings) = tree.path.ok_or(Error::TransactionNotInProof)?;
+
+        Ok(Self {
+            header,
+            transaction_count,
+            tx_index,
+            siblings,
+        })
+    }
+
+    /// The concatenated sibling hashes (in internal byte order), i.e. the `merkleProof`
+    /// argument of the contract.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.siblings
+            .iter()
+            .flat_map(|hash| hash.to_vec())
+            .collect()
+    }
+
+    /// Check that the proof in contract format passes [`verify_merkle_proof`].
+    pub fn verify(&self, txid: &Txid) -> Result<(), Error> {
+        verify_merkle_proof(
+            txid,
+            &serialize(&self.header),
+            self.tx_index,
+            &self.to_bytes(),
+        )
+    }
+}
+
+/// Check the inclusion of `txid` in the block with the given 80-byte header, exactly like
+/// `Relay.verifyTx` does via `ValidateSPV.prove`: the merkle root is recomputed from the
+/// txid (in internal byte order) and the concatenated siblings, where bit `i` of `tx_index`
+/// selects whether the sibling at level `i` is hashed before or after the current node.
+pub fn verify_merkle_proof(
+    txid: &Txid,
+    header: &[u8],
+    tx_index: u32,
+    merkle_proof: &[u8],
+) -> Result<(), Error> {
+    if *txid == Txid::default() {
+        return Err(Error::TransactionNotInProof);
+    }
+    if header.len() != 80 {
+        return Err(Error::InvalidBlockHeader);
+    }
+    // `extractMerkleRootLE`, i.e. the internal byte order
+    let merkle_root = &header[36..68];
+
+    // shortcut for blocks with a single transaction
+    if &txid[..] == merkle_root && tx_index == 0 && merkle_proof.is_empty() {
+        return Ok(());
+    }
+    // `verifyHash256Merkle` rejects proofs without intermediate nodes otherwise
+    if merkle_proof.is_empty() {
+        return Err(Error::MerkleRootMismatch);
+    }
+    if !merkle_proof.len().is_multiple_of(32) {
+        return Err(Error::InvalidMerkleProof);
+    }
+
+    let mut index = tx_index;
+    let mut current = TxMerkleNode::from_inner(txid.into_inner());
+    for sibling in merkle_proof.chunks(32) {
+        current = if index % 2 == 1 {
+            TxMerkleNode::hash(&[sibling, &current[..]].concat())
+        } else {
+            TxMerkleNode::hash(&[&current[..], sibling].concat())
+        };
+        index >>= 1;
+    }
+
+    if &current[..] == merkle_root {
+        Ok(())
+    } else {
+        Err(Error::MerkleRootMismatch)
+    }
+}
+
+struct PartialMerkleTree {
+    transaction_count: u32,
+    hashes: Vec<TxMerkleNode>,
+    bits: Vec<bool>,
+    hashes_used: usize,
+    bits_used: usize,
+    txid: TxMerkleNode,
+    /// Index and siblings of `txid`, once found.
+    path: Option<(u32, Vec<TxMerkleNode>)>,
+}
+
+impl PartialMerkleTree {
+    /// Number of nodes at the given height, where the leaves are at height 0.
+    fn width(&self, height: u32) -> u32 {
+        ((self.transaction_count as u64 + (1 << height) - 1) >> height) as u32
+    }
+
+    /// Depth-first traversal as in `CPartialMerkleTree::TraverseAndExtract`, returning the
+    /// hash of the node and recording the merkle path of the matching leaf.
+    fn traverse(&mut self, height: u32, pos: u32) -> Result<TxMerkleNode, Error> {
+        let is_parent_of_match = *self
+            .bits
+            .get(self.bits_used)
+            .ok_or(Error::InvalidMerkleProof)?;
+        self.bits_used += 1;
+
+        if height == 0 || !is_parent_of_match {
+            let hash = *self
+                .hashes
+                .get(self.hashes_used)
+                .ok_or(Error::InvalidMerkleProof)?;
+            self.hashes_used += 1;
+            if height == 0 && is_parent_of_match && hash == self.txid && self.path.is_none() {
+                self.path = Some((pos, vec![]));
+            }
+            return Ok(hash);
+        }
+
+        let found_before = self.path.is_some();
+        let left = self.traverse(height - 1, pos * 2)?;
+        let found_left = !found_before && self.path.is_some();
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            let right = self.traverse(height - 1, pos * 2 + 1)?;
+            // identical siblings would allow forging a proof (CVE-2012-2459)
+            if right == left {
+                return Err(Error::InvalidMerkleProof);
+            }
+            right
+        } else {
+            left
+        };
+        let found_right = !found_before && !found_left && self.path.is_some();
+
+        if let Some((_, siblings)) = self.path.as_mut() {
+            if found_left {
+                siblings.push(right);
+            } else if found_right {
+                siblings.push(left);
+            }
+        }
+        Ok(TxMerkleNode::hash(&[&left[..], &right[..]].concat()))
+    }
+}
+
+/// Hash of the recipient of an output as extracted by `BTCUtils.extractHash`, for
+/// p2pkh, p2sh and p2wpkh outputs.
+pub fn extract_output_hash(script_pubkey: &Script) -> Option<H160> {
+    let bytes = script_pubkey.as_bytes();
+    if script_pubkey.is_v0_p2wpkh() || script_pubkey.is_p2sh() {
+        Some(H160::from_slice(&bytes[2..22]))
+    } else if script_pubkey.is_p2pkh() {
+        Some(H160::from_slice(&bytes[3..23]))
+    } else {
+        None
+    }
+}
+
+/// Arguments of `OneBtc.executeIssue` proving the payment to a vault.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelayProof {
+    /// Concatenated sibling hashes, see [`MerkleProof::to_bytes`].
+    pub merkle_proof: Vec<u8>,
+    /// The transaction without witness data, see [`serialize_without_witness`].
+    pub raw_tx: Vec<u8>,
+    pub block_height: u32,
+    pub tx_index: u32,
+    /// The serialized 80-byte block header.
+    pub header: Vec<u8>,
+    /// Index of the output paying `recipient`.
+    pub output_index: u32,
+}
+
+impl RelayProof {
+    /// Build the proof for the first output of `raw_tx` paying to the 20-byte `recipient` hash.
+    /// The witness of a segwit `raw_tx` is stripped.
+    pub fn new(
+        raw_tx: Vec<u8>,
+        proof: &[u8],
+        block_height: u32,
+        recipient: &H160,
+    ) -> Result<Self, Error> {
+        let transaction: Transaction = deserialize(&raw_tx)?;
+        let merkle_proof = MerkleProof::parse(proof, &transaction.txid())?;
+        let output_index = transaction
+            .output
+            .iter()
+            .position(|output| {
+                extract_output_hash(&output.script_pubkey).as_ref() == Some(recipient)
+            })
+            .ok_or(Error::RecipientOutputNotFound)?;
+
+        Ok(Self {
+            merkle_proof: merkle_proof.to_bytes(),
+            raw_tx: serialize_without_witness(&transaction),
+            block_height,
+            tx_index: merkle_proof.tx_index,
+            header: serialize(&merkle_proof.header),
+            output_index: output_index as u32,
+        })
+    }
+
+    pub fn from_metadata(metadata: &TransactionMetadata, recipient: &H160) -> Result<Self, Error> {
+        Self::new(
+            metadata.stripped_raw_tx.clone(),
+            &metadata.proof,
+            metadata.block_height,
+            recipient,
+        )
+    }
+
+    /// The block height in the upper and the tx index in the lower 32 bits, as unpacked by
+    /// `executeIssue`.
+    pub fn height_and_index(&self) -> u64 {
+        (self.block_height as u64) << 32 | self.tx_index as u64
+    }
+
+    /// Check that the proof will pass `Relay.verifyTx`, which identifies the transaction by
+    /// the hash of `raw_tx`. Whether the header is part of the relay's main chain, and has
+    /// enough confirmations there, can only be checked by the contract.
+    pub fn verify(&self) -> Result<(), Error> {
+        verify_merkle_proof(
+            &Txid::hash(&self.raw_tx),
+            &self.header,
+            self.tx_index,
+            &self.merkle_proof,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock::MockBitcoinCore, BitcoinCoreApi, Network, PublicKey};
+    use bitcoincore_rpc::bitcoin::{
+        secp256k1::{rand::rngs::OsRng, Secp256k1},
+        OutPoint, TxIn, TxOut,
+    };
+
+    fn payment(value: u64, recipient: &PublicKey) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::default(), value as u32),
+                script_sig: Script::new(),
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            output: vec![
+                TxOut {
+                    value: 1,
+                    script_pubkey: Script::new_op_return(&[value as u8]),
+                },
+                TxOut {
+                    value,
+                    script_pubkey: Script::new_v0_wpkh(&recipient.wpubkey_hash().unwrap()),
+                },
+            ],
+        }
+    }
+
+    fn new_public_key() -> PublicKey {
+        let secp = Secp256k1::new();
+        let (_, public_key) = secp.generate_keypair(&mut OsRng::new().unwrap());
+        PublicKey {
+            compressed: true,
+            key: public_key,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merkle_proof_of_every_transaction() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let recipient = new_public_key();
+        // including the coinbase the block has an odd number of transactions
+        for value in 1..=6 {
+            btc_rpc.add_to_mempool(payment(value, &recipient));
+        }
+        let block_hash = btc_rpc.mine_block();
+        let block = btc_rpc.get_block(&block_hash).await.unwrap();
+
+        for (index, transaction) in block.txdata.iter().enumerate() {
+            let txid = transaction.txid();
+            let proof = btc_rpc.get_proof(txid, &block_hash).await.unwrap();
+            let merkle_proof = MerkleProof::parse(&proof, &txid).unwrap();
+
+            assert_eq!(merkle_proof.header, block.header);
+            assert_eq!(merkle_proof.transaction_count, 7);
+            assert_eq!(merkle_proof.tx_index, index as u32);
+            assert_eq!(merkle_proof.siblings.len(), 3);
+            assert_eq!(merkle_proof.to_bytes().len(), 3 * 32);
+            merkle_proof.verify(&txid).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merkle_proof_of_single_transaction_block() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let block_hash = btc_rpc.mine_block();
+        let block = btc_rpc.get_block(&block_hash).await.unwrap();
+        let txid = block.txdata[0].txid();
+
+        let proof = btc_rpc.get_proof(txid, &block_hash).await.unwrap();
+        let merkle_proof = MerkleProof::parse(&proof, &txid).unwrap();
+        assert_eq!(merkle_proof.tx_index, 0);
+        assert!(merkle_proof.siblings.is_empty());
+        merkle_proof.verify(&txid).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verify_merkle_proof_rejects_mismatch() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let recipient = new_public_key();
+        let txids: Vec<_> = (1..=4)
+            .map(|value| btc_rpc.add_to_mempool(payment(value, &recipient)))
+            .collect();
+        let block_hash = btc_rpc.mine_block();
+        let proof = btc_rpc.get_proof(txids[1], &block_hash).await.unwrap();
+        let merkle_proof = MerkleProof::parse(&proof, &txids[1]).unwrap();
+        let header = serialize(&merkle_proof.header);
+        let siblings = merkle_proof.to_bytes();
+        verify_merkle_proof(&txids[1], &header, 2, &siblings).unwrap();
+
+        let mismatch = |txid, tx_index, siblings: &[u8]| {
+            matches!(
+                verify_merkle_proof(txid, &header, tx_index, siblings),
+                Err(Error::MerkleRootMismatch)
+            )
+        };
+        assert!(mismatch(&txids[0], 2, &siblings));
+        assert!(mismatch(&txids[1], 3, &siblings));
+        assert!(mismatch(&txids[1], 2, &siblings[..64]));
+        assert!(mismatch(&txids[1], 2, &[]));
+        let mut tampered = siblings.clone();
+        tampered[0] ^= 1;
+        assert!(mismatch(&txids[1], 2, &tampered));
+
+        assert!(matches!(
+            verify_merkle_proof(&txids[1], &header, 2, &siblings[1..]),
+            Err(Error::InvalidMerkleProof)
+        ));
+        assert!(matches!(
+            verify_merkle_proof(&txids[1], &header[1..], 2, &siblings),
+            Err(Error::InvalidBlockHeader)
+        ));
+        assert!(verify_merkle_proof(&Txid::default(), &header, 2, &siblings).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_merkle_proof_rejects_invalid_proofs() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let recipient = new_public_key();
+        let txid = btc_rpc.add_to_mempool(payment(1, &recipient));
+        let block_hash = btc_rpc.mine_block();
+        let proof = btc_rpc.get_proof(txid, &block_hash).await.unwrap();
+
+        assert!(matches!(
+            MerkleProof::parse(&proof, &Txid::default()),
+            Err(Error::TransactionNotInProof)
+        ));
+        assert!(MerkleProof::parse(&proof[..proof.len() - 1], &txid).is_err());
+
+        // flip a bit of the first hash in the tree
+        let mut tampered = proof.clone();
+        tampered[80 + 4 + 1] ^= 1;
+        assert!(matches!(
+            MerkleProof::parse(&tampered, &txid),
+            Err(Error::InvalidMerkleProof)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_relay_proof() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        btc_rpc.mine_blocks(2);
+        let recipient = new_public_key();
+        btc_rpc.add_to_mempool(payment(1, &new_public_key()));
+        let txid = btc_rpc.add_to_mempool(payment(2, &recipient));
+        btc_rpc.mine_block();
+
+        let metadata = btc_rpc
+            .wait_for_transaction_metadata(txid, 1)
+            .await
+            .unwrap();
+        let recipient_hash = H160::from_slice(&recipient.wpubkey_hash().unwrap()[..]);
+        let relay_proof = RelayProof::from_metadata(&metadata, &recipient_hash).unwrap();
+
+        assert_eq!(relay_proof.block_height, 3);
+        assert_eq!(relay_proof.tx_index, 2);
+        assert_eq!(relay_proof.height_and_index(), 3 << 32 | 2);
+        assert_eq!(relay_proof.output_index, 1);
+        assert_eq!(relay_proof.header.len(), 80);
+        assert_eq!(relay_proof.raw_tx, metadata.stripped_raw_tx);
+        relay_proof.verify().unwrap();
+
+        assert!(matches!(
+            RelayProof::from_metadata(&metadata, &H160::zero()),
+            Err(Error::RecipientOutputNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_extract_output_hash() {
+        let public_key = new_public_key();
+        let hash = H160::from_slice(&public_key.pubkey_hash()[..]);
+        assert_eq!(
+            extract_output_hash(&Script::new_p2pkh(&public_key.pubkey_hash())),
+            Some(hash)
+        );
+        assert_eq!(
+            extract_output_hash(&Script::new_v0_wpkh(&public_key.wpubkey_hash().unwrap())),
+            Some(hash)
+        );
+        assert_eq!(extract_output_hash(&Script::new_op_return(&[0; 20])), None);
+    }
+}
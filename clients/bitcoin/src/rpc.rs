@@ -0,0 +1,945 @@
+//! Asynchronous JSON-RPC transport for bitcoind. The blocking `bitcoincore_rpc::Client` would
+//! stall the executor, so requests are made over a pooled HTTP client instead. Errors are
+//! reported in the same shape as `bitcoincore_rpc`, so the helpers on [`Error`] still apply.
+
+use crate::{
+    descriptor::{KeyOrigin, WalletType},
+    deserialize, json,
+    policy::MempoolAcceptance,
+    Address, Auth, BitcoinError, Block, BlockHash, BlockHeader, ConversionError, Error,
+    JsonRpcError, OutPoint, PrivateKey, PublicKey, Transaction, Txid,
+};
+use bitcoincore_rpc::{
+    bitcoin::{
+        consensus::encode::serialize_hex,
+        util::bip32::{DerivationPath, Fingerprint},
+    },
+    jsonrpc::{error::RpcError, Request, Response},
+};
+use reqwest::header::CONTENT_TYPE;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Timeout applied to every request, unless overridden.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Idle connections kept open to the node, shared between all clones of the client.
+const MAX_IDLE_CONNECTIONS: usize = 32;
+
+/// Batches larger than this are split over multiple http requests, to bound the size of a
+/// single response (e.g. when fetching every transaction of a busy mempool).
+pub const MAX_BATCH_SIZE: usize = 500;
+
+#[derive(Clone)]
+pub struct RpcClient {
+    client: reqwest::Client,
+    url: String,
+    user: Option<String>,
+    pass: Option<String>,
+    request_timeout: Duration,
+    nonce: Arc<AtomicU64>,
+}
+
+fn read_auth(auth: Auth) -> Result<(Option<String>, Option<String>), Error> {
+    match auth {
+        Auth::None => Ok((None, None)),
+        Auth::UserPass(user, pass) => Ok((Some(user), Some(pass))),
+        Auth::CookieFile(path) => {
+            let contents = fs::read_to_string(path).map_err(BitcoinError::Io)?;
+            let mut split = contents.trim_end().splitn(2, ':');
+            match (split.next(), split.next()) {
+                (Some(user), Some(pass)) => Ok((Some(user.to_string()), Some(pass.to_string()))),
+                _ => Err(BitcoinError::InvalidCookieFile.into()),
+            }
+        }
+    }
+}
+
+fn into_result<T: DeserializeOwned>(response: Response) -> Result<T, Error> {
+    Ok(response.into_result().map_err(BitcoinError::JsonRpc)?)
+}
+
+fn decode_hex<T: bitcoincore_rpc::bitcoin::consensus::Decodable>(hex: &str) -> Result<T, Error> {
+    Ok(deserialize(
+        &hex::decode(hex).map_err(ConversionError::from)?,
+    )?)
+}
+
+impl RpcClient {
+    pub fn new(url: String, auth: Auth, request_timeout: Duration) -> Result<Self, Error> {
+        let (user, pass) = read_auth(auth)?;
+        let client = reqwest::Client::builder()
+            .pool_max_idle_per_host(MAX_IDLE_CONNECTIONS)
+            .build()?;
+        Ok(Self {
+            client,
+            url,
+            user,
+            pass,
+            request_timeout,
+            nonce: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Returns a client that shares the connection pool, but uses a different default timeout.
+    pub fn with_request_timeout(&self, request_timeout: Duration) -> Self {
+        Self {
+            request_timeout,
+            ..self.clone()
+        }
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        self.request_timeout
+    }
+
+    fn post(&self, timeout: Duration) -> reqwest::RequestBuilder {
+        let builder = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .timeout(timeout);
+        match self.user {
+            Some(ref user) => builder.basic_auth(user, self.pass.as_ref()),
+            None => builder,
+        }
+    }
+
+    fn next_id(&self) -> Value {
+        Value::from(self.nonce.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Call `method` with the default request timeout.
+    pub async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        args: &[Value],
+    ) -> Result<T, Error> {
+        self.call_with_timeout(method, args, self.request_timeout)
+            .await
+    }
+
+    /// Call `method`, failing with a timeout error if no response is received in time.
+    pub async fn call_with_timeout<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        args: &[Value],
+        timeout: Duration,
+    ) -> Result<T, Error> {
+        let request = Request {
+            method,
+            params: args,
+            id: self.next_id(),
+            jsonrpc: Some("2.0"),
+        };
+        // bitcoind reports rpc errors with a non-success status code, so the body is
+        // always parsed. An empty body (e.g. on 401) surfaces as a json decode error
+        let body = self
+            .post(timeout)
+            .json(&request)
+            .send()
+            .await?
+            .bytes()
+            .await?;
+        let response: Response = serde_json::from_slice(&body)
+            .map_err(|err| BitcoinError::JsonRpc(JsonRpcError::Json(err)))?;
+        if response.id != request.id {
+            return Err(BitcoinError::JsonRpc(JsonRpcError::NonceMismatch).into());
+        }
+        into_result(response)
+    }
+
+    /// Send the `(method, args)` requests as JSON-RPC batches, returning the responses in
+    /// the same order as the requests. Errors of individual calls are not checked here.
+    pub async fn send_batch(
+        &self,
+        requests: &[(&str, Vec<Value>)],
+    ) -> Result<Vec<Response>, Error> {
+        let mut responses = Vec::with_capacity(requests.len());
+        for chunk in requests.chunks(MAX_BATCH_SIZE) {
+            let chunk: Vec<_> = chunk
+                .iter()
+                .map(|(method, args)| Request {
+                    method,
+                    params: args,
+                    id: self.next_id(),
+                    jsonrpc: Some("2.0"),
+                })
+                .collect();
+            let body = self
+                .post(self.request_timeout)
+                .json(&chunk)
+                .send()
+                .await?
+                .bytes()
+                .await?;
+            let batch: Vec<Response> = serde_json::from_slice(&body)
+                .map_err(|err| BitcoinError::JsonRpc(JsonRpcError::Json(err)))?;
+
+            // responses may arrive in any order, match them by id
+            let mut by_id = HashMap::with_capacity(batch.len());
+            for response in batch {
+                let id = response.id.to_string();
+                if by_id.insert(id, response).is_some() {
+                    return Err(BitcoinError::JsonRpc(JsonRpcError::WrongBatchResponseSize).into());
+                }
+            }
+            for request in chunk {
+                let response =
+                    by_id
+                        .remove(&request.id.to_string())
+                        .ok_or(BitcoinError::JsonRpc(JsonRpcError::WrongBatchResponseId(
+                            request.id,
+                        )))?;
+                responses.push(response);
+            }
+            if let Some((_, response)) = by_id.into_iter().next() {
+                return Err(
+                    BitcoinError::JsonRpc(JsonRpcError::WrongBatchResponseId(response.id)).into(),
+                );
+            }
+        }
+        Ok(responses)
+    }
+
+    /// Call `method` once for every entry of `args` in as few round trips as possible.
+    pub async fn call_batch<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        args: Vec<Vec<Value>>,
+    ) -> Result<Vec<Result<T, Error>>, Error> {
+        let requests: Vec<_> = args.into_iter().map(|args| (method, args)).collect();
+        Ok(self
+            .send_batch(&requests)
+            .await?
+            .into_iter()
+            .map(into_result)
+            .collect())
+    }
+
+    pub async fn get_blockchain_info(&self) -> Result<json::GetBlockchainInfoResult, Error> {
+        self.call("getblockchaininfo", &[]).await
+    }
+
+    pub async fn get_block_count(&self) -> Result<u64, Error> {
+        self.call("getblockcount", &[]).await
+    }
+
+    pub async fn get_best_block_hash(&self) -> Result<BlockHash, Error> {
+        self.call("getbestblockhash", &[]).await
+    }
+
+    pub async fn get_block_hash(&self, height: u64) -> Result<BlockHash, Error> {
+        self.call("getblockhash", &[height.into()]).await
+    }
+
+    pub async fn get_block_hashes(
+        &self,
+        heights: &[u64],
+    ) -> Result<Vec<Result<BlockHash, Error>>, Error> {
+        self.call_batch(
+            "getblockhash",
+            heights
+                .iter()
+                .map(|height| vec![(*height).into()])
+                .collect(),
+        )
+        .await
+    }
+
+    pub async fn get_block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        let hex: String = self.call("getblock", &[json!(hash), 0.into()]).await?;
+        decode_hex(&hex)
+    }
+
+    pub async fn get_block_info(&self, hash: &BlockHash) -> Result<json::GetBlockResult, Error> {
+        self.call("getblock", &[json!(hash), 1.into()]).await
+    }
+
+    pub async fn get_block_header(&self, hash: &BlockHash) -> Result<BlockHeader, Error> {
+        let hex: String = self
+            .call("getblockheader", &[json!(hash), false.into()])
+            .await?;
+        decode_hex(&hex)
+    }
+
+    pub async fn get_raw_mempool(&self) -> Result<Vec<Txid>, Error> {
+        self.call("getrawmempool", &[]).await
+    }
+
+    pub async fn get_raw_transaction(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> Result<Transaction, Error> {
+        let mut args = vec![json!(txid), false.into()];
+        if let Some(block_hash) = block_hash {
+            args.push(json!(block_hash));
+        }
+        let hex: String = self.call("getrawtransaction", &args).await?;
+        decode_hex(&hex)
+    }
+
+    pub async fn get_raw_transaction_info(
+        &self,
+        txid: &Txid,
+        block_hash: Option<&BlockHash>,
+    ) -> Result<json::GetRawTransactionResult, Error> {
+        let mut args = vec![json!(txid), true.into()];
+        if let Some(block_hash) = block_hash {
+            args.push(json!(block_hash));
+        }
+        self.call("getrawtransaction", &args).await
+    }
+
+    pub async fn get_raw_transaction_infos(
+        &self,
+        txids: &[Txid],
+    ) -> Result<Vec<Result<json::GetRawTransactionResult, Error>>, Error> {
+        self.call_batch(
+            "getrawtransaction",
+            txids
+                .iter()
+                .map(|txid| vec![json!(txid), true.into()])
+                .collect(),
+        )
+        .await
+    }
+
+    /// Fetch the raw transaction and its merkle proof from `block_hash` in a single batch.
+    pub async fn get_raw_transaction_and_proof(
+        &self,
+        txid: &Txid,
+        block_hash: &BlockHash,
+    ) -> Result<(Transaction, Vec<u8>), Error> {
+        let mut responses = self
+            .send_batch(&[
+                (
+                    "getrawtransaction",
+                    vec![json!(txid), false.into(), json!(block_hash)],
+                ),
+                ("gettxoutproof", vec![json!([txid]), json!(block_hash)]),
+            ])
+            .await?
+            .into_iter();
+        let (transaction, proof) = match (responses.next(), responses.next()) {
+            (Some(transaction), Some(proof)) => (transaction, proof),
+            _ => return Err(BitcoinError::JsonRpc(JsonRpcError::WrongBatchResponseSize).into()),
+        };
+        let transaction: String = into_result(transaction)?;
+        let proof: String = into_result(proof)?;
+        Ok((
+            decode_hex(&transaction)?,
+            hex::decode(proof).map_err(ConversionError::from)?,
+        ))
+    }
+
+    pub async fn get_tx_out_proof(
+        &self,
+        txids: &[Txid],
+        block_hash: Option<&BlockHash>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut args = vec![json!(txids)];
+        if let Some(block_hash) = block_hash {
+            args.push(json!(block_hash));
+        }
+        let hex: String = self.call("gettxoutproof", &args).await?;
+        Ok(hex::decode(hex).map_err(ConversionError::from)?)
+    }
+
+    pub async fn get_transaction(&self, txid: &Txid) -> Result<json::GetTransactionResult, Error> {
+        self.call("gettransaction", &[json!(txid)]).await
+    }
+
+    /// Transactions among the last `count` wallet transactions that pay from the wallet,
+    /// excluding those that were replaced, abandoned or conflict with the main chain.
+    pub async fn list_sent_transactions(&self, count: usize) -> Result<Vec<Txid>, Error> {
+        #[derive(Deserialize)]
+        struct ListTransactionsEntry {
+            txid: Txid,
+            category: String,
+            confirmations: i64,
+            #[serde(default)]
+            abandoned: bool,
+            replaced_by_txid: Option<Txid>,
+        }
+
+        let entries: Vec<ListTransactionsEntry> = self
+            .call(
+                "listtransactions",
+                &["*".into(), count.into(), 0.into(), true.into()],
+            )
+            .await?;
+        let mut txids = Vec::new();
+        for entry in entries {
+            let live = entry.confirmations >= 0 && !entry.abandoned;
+            if entry.category == "send"
+                && live
+                && entry.replaced_by_txid.is_none()
+                && !txids.contains(&entry.txid)
+            {
+                txids.push(entry.txid);
+            }
+        }
+        Ok(txids)
+    }
+
+    pub async fn get_wallet_transactions(
+        &self,
+        txids: &[Txid],
+    ) -> Result<Vec<Result<json::GetTransactionResult, Error>>, Error> {
+        self.call_batch(
+            "gettransaction",
+            txids.iter().map(|txid| vec![json!(txid)]).collect(),
+        )
+        .await
+    }
+
+    pub async fn get_new_address(&self, address_type: json::AddressType) -> Result<Address, Error> {
+        self.call("getnewaddress", &[Value::Null, json!(address_type)])
+            .await
+    }
+
+    pub async fn get_address_info(
+        &self,
+        address: &Address,
+    ) -> Result<json::GetAddressInfoResult, Error> {
+        self.call("getaddressinfo", &[address.to_string().into()])
+            .await
+    }
+
+    pub async fn get_wallet_type(&self) -> Result<WalletType, Error> {
+        #[derive(Deserialize)]
+        struct WalletInfo {
+            /// Missing in versions without descriptor wallets.
+            #[serde(default)]
+            descriptors: bool,
+        }
+
+        let info: WalletInfo = self.call("getwalletinfo", &[]).await?;
+        Ok(if info.descriptors {
+            WalletType::Descriptor
+        } else {
+            WalletType::Legacy
+        })
+    }
+
+    /// The origin of the key of `address`, if it was derived by the wallet.
+    pub async fn get_key_origin(&self, address: &Address) -> Result<Option<KeyOrigin>, Error> {
+        #[derive(Deserialize)]
+        struct AddressInfo {
+            hdmasterfingerprint: Option<Fingerprint>,
+            hdkeypath: Option<DerivationPath>,
+        }
+
+        let info: AddressInfo = self
+            .call("getaddressinfo", &[address.to_string().into()])
+            .await?;
+        Ok(match (info.hdmasterfingerprint, info.hdkeypath) {
+            (Some(fingerprint), Some(path)) => Some(KeyOrigin { fingerprint, path }),
+            _ => None,
+        })
+    }
+
+    /// The descriptors of a descriptor wallet, including their private keys.
+    pub async fn list_private_descriptors(&self) -> Result<Vec<String>, Error> {
+        #[derive(Deserialize)]
+        struct Descriptor {
+            desc: String,
+        }
+        #[derive(Deserialize)]
+        struct ListDescriptorsResult {
+            descriptors: Vec<Descriptor>,
+        }
+
+        let result: ListDescriptorsResult = self.call("listdescriptors", &[true.into()]).await?;
+        Ok(result
+            .descriptors
+            .into_iter()
+            .map(|descriptor| descriptor.desc)
+            .collect())
+    }
+
+    /// Append the checksum to `descriptor`, as required by `importdescriptors`.
+    pub async fn add_descriptor_checksum(&self, descriptor: &str) -> Result<String, Error> {
+        let mut info: Value = self.call("getdescriptorinfo", &[descriptor.into()]).await?;
+        let checksum: String = serde_json::from_value(info["checksum"].take())?;
+        Ok(format!("{}#{}", descriptor, checksum))
+    }
+
+    /// Import a `descriptor` that includes its checksum. Without `rescan`, only outputs
+    /// received from now on are tracked.
+    pub async fn import_descriptor(
+        &self,
+        descriptor: &str,
+        label: &str,
+        rescan: bool,
+    ) -> Result<(), Error> {
+        #[derive(Deserialize)]
+        struct ImportResult {
+            success: bool,
+            error: Option<RpcError>,
+        }
+
+        let timestamp = if rescan { json!(0) } else { json!("now") };
+        let request = json!([{ "desc": descriptor, "timestamp": timestamp, "label": label }]);
+        let results: Vec<ImportResult> = self.call("importdescriptors", &[request]).await?;
+        match results.into_iter().next() {
+            Some(ImportResult { success: true, .. }) => Ok(()),
+            // reported per descriptor rather than as an rpc error
+            Some(ImportResult {
+                error: Some(err), ..
+            }) => Err(BitcoinError::JsonRpc(JsonRpcError::Rpc(err)).into()),
+            _ => Err(BitcoinError::UnexpectedStructure.into()),
+        }
+    }
+
+    pub async fn dump_private_key(&self, address: &Address) -> Result<PrivateKey, Error> {
+        self.call("dumpprivkey", &[address.to_string().into()])
+            .await
+    }
+
+    /// Watch the outputs of `public_key`, without rescanning the chain.
+    pub async fn import_public_key(
+        &self,
+        public_key: &PublicKey,
+        label: &str,
+    ) -> Result<(), Error> {
+        self.call(
+            "importpubkey",
+            &[public_key.to_string().into(), label.into(), false.into()],
+        )
+        .await
+    }
+
+    pub async fn import_private_key(
+        &self,
+        private_key: &PrivateKey,
+        label: &str,
+        rescan: Option<bool>,
+    ) -> Result<(), Error> {
+        let mut args = vec![private_key.to_string().into(), label.into()];
+        if let Some(rescan) = rescan {
+            args.push(rescan.into());
+        }
+        self.call("importprivkey", &args).await
+    }
+
+    pub async fn rescan_blockchain(&self, start_height: usize) -> Result<Value, Error> {
+        self.call("rescanblockchain", &[start_height.into()]).await
+    }
+
+    pub async fn fund_raw_transaction(
+        &self,
+        raw_tx: &str,
+        options: &Value,
+    ) -> Result<json::FundRawTransactionResult, Error> {
+        self.call("fundrawtransaction", &[raw_tx.into(), options.clone()])
+            .await
+    }
+
+    /// Create and fund a PSBT with the `inputs` and `outputs` of `createrawtransaction`,
+    /// including the key origins so that external signers can find their keys.
+    pub async fn wallet_create_funded_psbt(
+        &self,
+        inputs: Value,
+        outputs: Value,
+        options: &Value,
+    ) -> Result<json::WalletCreateFundedPsbtResult, Error> {
+        self.call(
+            "walletcreatefundedpsbt",
+            &[inputs, outputs, 0.into(), options.clone(), true.into()],
+        )
+        .await
+    }
+
+    pub async fn finalize_psbt(&self, psbt: &str) -> Result<json::FinalizePsbtResult, Error> {
+        self.call("finalizepsbt", &[psbt.into()]).await
+    }
+
+    pub async fn estimate_smart_fee(
+        &self,
+        conf_target: u16,
+    ) -> Result<json::EstimateSmartFeeResult, Error> {
+        self.call("estimatesmartfee", &[conf_target.into()]).await
+    }
+
+    pub async fn get_mempool_entry(
+        &self,
+        txid: &Txid,
+    ) -> Result<json::GetMempoolEntryResult, Error> {
+        self.call("getmempoolentry", &[json!(txid)]).await
+    }
+
+    pub async fn list_unspent(
+        &self,
+        min_conf: u32,
+        max_conf: u32,
+    ) -> Result<Vec<json::ListUnspentResultEntry>, Error> {
+        self.call("listunspent", &[min_conf.into(), max_conf.into()])
+            .await
+    }
+
+    pub async fn lock_unspent(&self, unlock: bool, outpoints: &[OutPoint]) -> Result<bool, Error> {
+        let outpoints: Vec<_> = outpoints
+            .iter()
+            .map(|outpoint| json!({ "txid": outpoint.txid, "vout": outpoint.vout }))
+            .collect();
+        self.call("lockunspent", &[unlock.into(), outpoints.into()])
+            .await
+    }
+
+    /// Like `bumpfee`, but returns the unsigned replacement as a PSBT instead of broadcasting
+    /// it.
+    pub async fn psbt_bump_fee(&self, txid: &Txid, options: &Value) -> Result<String, Error> {
+        let mut result: Value = self
+            .call("psbtbumpfee", &[json!(txid), options.clone()])
+            .await?;
+        Ok(serde_json::from_value(result["psbt"].take())?)
+    }
+
+    pub async fn sign_raw_transaction_with_wallet(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<json::SignRawTransactionResult, Error> {
+        self.call(
+            "signrawtransactionwithwallet",
+            &[serialize_hex(transaction).into()],
+        )
+        .await
+    }
+
+    pub async fn test_mempool_accept(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<MempoolAcceptance, Error> {
+        let mut results: Vec<MempoolAcceptance> = self
+            .call("testmempoolaccept", &[json!([serialize_hex(transaction)])])
+            .await?;
+        results
+            .pop()
+            .ok_or_else(|| ConversionError::InvalidFormat.into())
+    }
+
+    pub async fn send_raw_transaction(&self, transaction: &Transaction) -> Result<Txid, Error> {
+        self.call("sendrawtransaction", &[serialize_hex(transaction).into()])
+            .await
+    }
+
+    pub async fn list_wallets(&self) -> Result<Vec<String>, Error> {
+        self.call("listwallets", &[]).await
+    }
+
+    pub async fn load_wallet(&self, wallet: &str) -> Result<json::LoadWalletResult, Error> {
+        self.call("loadwallet", &[wallet.into()]).await
+    }
+
+    /// Create a wallet of `wallet_type`, or of the default type of bitcoind if not set.
+    pub async fn create_wallet(
+        &self,
+        wallet: &str,
+        disable_private_keys: bool,
+        wallet_type: Option<WalletType>,
+    ) -> Result<json::LoadWalletResult, Error> {
+        let mut args = vec![wallet.into(), disable_private_keys.into()];
+        if let Some(wallet_type) = wallet_type {
+            // skip blank, passphrase and avoid_reuse
+            args.extend(vec![Value::Null; 3]);
+            args.push((wallet_type == WalletType::Descriptor).into());
+        }
+        self.call("createwallet", &args).await
+    }
+
+    pub async fn generate_to_address(
+        &self,
+        block_num: u64,
+        address: &Address,
+    ) -> Result<Vec<BlockHash>, Error> {
+        self.call(
+            "generatetoaddress",
+            &[block_num.into(), address.to_string().into()],
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{BitcoinRpcError, RpcError};
+    use std::sync::Mutex;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Minimal HTTP server standing in for bitcoind. Every request body is recorded and
+    /// answered by `respond`, which returns the status code and the json body.
+    pub(crate) async fn serve<F>(respond: F) -> (String, Arc<Mutex<Vec<(String, Value)>>>)
+    where
+        F: Fn(&Value) -> (u16, Value) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = requests.clone();
+        let respond = Arc::new(respond);
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let requests = recorded.clone();
+                let respond = respond.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    loop {
+                        // parse as many requests as the (keep-alive) connection sends
+                        let (head, body) = loop {
+                            let mut chunk = [0u8; 4096];
+                            let read = socket.read(&mut chunk).await.unwrap_or(0);
+                            if read == 0 {
+                                return;
+                            }
+                            buf.extend_from_slice(&chunk[..read]);
+                            let text = String::from_utf8_lossy(&buf).to_string();
+                            if let Some(end) = text.find("\r\n\r\n") {
+                                let head = text[..end].to_string();
+                                let length = head
+                                    .lines()
+                                    .find_map(|line| {
+                                        let line = line.to_lowercase();
+                                        line.strip_prefix("content-length:")
+                                            .map(|len| len.trim().parse::<usize>().unwrap())
+                                    })
+                                    .unwrap_or(0);
+                                if buf.len() >= end + 4 + length {
+                                    let body = buf[end + 4..end + 4 + length].to_vec();
+                                    buf.drain(..end + 4 + length);
+                                    break (head, body);
+                                }
+                            }
+                        };
+                        let request: Value = serde_json::from_slice(&body).unwrap();
+                        let (status, response) = respond(&request);
+                        requests.lock().unwrap().push((head, request));
+                        let response = response.to_string();
+                        let reply = format!(
+                            "HTTP/1.1 {} X\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                            status,
+                            response.len(),
+                            response
+                        );
+                        socket.write_all(reply.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    fn new_client(url: String) -> RpcClient {
+        RpcClient::new(
+            url,
+            Auth::UserPass("rpcuser".to_string(), "rpcpassword".to_string()),
+            DEFAULT_REQUEST_TIMEOUT,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_call_sends_json_rpc_request() {
+        let (url, requests) = serve(|request| {
+            (
+                200,
+                json!({ "result": 42, "error": null, "id": request["id"] }),
+            )
+        })
+        .await;
+        let client = new_client(url);
+
+        assert_eq!(client.get_block_count().await.unwrap(), 42);
+        assert_eq!(client.get_block_count().await.unwrap(), 42);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let (head, request) = &requests[0];
+        assert_eq!(request["method"], "getblockcount");
+        assert_eq!(request["params"], json!([]));
+        // base64("rpcuser:rpcpassword")
+        assert!(head.contains("cnBjdXNlcjpycGNwYXNzd29yZA=="));
+        assert_ne!(requests[0].1["id"], requests[1].1["id"]);
+    }
+
+    #[tokio::test]
+    async fn test_call_maps_rpc_errors() {
+        let (url, _) = serve(|request| {
+            (
+                500,
+                json!({
+                    "result": null,
+                    "error": { "code": -8, "message": "Block height out of range" },
+                    "id": request["id"]
+                }),
+            )
+        })
+        .await;
+        let client = new_client(url);
+
+        let err = client.get_block_hash(1000).await.unwrap_err();
+        assert!(err.is_invalid_parameter());
+        assert!(matches!(
+            err,
+            Error::BitcoinError(BitcoinError::JsonRpc(JsonRpcError::Rpc(RpcError { code, .. })))
+                if code == BitcoinRpcError::RpcInvalidParameter as i32
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_call_reports_invalid_response() {
+        let (url, _) = serve(|request| {
+            (
+                200,
+                json!({ "result": "not a number", "error": null, "id": request["id"] }),
+            )
+        })
+        .await;
+        assert!(new_client(url)
+            .get_block_count()
+            .await
+            .unwrap_err()
+            .is_json_decode_error());
+    }
+
+    /// Answers every request of a batch (in reverse order) with `respond`.
+    fn batch_responder<F>(respond: F) -> impl Fn(&Value) -> (u16, Value) + Send + Sync + 'static
+    where
+        F: Fn(&Value) -> Value + Send + Sync + 'static,
+    {
+        move |batch| {
+            let responses = batch
+                .as_array()
+                .expect("batch request")
+                .iter()
+                .rev()
+                .map(&respond)
+                .collect();
+            (200, Value::Array(responses))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_call_batch_matches_responses_by_id() {
+        let (url, requests) = serve(batch_responder(|request| {
+            let height = request["params"][0].as_u64().unwrap();
+            if height > 2 {
+                json!({
+                    "result": null,
+                    "error": { "code": -8, "message": "Block height out of range" },
+                    "id": request["id"]
+                })
+            } else {
+                json!({
+                    "result": format!("{:064x}", height),
+                    "error": null,
+                    "id": request["id"]
+                })
+            }
+        }))
+        .await;
+
+        let hashes = new_client(url)
+            .get_block_hashes(&[2, 0, 1, 3])
+            .await
+            .unwrap();
+        assert_eq!(hashes.len(), 4);
+        for (height, hash) in [2u64, 0, 1].iter().zip(hashes.iter()) {
+            assert_eq!(
+                hash.as_ref().unwrap().to_string(),
+                format!("{:064x}", height)
+            );
+        }
+        assert!(hashes[3].as_ref().unwrap_err().is_invalid_parameter());
+
+        // a single http round trip
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_call_batch_splits_large_batches() {
+        let (url, requests) = serve(batch_responder(
+            |request| json!({ "result": request["params"][0], "error": null, "id": request["id"] }),
+        ))
+        .await;
+
+        let args: Vec<_> = (0..MAX_BATCH_SIZE as u64 + 1)
+            .map(|i| vec![i.into()])
+            .collect();
+        let results: Vec<Result<u64, Error>> =
+            new_client(url).call_batch("echo", args).await.unwrap();
+        assert_eq!(
+            results.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            (0..MAX_BATCH_SIZE as u64 + 1).collect::<Vec<_>>()
+        );
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].1.as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_call_batch_rejects_missing_responses() {
+        let (url, _) = serve(|batch| {
+            let request = &batch[0];
+            (
+                200,
+                json!([{ "result": 1, "error": null, "id": request["id"] }]),
+            )
+        })
+        .await;
+
+        let result: Result<Vec<Result<u64, Error>>, Error> = new_client(url)
+            .call_batch("echo", vec![vec![], vec![]])
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::BitcoinError(BitcoinError::JsonRpc(
+                JsonRpcError::WrongBatchResponseId(_)
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_call_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        // accept, but never respond
+        let _server = tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            futures::future::pending::<()>().await
+        });
+
+        let client = new_client(url);
+        let err = client
+            .call_with_timeout::<u64>("getblockcount", &[], Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::HttpError(ref err) if err.is_timeout()));
+    }
+
+    #[tokio::test]
+    async fn test_connection_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let err = new_client(url).get_block_count().await.unwrap_err();
+        assert!(err.is_connection_refused());
+    }
+}
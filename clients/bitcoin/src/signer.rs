@@ -0,0 +1,177 @@
+//! Signing outside of the bitcoind wallet. With a [`Signer`], the wallet is watch-only:
+//! bitcoind tracks the addresses of the signer's keys and funds transactions as PSBTs, which
+//! the signer signs. The signer may live in a separate process or signing service, while
+//! [`SoftwareSigner`] keeps its key in memory.
+
+use crate::{psbt::PartiallySignedTransaction, Error, PrivateKey, PublicKey, Script};
+use async_trait::async_trait;
+use bitcoincore_rpc::bitcoin::{
+    blockdata::transaction::SigHashType,
+    hash_types::SigHash,
+    secp256k1::{All, Message, Secp256k1, Signature},
+    util::bip143::SigHashCache,
+};
+use std::collections::HashMap;
+
+#[async_trait]
+pub trait Signer: Send + Sync {
+    /// The keys held by the signer, whose p2wpkh outputs it can spend.
+    fn public_keys(&self) -> Vec<PublicKey>;
+
+    /// Sign the `sighash` of a transaction input with the private key of `public_key`.
+    async fn sign_sighash(
+        &self,
+        public_key: &PublicKey,
+        sighash: &SigHash,
+    ) -> Result<Signature, Error>;
+
+    /// Add a signature to every input of `psbt` that spends a p2wpkh output of one of the
+    /// signer's keys. The spent outputs must be included as `witness_utxo`, as done by
+    /// `create_psbt`. Other inputs are left to other signers.
+    async fn sign_psbt(&self, psbt: &mut PartiallySignedTransaction) -> Result<(), Error> {
+        let keys: HashMap<_, _> = self
+            .public_keys()
+            .into_iter()
+            .filter_map(|public_key| {
+                Some((Script::new_v0_wpkh(&public_key.wpubkey_hash()?), public_key))
+            })
+            .collect();
+        let unsigned_tx = psbt.global.unsigned_tx.clone();
+        let mut cache = SigHashCache::new(&unsigned_tx);
+        for (index, input) in psbt.inputs.iter_mut().enumerate() {
+            let (prevout, public_key) = match input
+                .witness_utxo
+                .as_ref()
+                .and_then(|prevout| Some((prevout.clone(), keys.get(&prevout.script_pubkey)?)))
+            {
+                Some(spent) => spent,
+                None => continue,
+            };
+            // the script code of p2wpkh, see BIP 143
+            let script_code = Script::new_p2pkh(&public_key.pubkey_hash());
+            let sighash =
+                cache.signature_hash(index, &script_code, prevout.value, SigHashType::All);
+            let signature = self.sign_sighash(public_key, &sighash).await?;
+
+            let mut signature = signature.serialize_der().to_vec();
+            signature.push(SigHashType::All as u8);
+            input.partial_sigs.insert(*public_key, signature);
+        }
+        Ok(())
+    }
+}
+
+/// Signs with a single key held in memory.
+pub struct SoftwareSigner {
+    secp: Secp256k1<All>,
+    private_key: PrivateKey,
+    public_key: PublicKey,
+}
+
+impl SoftwareSigner {
+    pub fn new(private_key: PrivateKey) -> Self {
+        let secp = Secp256k1::new();
+        let public_key = PublicKey::from_private_key(&secp, &private_key);
+        Self {
+            secp,
+            private_key,
+            public_key,
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+}
+
+#[async_trait]
+impl Signer for SoftwareSigner {
+    fn public_keys(&self) -> Vec<PublicKey> {
+        vec![self.public_key]
+    }
+
+    async fn sign_sighash(
+        &self,
+        public_key: &PublicKey,
+        sighash: &SigHash,
+    ) -> Result<Signature, Error> {
+        if public_key != &self.public_key {
+            return Err(Error::UnknownSigningKey);
+        }
+        let message = Message::from_slice(&sighash[..])?;
+        Ok(self.secp.sign(&message, &self.private_key.key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Network, OutPoint, SecretKey, Transaction, TxIn, TxOut};
+
+    fn new_signer(byte: u8) -> SoftwareSigner {
+        SoftwareSigner::new(PrivateKey {
+            compressed: true,
+            network: Network::Regtest,
+            key: SecretKey::from_slice(&[byte; 32]).unwrap(),
+        })
+    }
+
+    fn spend(script_pubkeys: Vec<Script>) -> PartiallySignedTransaction {
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: (0..script_pubkeys.len())
+                .map(|vout| TxIn {
+                    previous_output: OutPoint::new(Default::default(), vout as u32),
+                    script_sig: Script::new(),
+                    sequence: u32::MAX,
+                    witness: vec![],
+                })
+                .collect(),
+            output: vec![],
+        })
+        .unwrap();
+        for (input, script_pubkey) in psbt.inputs.iter_mut().zip(script_pubkeys) {
+            input.witness_utxo = Some(TxOut {
+                value: 100_000,
+                script_pubkey,
+            });
+        }
+        psbt
+    }
+
+    #[tokio::test]
+    async fn test_software_signer_signs_own_inputs() {
+        let (signer, other) = (new_signer(0x01), new_signer(0x02));
+        let script_pubkey = |signer: &SoftwareSigner| {
+            Script::new_v0_wpkh(&signer.public_key().wpubkey_hash().unwrap())
+        };
+        let mut psbt = spend(vec![script_pubkey(&signer), script_pubkey(&other)]);
+
+        signer.sign_psbt(&mut psbt).await.unwrap();
+        let signature = &psbt.inputs[0].partial_sigs[&signer.public_key()];
+        assert_eq!(signature.last(), Some(&(SigHashType::All as u8)));
+        assert!(psbt.inputs[1].partial_sigs.is_empty());
+
+        // the signature commits to the spent output
+        let sighash = SigHashCache::new(&psbt.global.unsigned_tx).signature_hash(
+            0,
+            &Script::new_p2pkh(&signer.public_key().pubkey_hash()),
+            100_000,
+            SigHashType::All,
+        );
+        let (_, der) = signature.split_last().unwrap();
+        assert!(Secp256k1::verification_only()
+            .verify(
+                &Message::from_slice(&sighash[..]).unwrap(),
+                &Signature::from_der(der).unwrap(),
+                &signer.public_key().key,
+            )
+            .is_ok());
+
+        assert!(matches!(
+            signer.sign_sighash(&other.public_key(), &sighash).await,
+            Err(Error::UnknownSigningKey)
+        ));
+    }
+}
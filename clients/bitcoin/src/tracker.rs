@@ -0,0 +1,378 @@
+//! Confirmation tracking of transactions that are not in the wallet, e.g. a user's issue
+//! payment observed by the relayer. `wait_for_transaction_metadata` relies on
+//! `gettransaction`, which only knows wallet transactions.
+
+use crate::{
+    deserialize, serialize_without_witness, BitcoinCoreApi, BlockHash, Error, Payload, Transaction,
+    TransactionMetadata, Txid, RETRY_DURATION,
+};
+use futures::future::try_join_all;
+use log::{debug, info, warn};
+use tokio::time::sleep;
+
+/// Collect the metadata of `txid`, which was mined in `block_hash`.
+async fn transaction_metadata<B: BitcoinCoreApi + Sync>(
+    rpc: &B,
+    txid: Txid,
+    block_height: u32,
+    block_hash: BlockHash,
+) -> Result<TransactionMetadata, Error> {
+    let raw_tx = rpc.get_raw_tx(&txid, &block_hash).await?;
+    let transaction: Transaction = deserialize(&raw_tx)?;
+    Ok(TransactionMetadata {
+        txid,
+        proof: rpc.get_proof(txid, &block_hash).await?,
+        stripped_raw_tx: serialize_without_witness(&transaction),
+        raw_tx,
+        block_height,
+        block_hash,
+    })
+}
+
+/// Follows a single transaction until it is buried under enough blocks. The containing
+/// block is looked up by txid if the node has `-txindex`, otherwise the blocks are scanned.
+pub struct TransactionTracker<B> {
+    rpc: B,
+    txid: Txid,
+    /// Next block to scan if the node cannot look up the transaction.
+    next_height: u32,
+    /// Height and hash of the block containing the transaction.
+    found: Option<(u32, BlockHash)>,
+}
+
+impl<B: BitcoinCoreApi + Send + Sync + 'static> TransactionTracker<B> {
+    /// Track `txid`, scanning blocks from `start_height` if needed, e.g. the height at which
+    /// the payment was requested.
+    pub fn new(rpc: B, txid: Txid, start_height: u32) -> Self {
+        Self {
+            rpc,
+            txid,
+            next_height: start_height,
+            found: None,
+        }
+    }
+
+    /// Height and hash of the main chain block containing the transaction, if it has been
+    /// mined.
+    pub async fn find_block(&mut self) -> Result<Option<(u32, BlockHash)>, Error> {
+        let tip_height = self.rpc.get_block_count().await? as u32;
+        if let Some((height, block_hash)) = self.found {
+            if height <= tip_height && self.rpc.get_block_hash(height).await? == block_hash {
+                return Ok(self.found);
+            }
+            warn!("Block {} containing {} was orphaned", block_hash, self.txid);
+            self.found = None;
+            self.next_height = self.next_height.min(height);
+        }
+
+        if let Some(block_hash) = self.rpc.find_transaction_block(&self.txid).await? {
+            let height = self.rpc.get_block_info(&block_hash).await?.height as u32;
+            self.found = Some((height, block_hash));
+            return Ok(self.found);
+        }
+
+        while self.next_height <= tip_height {
+            let block_hash = self.rpc.get_block_hash(self.next_height).await?;
+            let block = self.rpc.get_block(&block_hash).await?;
+            if block.txdata.iter().any(|tx| tx.txid() == self.txid) {
+                debug!("Found {} at height {}", self.txid, self.next_height);
+                self.found = Some((self.next_height, block_hash));
+                self.next_height += 1;
+                return Ok(self.found);
+            }
+            self.next_height += 1;
+        }
+        Ok(None)
+    }
+
+    /// Number of blocks on top of and including the containing block, 0 if unconfirmed.
+    pub async fn confirmations(&mut self) -> Result<u32, Error> {
+        match self.find_block().await? {
+            Some((height, _)) => {
+                let tip_height = self.rpc.get_block_count().await? as u32;
+                Ok((tip_height + 1).saturating_sub(height))
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Wait until the transaction has `num_confirmations`, and collect the same data as
+    /// [`wait_for_transaction_metadata`](BitcoinCoreApi::wait_for_transaction_metadata).
+    pub async fn wait_for_metadata(
+        mut self,
+        num_confirmations: u32,
+    ) -> Result<TransactionMetadata, Error> {
+        let (block_height, block_hash) = loop {
+            if self.confirmations().await? >= num_confirmations.max(1) {
+                break self.found.expect("confirmed transaction was found");
+            }
+            sleep(RETRY_DURATION).await;
+        };
+
+        transaction_metadata(&self.rpc, self.txid, block_height, block_hash).await
+    }
+}
+
+/// A payment to the watched payload in a main chain block.
+struct Payment {
+    txid: Txid,
+    amount: u64,
+    block_height: u32,
+    block_hash: BlockHash,
+}
+
+/// The total amount `transaction` pays to `payload` in any of its outputs, if any. Unlike
+/// [`TransactionExt::get_payment_amount_to`](crate::TransactionExt::get_payment_amount_to),
+/// this checks every output, as a deposit may be made by any transaction, e.g. a batched
+/// withdrawal with many outputs.
+fn payment_amount_to(transaction: &Transaction, payload: &Payload) -> Option<u64> {
+    transaction
+        .output
+        .iter()
+        .filter(|txout| Payload::from_script(&txout.script_pubkey).as_ref() == Some(payload))
+        .map(|txout| txout.value)
+        .reduce(|total, value| total + value)
+}
+
+/// Scan the blocks from `since_height` for payments to `payload`, see
+/// [`BitcoinCoreApi::wait_for_payment`].
+pub(crate) async fn wait_for_payment<B: BitcoinCoreApi + Sync>(
+    rpc: &B,
+    payload: Payload,
+    min_amount: Option<u64>,
+    since_height: u32,
+    num_confirmations: u32,
+) -> Result<Vec<TransactionMetadata>, Error> {
+    let num_confirmations = num_confirmations.max(1);
+    // scanned main chain blocks in ascending height
+    let mut scanned: Vec<(u32, BlockHash)> = Vec::new();
+    let mut payments: Vec<Payment> = Vec::new();
+    loop {
+        let tip_height = rpc.get_block_count().await? as u32;
+
+        // rescan blocks replaced by a reorg, their payments may have been mined elsewhere
+        while let Some(&(height, block_hash)) = scanned.last() {
+            if height <= tip_height && rpc.get_block_hash(height).await? == block_hash {
+                break;
+            }
+            warn!("Block {} at height {} was orphaned", block_hash, height);
+            scanned.pop();
+        }
+        let next_height = scanned
+            .last()
+            .map_or(since_height, |(height, _)| height + 1);
+        payments.retain(|payment| payment.block_height < next_height);
+
+        for height in next_height..=tip_height {
+            let block_hash = rpc.get_block_hash(height).await?;
+            let block = rpc.get_block(&block_hash).await?;
+            for transaction in block.txdata.iter() {
+                if let Some(amount) = payment_amount_to(transaction, &payload) {
+                    info!(
+                        "Found payment of {} sat in {} at height {}",
+                        amount,
+                        transaction.txid(),
+                        height
+                    );
+                    payments.push(Payment {
+                        txid: transaction.txid(),
+                        amount,
+                        block_height: height,
+                        block_hash,
+                    });
+                }
+            }
+            scanned.push((height, block_hash));
+        }
+
+        let confirmed: Vec<_> = payments
+            .iter()
+            .filter(|payment| tip_height + 1 - payment.block_height >= num_confirmations)
+            .collect();
+        let total: u64 = confirmed.iter().map(|payment| payment.amount).sum();
+        if !confirmed.is_empty() && total >= min_amount.unwrap_or(0) {
+            return try_join_all(confirmed.into_iter().map(|payment| {
+                transaction_metadata(rpc, payment.txid, payment.block_height, payment.block_hash)
+            }))
+            .await;
+        }
+        // unconfirmed payments do not count, so the mempool is not scanned
+        sleep(RETRY_DURATION).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::MockBitcoinCore, Address, Network, OutPoint, PrivateKey, PublicKey, Script,
+        SecretKey, TxIn, TxOut,
+    };
+    use bitcoincore_rpc::bitcoin::secp256k1::Secp256k1;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn transaction(value: u64) -> Transaction {
+        payment(value, Script::new())
+    }
+
+    fn payment(value: u64, script_pubkey: Script) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Default::default(), 0),
+                script_sig: Script::new(),
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey,
+            }],
+        }
+    }
+
+    fn deposit_address() -> Address {
+        let private_key = PrivateKey {
+            compressed: true,
+            network: Network::Regtest,
+            key: SecretKey::from_slice(&[0x42; 32]).unwrap(),
+        };
+        let public_key = PublicKey::from_private_key(&Secp256k1::new(), &private_key);
+        Address::p2wpkh(&public_key, Network::Regtest).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_partial_payments() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let address = deposit_address();
+        // paid before the issue request
+        btc_rpc.add_to_mempool(payment(50_000, address.script_pubkey()));
+        btc_rpc.mine_block();
+
+        let first = btc_rpc.add_to_mempool(payment(30_000, address.script_pubkey()));
+        btc_rpc.add_to_mempool(transaction(1_000));
+        btc_rpc.mine_blocks(2);
+        let wait = btc_rpc.wait_for_payment(address.payload.clone(), Some(50_000), 2, 1);
+        assert!(timeout(Duration::from_millis(100), wait).await.is_err());
+
+        // the second payment is unconfirmed
+        let second = btc_rpc.add_to_mempool(payment(20_000, address.script_pubkey()));
+        let wait = btc_rpc.wait_for_payment(address.payload.clone(), Some(50_000), 2, 1);
+        assert!(timeout(Duration::from_millis(100), wait).await.is_err());
+
+        btc_rpc.mine_block();
+        let payments = btc_rpc
+            .wait_for_payment(address.payload, Some(50_000), 2, 1)
+            .await
+            .unwrap();
+        let found: Vec<_> = payments
+            .iter()
+            .map(|metadata| (metadata.txid, metadata.block_height))
+            .collect();
+        assert_eq!(found, vec![(first, 2), (second, 4)]);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_payment_in_any_output() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let address = deposit_address();
+        // e.g. a batched withdrawal from an exchange, paying twice after three other outputs
+        let mut transaction = transaction(1_000);
+        let output = |value, script_pubkey| TxOut {
+            value,
+            script_pubkey,
+        };
+        transaction.output.extend_from_slice(&[
+            output(2_000, Script::new()),
+            output(3_000, Script::new()),
+            output(20_000, address.script_pubkey()),
+            output(10_000, address.script_pubkey()),
+        ]);
+        let txid = btc_rpc.add_to_mempool(transaction);
+        btc_rpc.mine_block();
+
+        let payments = btc_rpc
+            .wait_for_payment(address.payload, Some(30_000), 0, 1)
+            .await
+            .unwrap();
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].txid, txid);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_payment_confirmations() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let address = deposit_address();
+        let txid = btc_rpc.add_to_mempool(payment(30_000, address.script_pubkey()));
+        btc_rpc.mine_block();
+
+        let miner = btc_rpc.clone();
+        let mining = tokio::spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            miner.mine_block();
+        });
+        let payments = btc_rpc
+            .wait_for_payment(address.payload, None, 0, 2)
+            .await
+            .unwrap();
+        mining.await.unwrap();
+
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].txid, txid);
+        assert_eq!(payments[0].block_height, 1);
+    }
+
+    #[tokio::test]
+    async fn test_track_by_scanning_blocks() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        btc_rpc.mine_blocks(2);
+        let txid = btc_rpc.add_to_mempool(transaction(1_000));
+        let mut tracker = TransactionTracker::new(btc_rpc.clone(), txid, 2);
+        assert_eq!(tracker.confirmations().await.unwrap(), 0);
+
+        let block_hash = btc_rpc.mine_block();
+        btc_rpc.mine_block();
+        assert_eq!(tracker.find_block().await.unwrap(), Some((3, block_hash)));
+        assert_eq!(tracker.confirmations().await.unwrap(), 2);
+
+        let metadata = tracker.wait_for_metadata(2).await.unwrap();
+        assert_eq!(metadata.block_height, 3);
+        assert_eq!(metadata.block_hash, block_hash);
+        let tx: Transaction = deserialize(&metadata.raw_tx).unwrap();
+        assert_eq!(tx.txid(), txid);
+    }
+
+    #[tokio::test]
+    async fn test_track_with_txindex() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        btc_rpc.set_txindex(true);
+        let txid = btc_rpc.add_to_mempool(transaction(1_000));
+        let block_hash = btc_rpc.mine_block();
+        btc_rpc.mine_blocks(2);
+
+        // found although mined before the start height
+        let mut tracker = TransactionTracker::new(btc_rpc.clone(), txid, 3);
+        assert_eq!(tracker.find_block().await.unwrap(), Some((1, block_hash)));
+        assert_eq!(tracker.confirmations().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_track_across_reorg() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let txid = btc_rpc.add_to_mempool(transaction(1_000));
+        let orphaned = btc_rpc.mine_block();
+        let mut tracker = TransactionTracker::new(btc_rpc.clone(), txid, 0);
+        assert_eq!(tracker.find_block().await.unwrap(), Some((1, orphaned)));
+
+        // the transaction returns to the mempool and is mined in a different block
+        btc_rpc.invalidate_block(&orphaned);
+        assert_eq!(tracker.confirmations().await.unwrap(), 0);
+        btc_rpc.add_to_mempool(transaction(2_000));
+        let mined = btc_rpc.mine_blocks(2);
+        assert_ne!(mined[0], orphaned);
+        assert_eq!(tracker.find_block().await.unwrap(), Some((1, mined[0])));
+        assert_eq!(tracker.confirmations().await.unwrap(), 2);
+    }
+}
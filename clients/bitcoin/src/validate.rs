@@ -0,0 +1,427 @@
+//! Port of the bridge's `TxValidate.validateTransaction`, to reject a payment locally that the
+//! contract would revert on. The checks operate on the serialized outputs, like `BTCUtils`.
+
+use crate::{serialize, Error, Transaction, H160, H256};
+use thiserror::Error;
+
+/// Reasons for which `validateTransaction` reverts, displayed as the contract's revert message.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    #[error("Invalid OpReturn")]
+    InvalidOpReturn,
+    #[error("Insufficient BTC value")]
+    InsufficientValue,
+    #[error("Invalid recipient")]
+    InvalidRecipient,
+    #[error("Vout read overrun")]
+    OutputIndexOutOfBounds,
+    #[error("Slice out of bounds")]
+    SliceOutOfBounds,
+}
+
+/// Validate the outputs of `transaction` like the contract, returning the amount paid to
+/// `recipient` (the 20-byte hash of a p2pkh, p2sh or p2wpkh output).
+///
+/// If `op_return_id` is non-zero, the first non-empty OP_RETURN must encode it and the first
+/// output to the recipient is used. Otherwise only the output at `output_index` is considered.
+pub fn validate_transaction(
+    transaction: &Transaction,
+    minimum_btc: u64,
+    recipient: &H160,
+    op_return_id: &H256,
+    output_index: u32,
+) -> Result<u64, Error> {
+    let outputs: Vec<Vec<u8>> = transaction.output.iter().map(serialize).collect();
+    let mut btc_amount = 0;
+    let mut btc_address = H160::zero();
+
+    if !op_return_id.is_zero() {
+        let mut op_return_data: &[u8] = &[];
+        for output in outputs.iter() {
+            if op_return_data.is_empty() {
+                op_return_data = extract_op_return_data(output)?;
+                if !op_return_data.is_empty() {
+                    continue;
+                }
+            }
+            if &btc_address != recipient {
+                let hash = extract_hash(output)?;
+                if hash.len() == 20 && H160::from_slice(hash) == *recipient {
+                    btc_amount = extract_value(output);
+                    btc_address = *recipient;
+                }
+            }
+        }
+
+        if bytes_to_uint(op_return_data) != *op_return_id {
+            return Err(ValidationError::InvalidOpReturn.into());
+        }
+    } else {
+        let output = outputs
+            .get(output_index as usize)
+            .ok_or(ValidationError::OutputIndexOutOfBounds)?;
+        let hash = extract_hash(output)?;
+        btc_amount = extract_value(output);
+        // `toAddress` reads the first 20 bytes
+        btc_address = H160::from_slice(hash.get(..20).ok_or(ValidationError::SliceOutOfBounds)?);
+    }
+
+    if btc_amount < minimum_btc {
+        return Err(ValidationError::InsufficientValue.into());
+    }
+    if &btc_address != recipient {
+        return Err(ValidationError::InvalidRecipient.into());
+    }
+    Ok(btc_amount)
+}
+
+/// `BTCUtils.extractValue`: the little endian amount in the first 8 bytes.
+fn extract_value(output: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&output[..8]);
+    u64::from_le_bytes(value)
+}
+
+/// `BTCUtils.extractOpReturnData`: the pushed data if the script starts with OP_RETURN. Like
+/// the contract's slice, reading the first script byte of an empty script fails.
+fn extract_op_return_data(output: &[u8]) -> Result<&[u8], ValidationError> {
+    if *output.get(9).ok_or(ValidationError::SliceOutOfBounds)? != 0x6a {
+        return Ok(&[]);
+    }
+    let length = *output.get(10).ok_or(ValidationError::SliceOutOfBounds)? as usize;
+    output
+        .get(11..11 + length)
+        .ok_or(ValidationError::SliceOutOfBounds)
+}
+
+/// `BTCUtils.extractHash`: the hash of witness (20 or 32 bytes), p2pkh and p2sh outputs, or
+/// nothing for other or malformed scripts. Fails on an empty script, like the contract.
+fn extract_hash(output: &[u8]) -> Result<&[u8], ValidationError> {
+    let script_length = *output.get(8).ok_or(ValidationError::SliceOutOfBounds)? as usize;
+    if script_length + 9 != output.len() {
+        return Ok(&[]);
+    }
+
+    if *output.get(9).ok_or(ValidationError::SliceOutOfBounds)? == 0 {
+        if script_length < 2 {
+            return Ok(&[]);
+        }
+        let payload_length = output[10] as usize;
+        if payload_length != script_length - 2 || (payload_length != 0x20 && payload_length != 0x14)
+        {
+            return Ok(&[]);
+        }
+        Ok(&output[11..11 + payload_length])
+    } else {
+        Ok(match output.get(8..11) {
+            Some([0x19, 0x76, 0xa9]) if output[11] == 0x14 && output.ends_with(&[0x88, 0xac]) => {
+                &output[12..32]
+            }
+            Some([0x17, 0xa9, 0x14]) if output.ends_with(&[0x87]) => &output[11..31],
+            _ => &[],
+        })
+    }
+}
+
+/// `BytesLib.bytesToUint`: big endian, overflowing such that only the last 32 bytes count.
+fn bytes_to_uint(bytes: &[u8]) -> H256 {
+    let bytes = &bytes[bytes.len().saturating_sub(32)..];
+    let mut number = H256::zero();
+    number[32 - bytes.len()..].copy_from_slice(bytes);
+    number
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        derive_deposit_address, derive_deposit_key_hash, mock::MockBitcoinCore, BitcoinCoreApi,
+        Hash, Network, PublicKey, Script, ScriptHash, Txid,
+    };
+    use bitcoincore_rpc::bitcoin::{
+        blockdata::{opcodes, script::Builder},
+        secp256k1::{rand::rngs::OsRng, Secp256k1},
+        OutPoint, TxIn, TxOut, WScriptHash,
+    };
+
+    fn new_public_key() -> PublicKey {
+        let secp = Secp256k1::new();
+        let (_, public_key) = secp.generate_keypair(&mut OsRng::new().unwrap());
+        PublicKey {
+            compressed: true,
+            key: public_key,
+        }
+    }
+
+    fn transaction(outputs: Vec<(u64, Script)>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::default(), 0),
+                script_sig: Script::new(),
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            output: outputs
+                .into_iter()
+                .map(|(value, script_pubkey)| TxOut {
+                    value,
+                    script_pubkey,
+                })
+                .collect(),
+        }
+    }
+
+    fn hash_of(public_key: &PublicKey) -> H160 {
+        H160::from_slice(&public_key.pubkey_hash()[..])
+    }
+
+    fn p2wpkh(public_key: &PublicKey) -> Script {
+        Script::new_v0_wpkh(&public_key.wpubkey_hash().unwrap())
+    }
+
+    fn op_return(request_id: &H256) -> Script {
+        Script::new_op_return(request_id.as_bytes())
+    }
+
+    fn validation_error(result: Result<u64, Error>) -> ValidationError {
+        match result {
+            Err(Error::ValidationError(err)) => err,
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_op_return_mode() {
+        let recipient = new_public_key();
+        let request_id = H256::random();
+        let tx = transaction(vec![
+            (0, op_return(&request_id)),
+            (1000, p2wpkh(&new_public_key())),
+            (2000, p2wpkh(&recipient)),
+            (3000, p2wpkh(&recipient)),
+        ]);
+
+        // the first output to the recipient counts
+        assert_eq!(
+            validate_transaction(&tx, 2000, &hash_of(&recipient), &request_id, 0).unwrap(),
+            2000
+        );
+        assert_eq!(
+            validation_error(validate_transaction(
+                &tx,
+                2001,
+                &hash_of(&recipient),
+                &request_id,
+                0
+            )),
+            ValidationError::InsufficientValue
+        );
+        assert_eq!(
+            validation_error(validate_transaction(
+                &tx,
+                0,
+                &hash_of(&new_public_key()),
+                &request_id,
+                0
+            )),
+            ValidationError::InvalidRecipient
+        );
+        assert_eq!(
+            validation_error(validate_transaction(
+                &tx,
+                0,
+                &hash_of(&recipient),
+                &H256::random(),
+                0
+            )),
+            ValidationError::InvalidOpReturn
+        );
+    }
+
+    #[test]
+    fn test_op_return_position_and_encoding() {
+        let recipient = new_public_key();
+        let request_id = H256::from_low_u64_be(42);
+
+        // unlike `TransactionExt::get_op_return`, any position is accepted
+        let mut outputs: Vec<_> = (0..4).map(|_| (1, p2wpkh(&new_public_key()))).collect();
+        outputs.push((500, Script::new_p2pkh(&recipient.pubkey_hash())));
+        outputs.push((0, op_return(&request_id)));
+        let tx = transaction(outputs);
+        assert_eq!(
+            validate_transaction(&tx, 0, &hash_of(&recipient), &request_id, 0).unwrap(),
+            500
+        );
+
+        // the data is read as a big endian number, so leading zeros do not matter
+        let tx = transaction(vec![
+            (0, Script::new_op_return(&[42])),
+            (500, p2wpkh(&recipient)),
+        ]);
+        assert!(validate_transaction(&tx, 0, &hash_of(&recipient), &request_id, 0).is_ok());
+
+        // only the first op_return counts
+        let tx = transaction(vec![
+            (0, Script::new_op_return(&[1])),
+            (0, op_return(&request_id)),
+            (500, p2wpkh(&recipient)),
+        ]);
+        assert_eq!(
+            validation_error(validate_transaction(
+                &tx,
+                0,
+                &hash_of(&recipient),
+                &request_id,
+                0
+            )),
+            ValidationError::InvalidOpReturn
+        );
+
+        // a bare OP_RETURN makes the contract read out of bounds
+        let tx = transaction(vec![
+            (
+                0,
+                Builder::new()
+                    .push_opcode(opcodes::all::OP_RETURN)
+                    .into_script(),
+            ),
+            (500, p2wpkh(&recipient)),
+        ]);
+        assert_eq!(
+            validation_error(validate_transaction(
+                &tx,
+                0,
+                &hash_of(&recipient),
+                &request_id,
+                0
+            )),
+            ValidationError::SliceOutOfBounds
+        );
+    }
+
+    #[test]
+    fn test_output_index_mode() {
+        let recipient = new_public_key();
+        let tx = transaction(vec![
+            (0, op_return(&H256::random())),
+            (1000, p2wpkh(&new_public_key())),
+            (2000, p2wpkh(&recipient)),
+        ]);
+        let zero = H256::zero();
+
+        assert_eq!(
+            validate_transaction(&tx, 2000, &hash_of(&recipient), &zero, 2).unwrap(),
+            2000
+        );
+        // the op_return is ignored, only the given output is checked
+        assert_eq!(
+            validation_error(validate_transaction(&tx, 0, &hash_of(&recipient), &zero, 1)),
+            ValidationError::InvalidRecipient
+        );
+        assert_eq!(
+            validation_error(validate_transaction(&tx, 0, &hash_of(&recipient), &zero, 3)),
+            ValidationError::OutputIndexOutOfBounds
+        );
+        // there is no hash to read the address from
+        assert_eq!(
+            validation_error(validate_transaction(&tx, 0, &hash_of(&recipient), &zero, 0)),
+            ValidationError::SliceOutOfBounds
+        );
+
+        // for p2wsh outputs the first 20 bytes of the script hash are compared
+        let script_hash = WScriptHash::hash(&[1, 2, 3]);
+        let tx = transaction(vec![(1000, Script::new_v0_wsh(&script_hash))]);
+        let truncated = H160::from_slice(&script_hash[..20]);
+        assert_eq!(
+            validate_transaction(&tx, 0, &truncated, &zero, 0).unwrap(),
+            1000
+        );
+    }
+
+    #[test]
+    fn test_empty_script_output() {
+        let recipient = new_public_key();
+        let request_id = H256::random();
+        let tx = transaction(vec![
+            (0, Script::new()),
+            (0, op_return(&request_id)),
+            (1000, p2wpkh(&recipient)),
+        ]);
+
+        // the contract's slice reverts on the empty script instead of skipping the output
+        assert_eq!(
+            validation_error(validate_transaction(
+                &tx,
+                1000,
+                &hash_of(&recipient),
+                &request_id,
+                0
+            )),
+            ValidationError::SliceOutOfBounds
+        );
+        assert_eq!(
+            validation_error(validate_transaction(
+                &tx,
+                0,
+                &hash_of(&recipient),
+                &H256::zero(),
+                0
+            )),
+            ValidationError::SliceOutOfBounds
+        );
+    }
+
+    #[test]
+    fn test_extract_hash() {
+        let public_key = new_public_key();
+        let hash = hash_of(&public_key);
+        let extract = |script: Script| {
+            extract_hash(&serialize(&TxOut {
+                value: 0,
+                script_pubkey: script,
+            }))
+            .map(<[u8]>::to_vec)
+        };
+
+        assert_eq!(extract(p2wpkh(&public_key)), Ok(hash.as_bytes().to_vec()));
+        assert_eq!(
+            extract(Script::new_p2pkh(&public_key.pubkey_hash())),
+            Ok(hash.as_bytes().to_vec())
+        );
+        assert_eq!(
+            extract(Script::new_p2sh(&ScriptHash::hash(&[1]))),
+            Ok(ScriptHash::hash(&[1])[..].to_vec())
+        );
+        assert_eq!(extract(Script::new_op_return(&[1; 20])), Ok(vec![]));
+        // witness program with a length that does not match the script
+        assert_eq!(extract(Script::from(vec![0, 0x14, 1, 2])), Ok(vec![]));
+        assert_eq!(
+            extract(Script::new()),
+            Err(ValidationError::SliceOutOfBounds)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wallet_payment_to_deposit_address() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        btc_rpc.fund_wallet(1_000_000);
+        let vault_key = new_public_key();
+        let issue_id = H256::random();
+        let address = derive_deposit_address(&vault_key.key, issue_id, Network::Regtest).unwrap();
+        let recipient = derive_deposit_key_hash(&vault_key.key, issue_id).unwrap();
+
+        let request_id = H256::random();
+        let tx = btc_rpc
+            .create_transaction(address, 10_000, Some(request_id), Default::default())
+            .await
+            .unwrap()
+            .transaction;
+
+        assert_eq!(
+            validate_transaction(&tx, 10_000, &recipient, &request_id, 0).unwrap(),
+            10_000
+        );
+    }
+}
@@ -0,0 +1,346 @@
+//! Block and transaction notifications, either pushed by bitcoind over ZMQ
+//! (`-zmqpubrawblock`/`-zmqpubhashtx`) or, if that is not configured, obtained by polling.
+
+use crate::{deserialize, BitcoinCoreApi, Block, Error, Hash, Transaction, Txid, RETRY_DURATION};
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use log::{trace, warn};
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::TryInto,
+    time::Duration,
+};
+use tokio::time::sleep;
+use zeromq::{Socket, SocketRecv, SubSocket, ZmqMessage};
+
+const RAW_BLOCK_TOPIC: &str = "rawblock";
+const HASH_TX_TOPIC: &str = "hashtx";
+
+/// Endpoints of the ZMQ publishers of bitcoind, e.g. `tcp://127.0.0.1:28332`.
+#[derive(Debug, Clone, Default)]
+pub struct ZmqConfig {
+    /// Endpoint passed to bitcoind as `-zmqpubrawblock`
+    pub raw_block: Option<String>,
+    /// Endpoint passed to bitcoind as `-zmqpubhashtx`
+    pub hash_tx: Option<String>,
+}
+
+/// Split a notification into its body and sequence number. Bitcoind sends three
+/// frames: the topic, the body and a little endian sequence number.
+fn parse_notification(message: &ZmqMessage, topic: &str) -> Result<(Vec<u8>, u32), Error> {
+    match (message.get(0), message.get(1), message.get(2)) {
+        (Some(received_topic), Some(body), Some(sequence))
+            if received_topic.as_ref() == topic.as_bytes() =>
+        {
+            let sequence: [u8; 4] = sequence
+                .as_ref()
+                .try_into()
+                .map_err(|_| Error::InvalidZmqNotification)?;
+            Ok((body.to_vec(), u32::from_le_bytes(sequence)))
+        }
+        _ => Err(Error::InvalidZmqNotification),
+    }
+}
+
+/// Subscribe to `topic` on `endpoint`, yielding the body of every notification. The stream
+/// ends after the first failed receive (e.g. the publisher violated the protocol) instead of
+/// retrying the socket in a tight loop, subscribe again to resume.
+async fn subscribe(
+    endpoint: &str,
+    topic: &'static str,
+) -> Result<BoxStream<'static, Result<Vec<u8>, Error>>, Error> {
+    let mut socket = SubSocket::new();
+    socket.connect(endpoint).await?;
+    socket.subscribe(topic).await?;
+
+    let notifications = stream::unfold(Some((socket, None::<u32>)), move |state| async move {
+        let (mut socket, last_sequence) = state?;
+        let (result, sequence) = match socket.recv().await {
+            Ok(message) => match parse_notification(&message, topic) {
+                Ok((body, sequence)) => {
+                    match last_sequence {
+                        Some(last) if sequence != last.wrapping_add(1) => {
+                            warn!("missed {} zmq notification(s)", sequence.wrapping_sub(last))
+                        }
+                        _ => trace!("received {} notification {}", topic, sequence),
+                    }
+                    (Ok(body), Some(sequence))
+                }
+                Err(err) => (Err(err), last_sequence),
+            },
+            Err(err) => return Some((Err(err.into()), None)),
+        };
+        Some((result, Some((socket, sequence))))
+    });
+    Ok(notifications.boxed())
+}
+
+/// Stream of blocks connected to the tip, as published on `-zmqpubrawblock`.
+pub async fn raw_blocks(endpoint: &str) -> Result<BoxStream<'static, Result<Block, Error>>, Error> {
+    Ok(subscribe(endpoint, RAW_BLOCK_TOPIC)
+        .await?
+        .map(|body| Ok(deserialize(&body?)?))
+        .boxed())
+}
+
+/// Stream of txids as published on `-zmqpubhashtx`. This includes mempool transactions
+/// as well as the transactions of newly connected blocks.
+pub async fn tx_hashes(endpoint: &str) -> Result<BoxStream<'static, Result<Txid, Error>>, Error> {
+    Ok(subscribe(endpoint, HASH_TX_TOPIC)
+        .await?
+        .map(|body| {
+            // the hash is published in rpc (i.e. reversed) byte order
+            let mut body = body?;
+            body.reverse();
+            Ok(Txid::from_slice(&body).map_err(crate::ConversionError::from)?)
+        })
+        .boxed())
+}
+
+/// Stream of main chain blocks starting at `from_height`, obtained by polling. A failed
+/// poll yields the error, and the same height is polled again after a short delay.
+pub fn poll_blocks<B>(rpc: B, from_height: u32) -> BoxStream<'static, Result<Block, Error>>
+where
+    B: BitcoinCoreApi + Send + Sync + 'static,
+{
+    stream::unfold(
+        (rpc, from_height, false),
+        |(rpc, height, failed)| async move {
+            if failed {
+                sleep(RETRY_DURATION).await;
+            }
+            match rpc.wait_for_block(height, 1).await {
+                Ok(block) => Some((Ok(block), (rpc, height + 1, false))),
+                Err(err) => Some((Err(err), (rpc, height, true))),
+            }
+        },
+    )
+    .boxed()
+}
+
+/// Stream of transactions entering the mempool, obtained by polling every `interval`.
+/// Transactions that were already in the mempool when polling started are included.
+pub fn poll_mempool<B>(rpc: B, interval: Duration) -> BoxStream<'static, Result<Transaction, Error>>
+where
+    B: BitcoinCoreApi + Send + Sync + 'static,
+{
+    let state = (rpc, HashSet::<Txid>::new(), VecDeque::<Transaction>::new());
+    stream::unfold(state, move |(rpc, mut seen, mut pending)| async move {
+        loop {
+            if let Some(transaction) = pending.pop_front() {
+                return Some((Ok(transaction), (rpc, seen, pending)));
+            }
+
+            let mempool = match rpc.get_mempool_transactions().await {
+                Ok(iterator) => iterator.collect::<Result<Vec<_>, _>>(),
+                Err(err) => Err(err),
+            };
+            let mempool = match mempool {
+                Ok(mempool) => mempool,
+                Err(err) => return Some((Err(err), (rpc, seen, pending))),
+            };
+
+            // only remember what is still in the mempool, so the set does not grow forever
+            let current: HashSet<Txid> = mempool.iter().map(Transaction::txid).collect();
+            pending.extend(
+                mempool
+                    .into_iter()
+                    .filter(|transaction| !seen.contains(&transaction.txid())),
+            );
+            seen = current;
+
+            if pending.is_empty() {
+                sleep(interval).await;
+            }
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock::MockBitcoinCore, serialize, Network};
+    use bitcoincore_rpc::bitcoin::{blockdata::constants::genesis_block, TxOut};
+    use bytes::Bytes;
+    use std::convert::TryFrom;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time::timeout,
+    };
+    use zeromq::{PubSocket, SocketSend};
+
+    async fn new_publisher() -> (PubSocket, String) {
+        let mut publisher = PubSocket::new();
+        let endpoint = publisher.bind("tcp://127.0.0.1:0").await.unwrap();
+        (publisher, endpoint.to_string())
+    }
+
+    fn notification(topic: &str, body: Vec<u8>, sequence: u32) -> ZmqMessage {
+        ZmqMessage::try_from(vec![
+            Bytes::from(topic.to_string()),
+            Bytes::from(body),
+            Bytes::from(sequence.to_le_bytes().to_vec()),
+        ])
+        .unwrap()
+    }
+
+    /// Publish until the subscriber yields an item, since pub/sub drops messages that are
+    /// sent before the subscription has reached the publisher.
+    async fn publish_until_received<T>(
+        publisher: &mut PubSocket,
+        messages: Vec<ZmqMessage>,
+        stream: &mut BoxStream<'static, T>,
+    ) -> T {
+        timeout(Duration::from_secs(10), async {
+            loop {
+                for message in messages.iter() {
+                    publisher.send(message.clone()).await.unwrap();
+                }
+                if let Ok(Some(item)) = timeout(Duration::from_millis(100), stream.next()).await {
+                    return item;
+                }
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_notification() {
+        let message = notification(HASH_TX_TOPIC, vec![1, 2, 3], 7);
+        assert_eq!(
+            parse_notification(&message, HASH_TX_TOPIC).unwrap(),
+            (vec![1, 2, 3], 7)
+        );
+        assert!(parse_notification(&message, RAW_BLOCK_TOPIC).is_err());
+        assert!(parse_notification(&ZmqMessage::from(vec![1u8]), HASH_TX_TOPIC).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_raw_blocks() {
+        let (mut publisher, endpoint) = new_publisher().await;
+        let mut blocks = raw_blocks(&endpoint).await.unwrap();
+
+        let block = genesis_block(Network::Regtest);
+        let received = publish_until_received(
+            &mut publisher,
+            vec![
+                // other topics are not delivered
+                notification(HASH_TX_TOPIC, vec![0; 32], 0),
+                notification(RAW_BLOCK_TOPIC, serialize(&block), 0),
+            ],
+            &mut blocks,
+        )
+        .await;
+        assert_eq!(received.unwrap(), block);
+    }
+
+    #[tokio::test]
+    async fn test_tx_hashes() {
+        let (mut publisher, endpoint) = new_publisher().await;
+        let mut txids = tx_hashes(&endpoint).await.unwrap();
+
+        let txid = genesis_block(Network::Regtest).txdata[0].txid();
+        let mut body = txid.to_vec();
+        body.reverse();
+        let received = publish_until_received(
+            &mut publisher,
+            vec![notification(HASH_TX_TOPIC, body, 0)],
+            &mut txids,
+        )
+        .await;
+        assert_eq!(received.unwrap(), txid);
+    }
+
+    /// Accept subscribers on a raw tcp socket, completing the ZMTP handshake as a publisher
+    /// and then sending a command that the subscriber fails to decode.
+    async fn new_faulty_publisher() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("tcp://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut greeting = [0u8; 64];
+            greeting[0] = 0xff;
+            greeting[9] = 0x7f;
+            greeting[10] = 3;
+            greeting[12..16].copy_from_slice(b"NULL");
+            let mut ready = vec![0x04, 25, 5];
+            ready.extend_from_slice(b"READY");
+            ready.push(11);
+            ready.extend_from_slice(b"Socket-Type");
+            ready.extend_from_slice(&3u32.to_be_bytes());
+            ready.extend_from_slice(b"PUB");
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let _ = stream.write_all(&greeting).await;
+                let _ = stream.write_all(&ready).await;
+                let _ = stream.write_all(&[0x04, 4, 3, b'B', b'A', b'D']).await;
+                // keep the connection open, draining the subscriptions
+                tokio::spawn(async move {
+                    let mut buffer = [0u8; 256];
+                    while let Ok(read) = stream.read(&mut buffer).await {
+                        if read == 0 {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        endpoint
+    }
+
+    #[tokio::test]
+    async fn test_stream_ends_on_receive_error() {
+        let endpoint = new_faulty_publisher().await;
+        let txids = tx_hashes(&endpoint).await.unwrap();
+
+        let received: Vec<_> = timeout(Duration::from_secs(10), txids.collect())
+            .await
+            .unwrap();
+        assert_eq!(received.len(), 1);
+        assert!(matches!(received[0], Err(Error::ZmqError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_poll_blocks() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let hashes = btc_rpc.mine_blocks(2);
+
+        let blocks: Vec<_> = poll_blocks(btc_rpc.clone(), 1).take(2).collect().await;
+        assert_eq!(
+            blocks
+                .into_iter()
+                .map(|block| block.unwrap().block_hash())
+                .collect::<Vec<_>>(),
+            hashes
+        );
+    }
+
+    #[tokio::test]
+    async fn test_poll_mempool() {
+        let btc_rpc = MockBitcoinCore::new(Network::Regtest);
+        let transaction = |value| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Default::default(),
+            }],
+        };
+        btc_rpc.add_to_mempool(transaction(1));
+
+        let mut transactions = poll_mempool(btc_rpc.clone(), Duration::from_millis(10));
+        assert_eq!(transactions.next().await.unwrap().unwrap(), transaction(1));
+
+        btc_rpc.add_to_mempool(transaction(2));
+        assert_eq!(transactions.next().await.unwrap().unwrap(), transaction(2));
+        // transactions are only emitted once
+        assert!(timeout(Duration::from_millis(50), transactions.next())
+            .await
+            .is_err());
+    }
+}